@@ -87,7 +87,8 @@
               # work around https://github.com/NixOS/nixpkgs/issues/73404
               cd /tmp
 
-              $PROFILE/bin/switch-to-configuration switch
+              # `switch`, `boot` or `test`, depending on how the profile is activated
+              $PROFILE/bin/switch-to-configuration "''${ACTIVATION_MODE:-switch}"
 
               # https://github.com/serokell/deploy-rs/issues/31
               ${with base.config.boot.loader;
@@ -95,6 +96,11 @@
               "sed -i '/^default /d' ${efi.efiSysMountPoint}/loader/loader.conf"}
             '';
 
+            # nix-darwin has no boot or test activation, which deploy-rs refuses for nodes with `system = "darwin"`
+            darwin = base: (custom // { dryActivate = "echo \"nix-darwin can not dry-activate, would run $PROFILE/activate\""; }) base.config.system.build.toplevel ''
+              $PROFILE/activate
+            '';
+
             home-manager = base: custom base.activationPackage "$PROFILE/activate";
 
             noop = base: custom base ":";
@@ -107,15 +113,16 @@
 
             activate = deploy:
               let
-                profiles = builtins.concatLists (final.lib.mapAttrsToList (nodeName: node: final.lib.mapAttrsToList (profileName: profile: [ (toString profile.path) nodeName profileName ]) node.profiles) deploy.nodes);
+                profiles = builtins.concatLists (final.lib.mapAttrsToList (nodeName: node: final.lib.mapAttrsToList (profileName: profile: [ (toString profile.path) nodeName profileName (profile.activate or "deploy-rs-activate") ]) node.profiles) deploy.nodes);
               in
               final.runCommandNoCC "deploy-rs-check-activate" { } ''
                 for x in ${builtins.concatStringsSep " " (map (p: builtins.concatStringsSep ":" p) profiles)}; do
                   profile_path=$(echo $x | cut -f1 -d:)
                   node_name=$(echo $x | cut -f2 -d:)
                   profile_name=$(echo $x | cut -f3 -d:)
+                  activate_script=$(echo $x | cut -f4 -d:)
 
-                  test -f "$profile_path/deploy-rs-activate" || (echo "#$node_name.$profile_name is missing the deploy-rs-activate activation script" && exit 1);
+                  test -f "$profile_path/$activate_script" || (echo "#$node_name.$profile_name is missing the $activate_script activation script" && exit 1);
 
                   test -f "$profile_path/activate-rs" || (echo "#$node_name.$profile_name is missing the activate-rs activation script" && exit 1);
                 done
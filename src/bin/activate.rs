@@ -8,6 +8,8 @@ use signal_hook::{consts::signal::SIGHUP, iterator::Signals};
 
 use clap::Clap;
 
+use deploy::data::ActivationMode;
+
 use tokio::fs;
 use tokio::process::Command;
 use tokio::sync::mpsc;
@@ -72,6 +74,14 @@ struct ActivateOpts {
     /// Path for any temporary files that may be needed during activation
     #[clap(long)]
     temp_path: String,
+
+    /// How to activate the profile
+    #[clap(long, arg_enum, default_value = "switch")]
+    mode: ActivationMode,
+
+    /// The activation script to run, relative to the profile
+    #[clap(long, default_value = "deploy-rs-activate")]
+    script: String,
 }
 
 /// Activate a profile
@@ -90,6 +100,14 @@ struct WaitOpts {
 struct RevokeOpts {
     /// The profile path to revoke
     profile_path: String,
+
+    /// How the profile was activated
+    #[clap(long, arg_enum, default_value = "switch")]
+    mode: ActivationMode,
+
+    /// The activation script to run when re-activating the previous generation, relative to the profile
+    #[clap(long, default_value = "deploy-rs-activate")]
+    script: String,
 }
 
 #[derive(Error, Debug)]
@@ -104,6 +122,10 @@ pub enum DeactivateError {
     ListGenExit(Option<i32>),
     #[error("Error converting generation list output to utf8: {0}")]
     DecodeListGenUtf8(std::string::FromUtf8Error),
+    #[error("Command for listing generations did not list any generations")]
+    NoGenerations,
+    #[error("Could not find the generation ID in generation entry: {0}")]
+    NoGenerationId(String),
     #[error("Failed to run command for deleting generation: {0}")]
     DeleteGen(std::io::Error),
     #[error("Command for deleting generations resulted in a bad exit code: {0:?}")]
@@ -114,12 +136,47 @@ pub enum DeactivateError {
     ReactivateExit(Option<i32>),
 }
 
-pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
+/// Runs the activation script of the current generation of the profile again
+async fn reactivate(
+    profile_path: &str,
+    mode: ActivationMode,
+    script: &str,
+) -> Result<(), DeactivateError> {
+    let re_activate_exit_status = Command::new(format!("{}/{}", profile_path, script))
+        .env("PROFILE", profile_path)
+        .env("ACTIVATION_MODE", mode.as_str())
+        .current_dir(profile_path)
+        .status()
+        .await
+        .map_err(DeactivateError::Reactivate)?;
+
+    match re_activate_exit_status.code() {
+        Some(0) => (),
+        a => return Err(DeactivateError::ReactivateExit(a)),
+    };
+
+    Ok(())
+}
+
+/// Undoes activating a profile the given way.
+/// A profile activated with `test` was never made the default, so its current generation is simply
+/// re-activated, otherwise the profile is rolled back to the generation before.
+pub async fn deactivate(
+    profile_path: &str,
+    mode: ActivationMode,
+    script: &str,
+) -> Result<(), DeactivateError> {
     warn!("De-activating due to error");
 
+    if mode == ActivationMode::Test {
+        info!("Attempting to re-activate the current generation");
+
+        return reactivate(profile_path, mode, script).await;
+    }
+
     let nix_env_rollback_exit_status = Command::new("nix-env")
         .arg("-p")
-        .arg(&profile_path)
+        .arg(profile_path)
         .arg("--rollback")
         .status()
         .await
@@ -134,7 +191,7 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
 
     let nix_env_list_generations_out = Command::new("nix-env")
         .arg("-p")
-        .arg(&profile_path)
+        .arg(profile_path)
         .arg("--list-generations")
         .output()
         .await
@@ -151,19 +208,19 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
     let last_generation_line = generations_list
         .lines()
         .last()
-        .expect("Expected to find a generation in list");
+        .ok_or(DeactivateError::NoGenerations)?;
 
     let last_generation_id = last_generation_line
         .split_whitespace()
         .next()
-        .expect("Expected to get ID from generation entry");
+        .ok_or_else(|| DeactivateError::NoGenerationId(last_generation_line.to_string()))?;
 
     debug!("Removing generation entry {}", last_generation_line);
     warn!("Removing generation by ID {}", last_generation_id);
 
     let nix_env_delete_generation_exit_status = Command::new("nix-env")
         .arg("-p")
-        .arg(&profile_path)
+        .arg(profile_path)
         .arg("--delete-generations")
         .arg(last_generation_id)
         .status()
@@ -177,19 +234,7 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
 
     info!("Attempting to re-activate the last generation");
 
-    let re_activate_exit_status = Command::new(format!("{}/deploy-rs-activate", profile_path))
-        .env("PROFILE", &profile_path)
-        .current_dir(&profile_path)
-        .status()
-        .await
-        .map_err(DeactivateError::Reactivate)?;
-
-    match re_activate_exit_status.code() {
-        Some(0) => (),
-        a => return Err(DeactivateError::ReactivateExit(a)),
-    };
-
-    Ok(())
+    reactivate(profile_path, mode, script).await
 }
 
 #[derive(Error, Debug)]
@@ -231,6 +276,8 @@ pub async fn activation_confirmation(
     temp_path: String,
     confirm_timeout: u16,
     closure: String,
+    mode: ActivationMode,
+    script: &str,
 ) -> Result<(), ActivationConfirmationError> {
     let lock_path = deploy::make_lock_path(&temp_path, &closure);
 
@@ -276,7 +323,7 @@ pub async fn activation_confirmation(
     if let Err(err) = danger_zone(done, confirm_timeout).await {
         error!("Error waiting for confirmation event: {}", err);
 
-        if let Err(err) = deactivate(&profile_path).await {
+        if let Err(err) = deactivate(&profile_path, mode, script).await {
             error!(
                 "Error de-activating due to another error waiting for confirmation, oh no...: {}",
                 err
@@ -338,6 +385,8 @@ pub async fn wait(temp_path: String, closure: String) -> Result<(), WaitError> {
 
 #[derive(Error, Debug)]
 pub enum ActivateError {
+    #[error("Failed to create the directory of the profile: {0}")]
+    CreateProfileDir(std::io::Error),
     #[error("Failed to execute the command for setting profile: {0}")]
     SetProfile(std::io::Error),
     #[error("The command for setting profile resulted in a bad exit code: {0:?}")]
@@ -355,6 +404,7 @@ pub enum ActivateError {
     ActivationConfirmation(#[from] ActivationConfirmationError),
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn activate(
     profile_path: String,
     closure: String,
@@ -363,8 +413,18 @@ pub async fn activate(
     confirm_timeout: u16,
     magic_rollback: bool,
     dry_activate: bool,
+    mode: ActivationMode,
+    script: String,
 ) -> Result<(), ActivateError> {
-    if !dry_activate {
+    // With `test`, the profile is activated straight from the closure, without making it the default
+    if !dry_activate && mode != ActivationMode::Test {
+        // Profiles of users might be the first one in their directory
+        if let Some(dir) = Path::new(&profile_path).parent() {
+            fs::create_dir_all(dir)
+                .await
+                .map_err(ActivateError::CreateProfileDir)?;
+        }
+
         info!("Activating profile");
         let nix_env_set_exit_status = Command::new("nix-env")
             .arg("-p")
@@ -378,7 +438,7 @@ pub async fn activate(
             Some(0) => (),
             a => {
                 if auto_rollback && !dry_activate {
-                    deactivate(&profile_path).await?;
+                    deactivate(&profile_path, mode, &script).await?;
                 }
                 return Err(ActivateError::SetProfileExit(a));
             }
@@ -387,15 +447,16 @@ pub async fn activate(
 
     debug!("Running activation script");
 
-    let activation_location = if dry_activate {
+    let activation_location = if dry_activate || mode == ActivationMode::Test {
         &closure
     } else {
         &profile_path
     };
 
-    let activate_status = match Command::new(format!("{}/deploy-rs-activate", activation_location))
+    let activate_status = match Command::new(format!("{}/{}", activation_location, script))
         .env("PROFILE", activation_location)
         .env("DRY_ACTIVATE", if dry_activate { "1" } else { "0" })
+        .env("ACTIVATION_MODE", mode.as_str())
         .current_dir(activation_location)
         .status()
         .await
@@ -404,7 +465,7 @@ pub async fn activate(
         Ok(x) => x,
         Err(e) => {
             if auto_rollback && !dry_activate {
-                deactivate(&profile_path).await?;
+                deactivate(&profile_path, mode, &script).await?;
             }
             return Err(e);
         }
@@ -415,7 +476,7 @@ pub async fn activate(
             Some(0) => (),
             a => {
                 if auto_rollback {
-                    deactivate(&profile_path).await?;
+                    deactivate(&profile_path, mode, &script).await?;
                 }
                 return Err(ActivateError::RunActivateExit(a));
             }
@@ -428,12 +489,19 @@ pub async fn activate(
         if magic_rollback {
             info!("Magic rollback is enabled, setting up confirmation hook...");
 
-            match activation_confirmation(profile_path.clone(), temp_path, confirm_timeout, closure)
-                .await
+            match activation_confirmation(
+                profile_path.clone(),
+                temp_path,
+                confirm_timeout,
+                closure,
+                mode,
+                &script,
+            )
+            .await
             {
                 Ok(()) => {}
                 Err(err) => {
-                    deactivate(&profile_path).await?;
+                    deactivate(&profile_path, mode, &script).await?;
                     return Err(ActivateError::ActivationConfirmation(err));
                 }
             };
@@ -443,15 +511,30 @@ pub async fn activate(
     Ok(())
 }
 
-async fn revoke(profile_path: String) -> Result<(), DeactivateError> {
-    deactivate(profile_path.as_str()).await?;
+async fn revoke(
+    profile_path: String,
+    mode: ActivationMode,
+    script: String,
+) -> Result<(), DeactivateError> {
+    deactivate(profile_path.as_str(), mode, &script).await?;
     Ok(())
 }
 
+/// Makes a profile path relative to the home directory (which commands run over SSH start in) absolute,
+/// as the activation script is run from the profile
+fn absolute_profile_path(profile_path: String) -> String {
+    match std::env::current_dir() {
+        Ok(dir) if Path::new(&profile_path).is_relative() => {
+            dir.join(&profile_path).to_string_lossy().into_owned()
+        }
+        _ => profile_path,
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Ensure that this process stays alive after the SSH connection dies
-    let mut signals = Signals::new(&[SIGHUP])?;
+    let mut signals = Signals::new([SIGHUP])?;
     std::thread::spawn(move || {
         for _ in signals.forever() {
             println!("Received SIGHUP - ignoring...");
@@ -463,6 +546,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     deploy::init_logger(
         opts.debug_logs,
         opts.log_dir.as_deref(),
+        None,
         &match opts.subcmd {
             SubCommand::Activate(_) => deploy::LoggerType::Activate,
             SubCommand::Wait(_) => deploy::LoggerType::Wait,
@@ -472,13 +556,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let r = match opts.subcmd {
         SubCommand::Activate(activate_opts) => activate(
-            activate_opts.profile_path,
+            absolute_profile_path(activate_opts.profile_path),
             activate_opts.closure,
             activate_opts.auto_rollback,
             activate_opts.temp_path,
             activate_opts.confirm_timeout,
             activate_opts.magic_rollback,
             activate_opts.dry_activate,
+            activate_opts.mode,
+            activate_opts.script,
         )
         .await
         .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
@@ -487,15 +573,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .await
             .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
 
-        SubCommand::Revoke(revoke_opts) => revoke(revoke_opts.profile_path)
-            .await
-            .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+        SubCommand::Revoke(revoke_opts) => revoke(
+            absolute_profile_path(revoke_opts.profile_path),
+            revoke_opts.mode,
+            revoke_opts.script,
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
     };
 
     match r {
         Ok(()) => (),
         Err(err) => {
             error!("{}", err);
+            // Tell `deploy` that the profile could not be rolled back, so the node needs attention
+            if let Some(ActivateError::Deactivate(_)) = err.downcast_ref::<ActivateError>() {
+                std::process::exit(deploy::deploy::ROLLBACK_FAILED_EXIT_CODE)
+            }
             std::process::exit(1)
         }
     }
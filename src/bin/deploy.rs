@@ -12,7 +12,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Ok(()) => (),
         Err(err) => {
             error!("{}", err);
-            std::process::exit(1);
+            std::process::exit(err.exit_code());
         }
     }
 
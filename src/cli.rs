@@ -3,20 +3,28 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use std::collections::HashMap;
-use std::io::{stdin, stdout, Write};
+use std::collections::{HashMap, VecDeque};
+use std::io::{stderr, stdin, IsTerminal, Write};
 
-use clap::{ArgMatches, Clap, FromArgMatches};
+use clap::{ArgEnum, ArgMatches, Clap, FromArgMatches};
 
 use crate as deploy;
 
-use self::deploy::{DeployFlake, ParseFlakeError};
+use self::deploy::{DeployFlake, LogCommand, ParseFlakeError};
+use futures_util::future::join_all;
 use futures_util::stream::{StreamExt, TryStreamExt};
 use log::{debug, error, info, warn};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
+use tokio::sync::{mpsc, Semaphore};
 
 /// Simple Rust rewrite of a simple Nix Flake deployment tool
 #[derive(Clap, Debug, Clone)]
@@ -32,15 +40,45 @@ pub struct Opts {
     /// Check signatures when using `nix copy`
     #[clap(short, long)]
     checksigs: bool,
-    /// Use the interactive prompt before deployment
+    /// Check signatures, but copy paths built locally without signatures anyway, with a warning (implies --checksigs)
+    #[clap(long)]
+    allow_unsigned_local: bool,
+    /// Use the interactive prompt before activating the profiles of each node, and before rolling back a profile,
+    /// including before not confirming an activation so that magic rollback rolls it back
     #[clap(short, long)]
     interactive: bool,
+    /// Answer yes to the interactive prompts, deploying without asking
+    #[clap(short, long)]
+    yes: bool,
     /// Extra arguments to be passed to nix build
     extra_build_args: Vec<String>,
+    /// File with more extra arguments for nix build, appended to the others. Either a JSON array, or one argument per line
+    #[clap(long)]
+    build_args_file: Option<String>,
+
+    /// The `nix` binary to run instead of the one in the `PATH`, with the other Nix tools (like `nix-build`) next to it
+    #[clap(long)]
+    nix_bin: Option<String>,
+    /// The `ssh` binary to run instead of the one in the `PATH`, also for the connections Nix makes to nodes
+    #[clap(long)]
+    ssh_bin: Option<String>,
+
+    /// Config file with defaults for the options, instead of `deploy.toml` in the current directory
+    #[clap(long)]
+    config: Option<String>,
 
     /// Print debug logs to output
     #[clap(short, long)]
     debug_logs: bool,
+    /// The most verbose level of logs to print, taking precedence over `RUST_LOG` and --debug-logs
+    #[clap(long, arg_enum)]
+    log_level: Option<deploy::LogLevel>,
+    /// Print debug logs, or with `-vv` trace logs including every command that is run
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u64,
+    /// Make SSH and the Nix commands connecting to nodes print what they are doing, given twice for more detail
+    #[clap(long, parse(from_occurrences))]
+    verbose_ssh: u8,
     /// Directory to print logs to (including the background activation process)
     #[clap(long)]
     log_dir: Option<String>,
@@ -52,7 +90,7 @@ pub struct Opts {
     #[clap(short, long)]
     result_path: Option<String>,
 
-    /// Skip the automatic pre-build checks
+    /// Skip the automatic pre-build checks, and only warn about problems in the deployment data
     #[clap(short, long)]
     skip_checks: bool,
 
@@ -74,37 +112,537 @@ pub struct Opts {
     /// Override hostname used for the node
     #[clap(long)]
     hostname: Option<String>,
+    /// Override both the SSH user and the hostname with `user@host`, or only the hostname without a user
+    #[clap(long = "target", conflicts_with_all = &["ssh-user", "hostname"])]
+    ssh_target: Option<String>,
     /// Make activation wait for confirmation, or roll back after a period of time
     #[clap(long)]
     magic_rollback: Option<bool>,
-    /// How long activation should wait for confirmation (if using magic-rollback)
+    /// How long activation should wait for confirmation (if using magic-rollback), or for the activation itself otherwise
     #[clap(long)]
     confirm_timeout: Option<u16>,
     /// Where to store temporary files (only used by magic-rollback)
     #[clap(long)]
     temp_path: Option<String>,
+    /// Where to install profiles that do not set `profilePath`, with `{user}` and `{profile}` replaced
+    #[clap(long)]
+    profile_path_template: Option<String>,
     /// Show what will be activated on the machines
     #[clap(long)]
     dry_activate: bool,
+    /// Print the commands that would be run to build, push and activate, without running them
+    #[clap(long)]
+    dry_run: bool,
     /// Revoke all previously succeeded deploys when deploying multiple profiles
     #[clap(long)]
     rollback_succeeded: Option<bool>,
-    /// Which sudo command to use. Must accept at least two arguments: user name to execute commands as and the rest is the command to execute
+    /// Which sudo command to use. Must accept at least two arguments: user name to execute commands as and the rest is the command to execute. `{user}` is replaced by the user name if present
+    #[clap(long)]
+    sudo: Option<String>,
+    /// Deploy to up to this many nodes at the same time, instead of one after another
+    #[clap(long)]
+    max_parallel: Option<usize>,
+    /// Only deploy to the given nodes, may be repeated or given as a comma-separated list. Names can be glob patterns like `web-*`
+    #[clap(long, number_of_values(1))]
+    node: Vec<String>,
+    /// Only deploy to the nodes listed in this file, one per line, after the ones given with --node. Blank lines and `#` comments are ignored
+    #[clap(long)]
+    node_file: Option<String>,
+    /// Only deploy to the nodes with this tag, may be repeated to deploy to the nodes with any of the tags
+    #[clap(long, number_of_values(1))]
+    tag: Vec<String>,
+    /// Only deploy to the nodes with all of the tags given with --tag, instead of any of them
+    #[clap(long)]
+    all_tags: bool,
+    /// How to report the results of the deployment, `json` prints a report to stdout
+    #[clap(long, arg_enum, default_value = "human")]
+    output: OutputFormat,
+    /// Write metrics of the deployment to this file in the Prometheus text format, for the node-exporter textfile collector
+    #[clap(long)]
+    metrics_file: Option<String>,
+    /// Override if profiles should be built on the target node instead of locally
+    #[clap(long)]
+    remote_build: Option<bool>,
+    /// Override if the nodes are the local machine, running commands directly instead of over SSH and not copying anything.
+    /// By default, this is the case for nodes with `localhost` as their hostname
+    #[clap(long)]
+    local: Option<bool>,
+    /// Override if a single SSH connection should be shared by every command run on a node
+    #[clap(long)]
+    ssh_multiplexing: Option<bool>,
+    /// How many times to retry copying a profile to a node if it fails, waiting longer after each attempt (0 by default)
+    #[clap(long)]
+    copy_retries: Option<u32>,
+    /// Abort the deployment if pushing and activating all profiles takes longer than this many seconds
+    #[clap(long)]
+    timeout: Option<u64>,
+    /// Kill building or copying a profile if it takes longer than this many seconds, failing the deployment of its node
+    #[clap(long)]
+    build_timeout: Option<u64>,
+    /// Override the host to jump through when connecting to the node
+    #[clap(long)]
+    jump_host: Option<String>,
+    /// Override how many seconds SSH waits for a connection to a node to be established
+    #[clap(long)]
+    ssh_connect_timeout: Option<u16>,
+    /// Override how many seconds an idle SSH connection waits before checking that the node is still alive (0 disables this)
+    #[clap(long)]
+    ssh_keepalive_interval: Option<u16>,
+    /// Override how many of these checks may go unanswered before SSH gives up on the connection
+    #[clap(long)]
+    ssh_keepalive_count_max: Option<u16>,
+    /// Override whether SSH forwards the local agent to the node, also during activation
+    #[clap(long)]
+    forward_agent: Option<bool>,
+    /// Override whether SSH authenticates with GSSAPI (Kerberos)
+    #[clap(long)]
+    gssapi: Option<bool>,
+    /// After a successful activation, delete all but this many of the most recent generations of the profile
+    #[clap(long)]
+    keep_generations: Option<u32>,
+    /// Hold a lock on the node while activating, so that deployments to the same node can not overlap
+    #[clap(long)]
+    remote_lock: bool,
+    /// How many seconds to wait for the lock of a node that is being deployed to by someone else (implies --remote-lock)
+    #[clap(long)]
+    lock_timeout: Option<u16>,
+    /// Load the deployment data from this JSON file (as output by `nix eval --json <flake>#deploy`) instead of evaluating the flake
+    #[clap(long)]
+    deploy_data_file: Option<String>,
+    /// Deploy this already built store path as the profile given with --profile to the single node given with --node,
+    /// reachable at --hostname, without evaluating any flake
+    #[clap(long, group = "deploy")]
+    store_path: Option<String>,
+    /// The name of the profile to deploy with --store-path, or else the only profile to deploy to every selected node that has it
+    #[clap(long)]
+    profile: Option<String>,
+    /// Always evaluate the flake, instead of reusing the evaluation cached for its locked revision
+    #[clap(long)]
+    no_eval_cache: bool,
+    /// Allow evaluating the flake impurely, reading the environment or files outside of it (never cached)
+    #[clap(long)]
+    impure: bool,
+    /// Extra argument to pass to nix when evaluating and checking the flake, but not when building, may be repeated
+    #[clap(long, number_of_values(1), allow_hyphen_values(true))]
+    eval_arg: Vec<String>,
+    /// Command to run locally when activating or confirming a profile fails, with `DEPLOY_RS_NODE`, `DEPLOY_RS_PROFILE` and `DEPLOY_RS_ERROR` set
+    #[clap(long)]
+    on_failure: Option<String>,
+    /// Reboot the node after activating each profile, and wait for it to come back
+    #[clap(long)]
+    reboot: bool,
+    /// How many seconds to wait for a node to come back after rebooting (implies --reboot)
+    #[clap(long)]
+    reboot_timeout: Option<u16>,
+    /// How many times to retry a failing `confirmCommand` before the profile is rolled back (0 by default)
+    #[clap(long)]
+    confirm_retries: Option<u32>,
+    /// How many seconds to wait before retrying a failing `confirmCommand` (5 by default)
+    #[clap(long)]
+    confirm_retry_delay: Option<u16>,
+    /// With --interactive, how many seconds to wait for an answer before rolling a profile back anyway (60 by default)
+    #[clap(long)]
+    rollback_prompt_timeout: Option<u16>,
+    /// Confirm activations (with magic-rollback) over a new SSH connection instead of a shared one,
+    /// so that activations breaking SSH access are rolled back
+    #[clap(long)]
+    confirm_via_new_ssh: bool,
+    /// Override how profiles are activated, `boot` only activates them on the next boot and `test` does not keep them after it
+    #[clap(long, arg_enum)]
+    activation_mode: Option<deploy::data::ActivationMode>,
+    /// SSH config file to use instead of the default one, for every connection to the nodes
+    #[clap(long)]
+    ssh_config_file: Option<String>,
+    /// Whether to stop or to continue with the other nodes when deploying to a node fails. By default, deployment stops unless nodes are deployed in parallel
+    #[clap(long, arg_enum)]
+    on_error: Option<OnError>,
+    /// Environment variable to set for the activation script, as `KEY=VALUE`, may be repeated
+    #[clap(long, number_of_values(1))]
+    activation_env: Vec<String>,
+    /// After copying, check that the profiles are valid on the node (and signed by a trusted key with --checksigs) before activating them
+    #[clap(long)]
+    verify_copy: bool,
+    /// Limit the bandwidth of copying profiles to the nodes to this many KiB/s (using `pv`)
+    #[clap(long)]
+    copy_bwlimit: Option<u32>,
+    /// How to compress the profiles while copying them, `none` is faster on fast links. Over SSH, this is `none` or `ssh`
+    /// (the compression of SSH itself), and for the binary cache of --to-cache `none`, `zstd` or `xz`
+    #[clap(long, arg_enum)]
+    copy_compression: Option<deploy::CopyCompression>,
+    /// The compression level with `--copy-compression zstd` or `xz` for the binary cache of --to-cache, like 3 or 6
+    #[clap(long)]
+    copy_compression_level: Option<u32>,
+    /// Copy the profiles to this binary cache, and make the nodes substitute them from it, instead of copying them over SSH
+    #[clap(long)]
+    to_cache: Option<String>,
+    /// Copy the profiles of the next node while the current one is activated, instead of copying everything before activating
     #[clap(long)]
+    pipeline: bool,
+    /// With --pipeline, wait for all profiles of a node to be copied before activating any of them, so that none are if one fails to copy
+    #[clap(long)]
+    no_activate_if_push_failed: bool,
+    /// Activate the profiles without building or copying them, after checking that they are already on the nodes
+    #[clap(long)]
+    skip_push: bool,
+    /// Run activation in a transient systemd scope (with `systemd-run --scope`), so that it keeps running if the SSH connection drops
+    #[clap(long)]
+    systemd_run: bool,
+    /// Do not check that all nodes are reachable over SSH before building anything
+    #[clap(long)]
+    skip_preflight: bool,
+    /// Print the settings each selected profile would be deployed with (after applying all overrides) as JSON, without deploying
+    #[clap(long)]
+    print_deploy_data: bool,
+    /// Print a table of what deploying would do for each selected profile, checking which profiles the nodes already have, without deploying
+    #[clap(long)]
+    plan: bool,
+    /// After activating a profile, log the changes to its closure (with `nix store diff-closures` on the node)
+    #[clap(long)]
+    diff_closures: bool,
+    /// Activate profiles even if their node already points them at the store path being deployed
+    #[clap(long)]
+    force: bool,
+    /// Only deploy the profiles whose store path is not already active on their node
+    #[clap(long)]
+    skip_unchanged: bool,
+    /// Deploy the nodes in reverse order, with nodes deployed before the nodes they are `after`
+    #[clap(long)]
+    reverse: bool,
+    /// Binary cache for the nodes to substitute paths from when copying or building on them, may be repeated
+    #[clap(long, number_of_values(1))]
+    substituter: Vec<String>,
+    /// Public key the nodes should trust for paths substituted from the binary caches, may be repeated
+    #[clap(long, number_of_values(1))]
+    trusted_public_key: Vec<String>,
+    /// Log the closure size of each profile before copying it to the node
+    #[clap(long)]
+    show_closure_size: bool,
+
+    #[clap(subcommand)]
+    subcommand: Option<Subcommand>,
+}
+
+/// Defaults for the options of `Opts`, read from `deploy.toml` or the file given with `--config`.
+/// Options given on the command line take precedence over these. Options that select what is deployed
+/// or how the command runs this once (like the targets, `--node` or `--dry-run`) can only be given on the command line.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    checksigs: Option<bool>,
+    allow_unsigned_local: Option<bool>,
+    no_activate_if_push_failed: Option<bool>,
+    skip_checks: Option<bool>,
+    debug_logs: Option<bool>,
+    keep_result: Option<bool>,
+    verify_copy: Option<bool>,
+    confirm_via_new_ssh: Option<bool>,
+    systemd_run: Option<bool>,
+    skip_preflight: Option<bool>,
+    pipeline: Option<bool>,
+    diff_closures: Option<bool>,
+    skip_unchanged: Option<bool>,
+    show_closure_size: Option<bool>,
+    impure: Option<bool>,
+    no_eval_cache: Option<bool>,
+    verbose_ssh: Option<u8>,
+    log_dir: Option<String>,
+    result_path: Option<String>,
+    build_args_file: Option<String>,
+    metrics_file: Option<String>,
+    log_level: Option<deploy::LogLevel>,
+    ssh_user: Option<String>,
+    profile_user: Option<String>,
+    ssh_opts: Option<String>,
+    fast_connection: Option<bool>,
+    auto_rollback: Option<bool>,
+    magic_rollback: Option<bool>,
+    confirm_timeout: Option<u16>,
+    temp_path: Option<String>,
+    profile_path_template: Option<String>,
+    rollback_succeeded: Option<bool>,
     sudo: Option<String>,
+    max_parallel: Option<usize>,
+    remote_build: Option<bool>,
+    local: Option<bool>,
+    ssh_multiplexing: Option<bool>,
+    jump_host: Option<String>,
+    ssh_connect_timeout: Option<u16>,
+    ssh_keepalive_interval: Option<u16>,
+    ssh_keepalive_count_max: Option<u16>,
+    forward_agent: Option<bool>,
+    gssapi: Option<bool>,
+    keep_generations: Option<u32>,
+    remote_lock: Option<bool>,
+    lock_timeout: Option<u16>,
+    on_failure: Option<String>,
+    activation_mode: Option<deploy::data::ActivationMode>,
+    ssh_config_file: Option<String>,
+    on_error: Option<OnError>,
+    nix_bin: Option<String>,
+    ssh_bin: Option<String>,
+    copy_retries: Option<u32>,
+    timeout: Option<u64>,
+    build_timeout: Option<u64>,
+    confirm_retries: Option<u32>,
+    confirm_retry_delay: Option<u16>,
+    rollback_prompt_timeout: Option<u16>,
+    copy_bwlimit: Option<u32>,
+    copy_compression: Option<deploy::CopyCompression>,
+    copy_compression_level: Option<u32>,
+    to_cache: Option<String>,
+    activation_env: Option<Vec<String>>,
+    substituter: Option<Vec<String>>,
+    trusted_public_key: Option<Vec<String>>,
+    eval_arg: Option<Vec<String>>,
+}
+
+/// The file `deploy.toml` is looked for in the current directory if `--config` is not given
+const DEFAULT_CONFIG_FILE: &str = "deploy.toml";
+
+/// Reads the config file, returning its path along with it. Without `--config`, a missing `deploy.toml` is no error.
+fn load_config(config: Option<&str>) -> Result<Option<(String, ConfigFile)>, RunError> {
+    let path = match config {
+        Some(path) => path,
+        None if std::path::Path::new(DEFAULT_CONFIG_FILE).is_file() => DEFAULT_CONFIG_FILE,
+        None => return Ok(None),
+    };
+
+    let config = toml::from_str(
+        &std::fs::read_to_string(path).map_err(|e| RunError::ConfigFile(path.to_string(), e))?,
+    )
+    .map_err(|e| RunError::ConfigFileParse(path.to_string(), e))?;
+
+    Ok(Some((path.to_string(), config)))
+}
+
+impl Opts {
+    /// Uses the values of the config file for the options not given on the command line
+    fn apply_config(&mut self, config: ConfigFile) {
+        fn or<T>(opt: &mut Option<T>, default: Option<T>) {
+            if opt.is_none() {
+                *opt = default;
+            }
+        }
+
+        fn or_vec<T>(opt: &mut Vec<T>, default: Option<Vec<T>>) {
+            if opt.is_empty() {
+                *opt = default.unwrap_or_default();
+            }
+        }
+
+        // Flags are off unless given, so `false` in the config file is the same as leaving them out,
+        // and `true` is like giving them on the command line
+        self.checksigs |= config.checksigs.unwrap_or(false);
+        self.allow_unsigned_local |= config.allow_unsigned_local.unwrap_or(false);
+        self.no_activate_if_push_failed |= config.no_activate_if_push_failed.unwrap_or(false);
+        self.skip_checks |= config.skip_checks.unwrap_or(false);
+        self.remote_lock |= config.remote_lock.unwrap_or(false);
+        self.debug_logs |= config.debug_logs.unwrap_or(false);
+        self.keep_result |= config.keep_result.unwrap_or(false);
+        self.verify_copy |= config.verify_copy.unwrap_or(false);
+        self.confirm_via_new_ssh |= config.confirm_via_new_ssh.unwrap_or(false);
+        self.systemd_run |= config.systemd_run.unwrap_or(false);
+        self.skip_preflight |= config.skip_preflight.unwrap_or(false);
+        self.pipeline |= config.pipeline.unwrap_or(false);
+        self.diff_closures |= config.diff_closures.unwrap_or(false);
+        self.skip_unchanged |= config.skip_unchanged.unwrap_or(false);
+        self.show_closure_size |= config.show_closure_size.unwrap_or(false);
+        self.impure |= config.impure.unwrap_or(false);
+        self.no_eval_cache |= config.no_eval_cache.unwrap_or(false);
+
+        if self.verbose_ssh == 0 {
+            self.verbose_ssh = config.verbose_ssh.unwrap_or(0);
+        }
+
+        or(&mut self.log_level, config.log_level);
+        or(&mut self.ssh_user, config.ssh_user);
+        or(&mut self.profile_user, config.profile_user);
+        or(&mut self.ssh_opts, config.ssh_opts);
+        or(&mut self.fast_connection, config.fast_connection);
+        or(&mut self.auto_rollback, config.auto_rollback);
+        or(&mut self.magic_rollback, config.magic_rollback);
+        or(&mut self.confirm_timeout, config.confirm_timeout);
+        or(&mut self.temp_path, config.temp_path);
+        or(
+            &mut self.profile_path_template,
+            config.profile_path_template,
+        );
+        or(&mut self.rollback_succeeded, config.rollback_succeeded);
+        or(&mut self.sudo, config.sudo);
+        or(&mut self.max_parallel, config.max_parallel);
+        or(&mut self.remote_build, config.remote_build);
+        or(&mut self.local, config.local);
+        or(&mut self.ssh_multiplexing, config.ssh_multiplexing);
+        or(&mut self.jump_host, config.jump_host);
+        or(&mut self.ssh_connect_timeout, config.ssh_connect_timeout);
+        or(
+            &mut self.ssh_keepalive_interval,
+            config.ssh_keepalive_interval,
+        );
+        or(
+            &mut self.ssh_keepalive_count_max,
+            config.ssh_keepalive_count_max,
+        );
+        or(&mut self.forward_agent, config.forward_agent);
+        or(&mut self.gssapi, config.gssapi);
+        or(&mut self.keep_generations, config.keep_generations);
+        or(&mut self.lock_timeout, config.lock_timeout);
+        or(&mut self.on_failure, config.on_failure);
+        or(&mut self.activation_mode, config.activation_mode);
+        or(&mut self.ssh_config_file, config.ssh_config_file);
+        or(&mut self.on_error, config.on_error);
+        or(&mut self.nix_bin, config.nix_bin);
+        or(&mut self.ssh_bin, config.ssh_bin);
+        or(&mut self.log_dir, config.log_dir);
+        or(&mut self.result_path, config.result_path);
+        or(&mut self.build_args_file, config.build_args_file);
+        or(&mut self.metrics_file, config.metrics_file);
+        or(&mut self.copy_retries, config.copy_retries);
+        or(&mut self.timeout, config.timeout);
+        or(&mut self.build_timeout, config.build_timeout);
+        or(&mut self.confirm_retries, config.confirm_retries);
+        or(&mut self.confirm_retry_delay, config.confirm_retry_delay);
+        or(
+            &mut self.rollback_prompt_timeout,
+            config.rollback_prompt_timeout,
+        );
+        or(&mut self.copy_bwlimit, config.copy_bwlimit);
+        or(&mut self.copy_compression, config.copy_compression);
+        or(
+            &mut self.copy_compression_level,
+            config.copy_compression_level,
+        );
+        or(&mut self.to_cache, config.to_cache);
+
+        // Lists given on the command line replace those of the config file instead of adding to them
+        or_vec(&mut self.activation_env, config.activation_env);
+        or_vec(&mut self.substituter, config.substituter);
+        or_vec(&mut self.trusted_public_key, config.trusted_public_key);
+        or_vec(&mut self.eval_arg, config.eval_arg);
+    }
+}
+
+#[test]
+fn test_apply_config() {
+    let config: ConfigFile = toml::from_str(
+        r#"
+            checksigs = false
+            skip_checks = true
+            copy_retries = 1
+            timeout = 600
+            copy_compression = "ssh"
+            substituter = ["https://cache.example.com"]
+            activation_env = ["STAGE=prod"]
+        "#,
+    )
+    .unwrap();
+
+    let mut opts = Opts::try_parse_from([
+        "deploy",
+        "--copy-retries",
+        "3",
+        "--activation-env",
+        "STAGE=test",
+    ])
+    .unwrap();
+    opts.apply_config(config);
+
+    assert!(!opts.checksigs);
+    assert!(opts.skip_checks);
+    assert_eq!(opts.copy_retries, Some(3));
+    assert_eq!(opts.timeout, Some(600));
+    assert_eq!(opts.build_timeout, None);
+    assert_eq!(opts.copy_compression, Some(deploy::CopyCompression::Ssh));
+    assert_eq!(opts.substituter, vec!["https://cache.example.com"]);
+    assert_eq!(opts.activation_env, vec!["STAGE=test"]);
+
+    assert!(toml::from_str::<ConfigFile>("dry_run = true").is_err());
+}
+
+#[derive(Clap, Debug, Clone)]
+pub enum Subcommand {
+    /// Roll the profiles of a node back to their previous generation, without deploying anything
+    Rollback(RollbackOpts),
+}
+
+#[derive(Clap, Debug, Clone)]
+pub struct RollbackOpts {
+    /// The node to roll back, as `<flake>#<node>` for all of its profiles or `<flake>#<node>.<profile>` for one of them
+    target: String,
+}
+
+/// Rolls back the profile selected by `deploy_flake` on its node, or all profiles of the node in reverse order
+async fn run_rollback(
+    deploy_flake: &deploy::DeployFlake<'_>,
+    data: &deploy::data::Data,
+    cmd_overrides: &deploy::CmdOverrides,
+    debug_logs: bool,
+    log_dir: Option<&str>,
+) -> Result<(), RunError> {
+    let node_name = deploy_flake
+        .node
+        .as_ref()
+        .ok_or_else(|| RunError::RollbackNoNode(deploy_flake.repo.to_string()))?;
+
+    let node = data
+        .nodes
+        .get(node_name)
+        .ok_or_else(|| node_not_found(data, node_name))?;
+
+    let mut profiles = match &deploy_flake.profile {
+        Some(profile_name) => match node.node_settings.profiles.get(profile_name) {
+            Some(profile) => vec![(profile_name.as_str(), profile)],
+            None => return Err(profile_not_found(profile_name, &[node]).into()),
+        },
+        None => node_profiles(node)?,
+    };
+
+    // Profiles activated later may depend on earlier ones, so they are rolled back first
+    profiles.reverse();
+
+    let mut srv_resolved = HashMap::new();
+
+    for (profile_name, profile) in profiles {
+        let mut deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            node,
+            node_name,
+            profile,
+            profile_name,
+            cmd_overrides,
+            debug_logs,
+            log_dir,
+        );
+
+        deploy_data
+            .resolve_srv_hostname(&mut srv_resolved)
+            .await
+            .map_err(RunDeployError::DeployDataDefs)?;
+
+        let deploy_defs = deploy_data.defs().map_err(RunDeployError::DeployDataDefs)?;
+
+        let result = deploy::deploy::rollback(&deploy_data, &deploy_defs).await;
+
+        deploy::deploy::close_ssh_master(&deploy_data, &deploy_defs).await;
+
+        result?;
+    }
+
+    Ok(())
 }
 
 /// Returns if the available Nix installation supports flakes
 async fn test_flake_support() -> Result<bool, std::io::Error> {
     debug!("Checking for flake support");
 
-    Ok(Command::new("nix")
+    Ok(Command::new(deploy::nix_tool("nix"))
         .arg("eval")
         .arg("--expr")
         .arg("builtins.getFlake")
         // This will error on some machines "intentionally", and we don't really need that printing
         .stdout(Stdio::null())
         .stderr(Stdio::null())
+        .logged()
         .status()
         .await?
         .success())
@@ -113,36 +651,74 @@ async fn test_flake_support() -> Result<bool, std::io::Error> {
 #[derive(Error, Debug)]
 pub enum CheckDeploymentError {
     #[error("Failed to execute Nix checking command: {0}")]
-    NixCheck(#[from] std::io::Error),
+    NixCheck(std::io::Error),
     #[error("Nix checking command resulted in a bad exit code: {0:?}")]
     NixCheckExit(Option<i32>),
+    #[error("Failed to find the repository {0}: {1}")]
+    ClassicRepo(String, std::io::Error),
+}
+
+/// Makes an expression for `nix-instantiate` and `nix-build` importing a classic (non-flake) repository,
+/// given to them with `--argstr repo <path>` so that the path does not need to be valid Nix syntax.
+/// `body` can refer to the imported repository as `x`.
+fn classic_repo_expression(body: &str) -> String {
+    format!(
+        "{{ repo }}: let r = import (/. + repo); x = if builtins.isFunction r then r {{ }} else r; in {}",
+        body
+    )
+}
+
+/// The absolute path of a classic repository, as the expressions importing it expect
+fn classic_repo_path(repo: &str) -> Result<String, std::io::Error> {
+    Ok(std::fs::canonicalize(repo)?.to_string_lossy().into_owned())
+}
+
+/// Returns if the repository is a local directory without a `flake.nix`, which can only be evaluated the classic way
+fn is_classic_repo(repo: &str) -> bool {
+    let path = std::path::Path::new(repo);
+
+    path.is_dir() && !path.join("flake.nix").exists()
 }
 
 async fn check_deployment(
     supports_flakes: bool,
     repo: &str,
     extra_build_args: &[String],
+    eval_args: &[String],
 ) -> Result<(), CheckDeploymentError> {
     info!("Running checks for flake in {}", repo);
 
     let mut check_command = match supports_flakes {
-        true => Command::new("nix"),
-        false => Command::new("nix-build"),
+        true => Command::new(deploy::nix_tool("nix")),
+        false => Command::new(deploy::nix_tool("nix-build")),
     };
 
     if supports_flakes {
         check_command.arg("flake").arg("check").arg(repo);
     } else {
-        check_command.arg("-E")
-                .arg("--no-out-link")
-                .arg(format!("let r = import {}/.; x = (if builtins.isFunction r then (r {{}}) else r); in if x ? checks then x.checks.${{builtins.currentSystem}} else {{}}", repo));
+        check_command
+            .arg("--no-out-link")
+            .arg("--argstr")
+            .arg("repo")
+            .arg(
+                classic_repo_path(repo)
+                    .map_err(|e| CheckDeploymentError::ClassicRepo(repo.to_string(), e))?,
+            )
+            .arg("-E")
+            .arg(classic_repo_expression(
+                "if x ? checks then x.checks.${builtins.currentSystem} else { }",
+            ));
     }
 
-    for extra_arg in extra_build_args {
+    for extra_arg in extra_build_args.iter().chain(eval_args) {
         check_command.arg(extra_arg);
     }
 
-    let check_status = check_command.status().await?;
+    let check_status = check_command
+        .logged()
+        .status()
+        .await
+        .map_err(CheckDeploymentError::NixCheck)?;
 
     match check_status.code() {
         Some(0) => (),
@@ -158,43 +734,199 @@ pub enum GetDeploymentDataError {
     NixEval(std::io::Error),
     #[error("Failed to read output from evaluation: {0}")]
     NixEvalOut(std::io::Error),
-    #[error("Evaluation resulted in a bad exit code: {0:?}")]
-    NixEvalExit(Option<i32>),
+    #[error("Evaluation resulted in a bad exit code: {0:?}, its last output was:\n{1}")]
+    NixEvalExit(Option<i32>, String),
     #[error("Error converting evaluation output to utf8: {0}")]
     DecodeUtf8(#[from] std::string::FromUtf8Error),
     #[error("Error decoding the JSON from evaluation: {0}")]
     DecodeJson(#[from] serde_json::error::Error),
     #[error("Impossible happened: profile is set but node is not")]
     ProfileNoNode,
+    #[error("Failed to find the repository {0}: {1}")]
+    ClassicRepo(String, std::io::Error),
+}
+
+/// Returns the key to cache the evaluation of `flake` under, as long as the flake is locked to a revision.
+/// Flakes without a revision (like dirty git trees or paths) can change without a new key, so they are never cached.
+async fn eval_cache_key(
+    flake: &deploy::DeployFlake<'_>,
+    extra_build_args: &[String],
+    eval_args: &[String],
+) -> Option<String> {
+    let metadata_output = Command::new(deploy::nix_tool("nix"))
+        .arg("flake")
+        .arg("metadata")
+        .arg("--json")
+        .arg(flake.repo)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .logged()
+        .output()
+        .await;
+
+    let metadata: serde_json::Value = match metadata_output {
+        Ok(output) if output.status.success() => serde_json::from_slice(&output.stdout).ok()?,
+        _ => {
+            debug!(
+                "Could not get the metadata of flake {}, not caching its evaluation",
+                flake.repo
+            );
+            return None;
+        }
+    };
+
+    let revision = match metadata.get("revision").and_then(|x| x.as_str()) {
+        Some(revision) => revision,
+        None => {
+            debug!(
+                "Flake {} is not locked to a revision, not caching its evaluation",
+                flake.repo
+            );
+            return None;
+        }
+    };
+
+    // Everything else that changes the result of the evaluation is part of the key as well
+    Some(
+        serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "url": metadata.get("url"),
+            "revision": revision,
+            "node": flake.node,
+            "profile": flake.profile,
+            "extraBuildArgs": extra_build_args,
+            "evalArgs": eval_args,
+        })
+        .to_string(),
+    )
+}
+
+/// The file the evaluation cached under `key` is stored in, in `$XDG_CACHE_HOME/deploy-rs/eval`
+fn eval_cache_path(key: &str) -> Option<PathBuf> {
+    let cache_dir = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+
+    Some(
+        cache_dir
+            .join("deploy-rs")
+            .join("eval")
+            .join(eval_cache_file_name(key)),
+    )
+}
+
+/// The name of the file the evaluation cached under `key` is stored in, the SHA-256 of the key.
+/// It has to stay the same across builds of deploy-rs, which the hashers of std do not promise.
+fn eval_cache_file_name(key: &str) -> String {
+    let hash: String = Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect();
+
+    format!("{}.json", hash)
+}
+
+#[test]
+fn test_eval_cache_file_name() {
+    assert_eq!(
+        eval_cache_file_name("abc"),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad.json"
+    );
+}
+
+/// Reads the evaluation cached under `key`. The key is stored along with the evaluation, so that
+/// a colliding file name does not result in using the evaluation of another flake
+fn read_eval_cache(key: &str) -> Option<String> {
+    let cached: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(eval_cache_path(key)?).ok()?).ok()?;
+
+    if cached.get("key")?.as_str()? != key {
+        return None;
+    }
+
+    Some(cached.get("data")?.as_str()?.to_string())
+}
+
+/// Caches the evaluation under `key`, failing to do so only results in a warning
+fn write_eval_cache(key: &str, data_json: &str) {
+    let path = match eval_cache_path(key) {
+        Some(path) => path,
+        None => return,
+    };
+
+    let result = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| {
+            std::fs::write(
+                &path,
+                serde_json::json!({ "key": key, "data": data_json }).to_string(),
+            )
+        });
+
+    if let Err(e) = result {
+        warn!(
+            "Failed to write the evaluation cache {}: {}",
+            path.display(),
+            e
+        );
+    }
 }
 
+/// How many of the last lines Nix printed are included in the error when evaluating fails
+const EVAL_ERROR_LINES: usize = 20;
+
 /// Evaluates the Nix in the given `repo` and return the processed Data from it
 async fn get_deployment_data(
     supports_flakes: bool,
     flakes: &[deploy::DeployFlake<'_>],
     extra_build_args: &[String],
+    eval_args: &[String],
+    eval_cache: bool,
 ) -> Result<Vec<deploy::data::Data>, GetDeploymentDataError> {
-    futures_util::stream::iter(flakes).then(|flake| async move {
+    // An impure evaluation can change without the flake changing
+    let impure = eval_args.iter().any(|x| x == "--impure");
+
+    futures_util::stream::iter(flakes)
+        .then(|flake| async move {
+            let cache_key = match eval_cache && supports_flakes && !impure {
+                true => eval_cache_key(flake, extra_build_args, eval_args).await,
+                false => None,
+            };
+
+            if let Some(data_json) = cache_key.as_deref().and_then(read_eval_cache) {
+                match serde_json::from_str(&data_json) {
+                    Ok(data) => {
+                        info!("Using the cached evaluation of flake in {}", flake.repo);
+                        return Ok(data);
+                    }
+                    Err(e) => warn!(
+                        "Ignoring the evaluation cache of flake in {}: {}",
+                        flake.repo, e
+                    ),
+                }
+            }
 
-    info!("Evaluating flake in {}", flake.repo);
+            info!("Evaluating flake in {}", flake.repo);
 
-    let mut c = if supports_flakes {
-        Command::new("nix")
-    } else {
-        Command::new("nix-instantiate")
-    };
+            let mut c = if supports_flakes {
+                Command::new(deploy::nix_tool("nix"))
+            } else {
+                Command::new(deploy::nix_tool("nix-instantiate"))
+            };
 
-    if supports_flakes {
-        c.arg("eval")
-            .arg("--json")
-            .arg(format!("{}#deploy", flake.repo))
-            // We use --apply instead of --expr so that we don't have to deal with builtins.getFlake
-            .arg("--apply");
-        match (&flake.node, &flake.profile) {
-            (Some(node), Some(profile)) => {
-                // Ignore all nodes and all profiles but the one we're evaluating
-                c.arg(format!(
-                    r#"
+            if supports_flakes {
+                c.arg("eval")
+                    .arg("--json")
+                    .arg(format!("{}#deploy", flake.repo))
+                    // We use --apply instead of --expr so that we don't have to deal with builtins.getFlake
+                    .arg("--apply");
+                match (&flake.node, &flake.profile) {
+                    (Some(node), Some(profile)) => {
+                        // Ignore all nodes and all profiles but the one we're evaluating
+                        c.arg(format!(
+                            r#"
                       deploy:
                       (deploy // {{
                         nodes = {{
@@ -206,13 +938,13 @@ async fn get_deployment_data(
                         }};
                       }})
                      "#,
-                    node, profile
-                ))
-            }
-            (Some(node), None) => {
-                // Ignore all nodes but the one we're evaluating
-                c.arg(format!(
-                    r#"
+                            node, profile
+                        ))
+                    }
+                    (Some(node), None) => {
+                        // Ignore all nodes but the one we're evaluating
+                        c.arg(format!(
+                            r#"
                       deploy:
                       (deploy // {{
                         nodes = {{
@@ -220,48 +952,143 @@ async fn get_deployment_data(
                         }};
                       }})
                     "#,
-                    node
-                ))
-            }
-            (None, None) => {
-                // We need to evaluate all profiles of all nodes anyway, so just do it strictly
-                c.arg("deploy: deploy")
+                            node
+                        ))
+                    }
+                    (None, None) => {
+                        // We need to evaluate all profiles of all nodes anyway, so just do it strictly
+                        c.arg("deploy: deploy")
+                    }
+                    (None, Some(_)) => return Err(GetDeploymentDataError::ProfileNoNode),
+                }
+            } else {
+                c.arg("--strict")
+                    .arg("--read-write-mode")
+                    .arg("--json")
+                    .arg("--eval")
+                    .arg("--argstr")
+                    .arg("repo")
+                    .arg(classic_repo_path(flake.repo).map_err(|e| {
+                        GetDeploymentDataError::ClassicRepo(flake.repo.to_string(), e)
+                    })?)
+                    .arg("-E")
+                    .arg(classic_repo_expression("x.deploy"))
+            };
+
+            for extra_arg in extra_build_args.iter().chain(eval_args) {
+                c.arg(extra_arg);
             }
-            (None, Some(_)) => return Err(GetDeploymentDataError::ProfileNoNode),
-        }
-    } else {
-        c
-            .arg("--strict")
-            .arg("--read-write-mode")
-            .arg("--json")
-            .arg("--eval")
-            .arg("-E")
-            .arg(format!("let r = import {}/.; in if builtins.isFunction r then (r {{}}).deploy else r.deploy", flake.repo))
-    };
 
-    for extra_arg in extra_build_args {
-        c.arg(extra_arg);
-    }
+            let mut build_child = c
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .logged()
+                .spawn()
+                .map_err(GetDeploymentDataError::NixEval)?;
 
-    let build_child = c
-        .stdout(Stdio::piped())
-        .spawn()
-        .map_err(GetDeploymentDataError::NixEval)?;
+            let stderr = build_child
+                .stderr
+                .take()
+                .expect("Evaluation stderr should be piped");
+
+            // The output is still shown while evaluating, and its end is kept for the error
+            let stderr_tail = async {
+                let mut lines = BufReader::new(stderr).lines();
+                let mut tail: VecDeque<String> = VecDeque::new();
+
+                while let Ok(Some(line)) = lines.next_line().await {
+                    eprintln!("{}", line);
+
+                    if tail.len() == EVAL_ERROR_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                }
+
+                tail
+            };
+
+            let (build_output, stderr_tail) =
+                tokio::join!(build_child.wait_with_output(), stderr_tail);
+
+            let build_output = build_output.map_err(GetDeploymentDataError::NixEvalOut)?;
+
+            match build_output.status.code() {
+                Some(0) => (),
+                a => {
+                    return Err(GetDeploymentDataError::NixEvalExit(
+                        a,
+                        Vec::from(stderr_tail).join("\n"),
+                    ))
+                }
+            };
+
+            let data_json = String::from_utf8(build_output.stdout)?;
+            let data = serde_json::from_str(&data_json)?;
+
+            if let Some(key) = cache_key {
+                write_eval_cache(&key, &data_json);
+            }
 
-    let build_output = build_child
-        .wait_with_output()
+            Ok(data)
+        })
+        .try_collect()
         .await
-        .map_err(GetDeploymentDataError::NixEvalOut)?;
+}
 
-    match build_output.status.code() {
-        Some(0) => (),
-        a => return Err(GetDeploymentDataError::NixEvalExit(a)),
-    };
+/// Checks the evaluated deployment data for problems that would otherwise only show up in the middle of deploying,
+/// returning all of them at once
+fn validate_deployment_data(
+    deploy_flake: &deploy::DeployFlake<'_>,
+    data: &deploy::data::Data,
+    cmd_overrides: &deploy::CmdOverrides,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let mut node_names: Vec<&String> = data.nodes.keys().collect();
+    node_names.sort_unstable();
+
+    for node_name in node_names {
+        let node = &data.nodes[node_name];
+
+        if node.node_settings.profiles.is_empty() {
+            problems.push(format!("Node `{}` has no profiles", node_name));
+        }
+
+        // When a single profile is deployed, the others are not evaluated at all
+        if deploy_flake.profile.is_none() {
+            for profile_name in &node.node_settings.profiles_order {
+                if !node.node_settings.profiles.contains_key(profile_name) {
+                    problems.push(format!(
+                        "Profile `{}` in `profilesOrder` of node `{}` does not exist",
+                        profile_name, node_name
+                    ));
+                }
+            }
+        }
+
+        let mut profile_names: Vec<&String> = node.node_settings.profiles.keys().collect();
+        profile_names.sort_unstable();
+
+        for profile_name in profile_names {
+            let deploy_data = deploy::make_deploy_data(
+                &data.generic_settings,
+                node,
+                node_name,
+                &node.node_settings.profiles[profile_name],
+                profile_name,
+                cmd_overrides,
+                false,
+                None,
+            );
 
-    let data_json = String::from_utf8(build_output.stdout)?;
+            if let Err(e) = deploy_data.defs() {
+                problems.push(e.to_string());
+            }
+        }
+    }
 
-    Ok(serde_json::from_str(&data_json)?)
-}).try_collect().await
+    problems
 }
 
 #[derive(Serialize)]
@@ -273,26 +1100,20 @@ struct PromptPart<'a> {
     ssh_opts: &'a [String],
 }
 
-fn print_deployment(
-    parts: &[(
-        &deploy::DeployFlake<'_>,
-        deploy::DeployData,
-        deploy::DeployDefs,
-    )],
-) -> Result<(), toml::ser::Error> {
+fn print_deployment(parts: &[&Part<'_>]) -> Result<(), toml::ser::Error> {
     let mut part_map: HashMap<String, HashMap<String, PromptPart>> = HashMap::new();
 
     for (_, data, defs) in parts {
         part_map
             .entry(data.node_name.to_string())
-            .or_insert_with(HashMap::new)
+            .or_default()
             .insert(
                 data.profile_name.to_string(),
                 PromptPart {
                     user: &defs.profile_user,
                     ssh_user: &defs.ssh_user,
-                    path: &data.profile.profile_settings.path,
-                    hostname: &data.node.node_settings.hostname,
+                    path: data.store_path(),
+                    hostname: &defs.hostname,
                     ssh_opts: &data.merged_settings.ssh_opts,
                 },
             );
@@ -308,47 +1129,45 @@ fn print_deployment(
 pub enum PromptDeploymentError {
     #[error("Failed to make printable TOML of deployment: {0}")]
     TomlFormat(#[from] toml::ser::Error),
-    #[error("Failed to flush stdout prior to query: {0}")]
-    StdoutFlush(std::io::Error),
+    #[error("Failed to flush stderr prior to query: {0}")]
+    StderrFlush(std::io::Error),
     #[error("Failed to read line from stdin: {0}")]
     StdinRead(std::io::Error),
     #[error("User cancelled deployment")]
     Cancelled,
 }
 
-fn prompt_deployment(
-    parts: &[(
-        &deploy::DeployFlake<'_>,
-        deploy::DeployData,
-        deploy::DeployDefs,
-    )],
+async fn prompt_deployment(
+    parts: &[&Part<'_>],
+    node_name: &str,
 ) -> Result<(), PromptDeploymentError> {
     print_deployment(parts)?;
 
-    info!("Are you sure you want to deploy these profiles?");
-    print!("> ");
+    info!(
+        "Are you sure you want to activate these profiles on node `{}`?",
+        node_name
+    );
+    eprint!("> ");
 
-    stdout()
+    stderr()
         .flush()
-        .map_err(PromptDeploymentError::StdoutFlush)?;
+        .map_err(PromptDeploymentError::StderrFlush)?;
 
-    let mut s = String::new();
-    stdin()
-        .read_line(&mut s)
+    let s = deploy::read_answer()
+        .await
         .map_err(PromptDeploymentError::StdinRead)?;
 
     if !yn::yes(&s) {
         if yn::is_somewhat_yes(&s) {
             info!("Sounds like you might want to continue, to be more clear please just say \"yes\". Do you want to deploy these profiles?");
-            print!("> ");
+            eprint!("> ");
 
-            stdout()
+            stderr()
                 .flush()
-                .map_err(PromptDeploymentError::StdoutFlush)?;
+                .map_err(PromptDeploymentError::StderrFlush)?;
 
-            let mut s = String::new();
-            stdin()
-                .read_line(&mut s)
+            let s = deploy::read_answer()
+                .await
                 .map_err(PromptDeploymentError::StdinRead)?;
 
             if !yn::yes(&s) {
@@ -374,12 +1193,10 @@ pub enum RunDeployError {
     DeployProfile(#[from] deploy::deploy::DeployProfileError),
     #[error("Failed to push profile: {0}")]
     PushProfile(#[from] deploy::push::PushProfileError),
-    #[error("No profile named `{0}` was found")]
-    ProfileNotFound(String),
-    #[error("No node named `{0}` was found")]
-    NodeNotFound(String),
-    #[error("Profile was provided without a node name")]
-    ProfileWithoutNode,
+    #[error("No profile named `{0}` was found{1}")]
+    ProfileNotFound(String, String),
+    #[error("No node named `{0}` was found{1}")]
+    NodeNotFound(String, String),
     #[error("Error processing deployment definitions: {0}")]
     DeployDataDefs(#[from] deploy::DeployDataDefsError),
     #[error("Failed to make printable TOML of deployment: {0}")]
@@ -388,44 +1205,978 @@ pub enum RunDeployError {
     PromptDeployment(#[from] PromptDeploymentError),
     #[error("Failed to revoke profile: {0}")]
     RevokeProfile(#[from] deploy::deploy::RevokeProfileError),
+    #[error("The following nodes are not reachable over SSH: {}", .0.join(", "))]
+    Unreachable(Vec<String>),
+    #[error("Deployment failed for the following nodes: {}", format_failed_nodes(.0))]
+    NodesFailed(Vec<(String, RunDeployError)>),
+    /// With the names the node might be a typo of, or else all available names
+    #[error("No node named `{0}` was found{1}")]
+    SelectedNodeNotFound(String, String),
+    #[error("Invalid node pattern `{0}`: {1}")]
+    InvalidNodeGlob(String, glob::PatternError),
+    #[error("No node matches `{0}`, available nodes are: {1}")]
+    NoNodeMatched(String, String),
+    #[error("Node `{0}` is defined in both `{1}` and `{2}`, node names must be unique across the deployed flakes")]
+    NodeNameCollision(String, String, String),
+    #[error(
+        "Nodes can not be selected with `--node` or `--tag` when the flake already names a node"
+    )]
+    NodeSelectionWithNode,
+    #[error("No node is tagged with {0}")]
+    NoNodeTagged(String),
+    #[error("Node `{0}` has no profile named `{1}`{2}")]
+    NodeHasNoProfile(String, String, String),
+    #[error("Node `{0}` is deployed after node `{1}`, which does not exist")]
+    UnknownDependency(String, String),
+    #[error("Nodes {0} are deployed after each other in a cycle")]
+    DependencyCycle(String),
+    #[error("Failed to make JSON report of deployment: {0}")]
+    JsonFormat(#[from] serde_json::Error),
+    #[error("Deployment did not finish within {0} seconds")]
+    Timeout(u64),
+    #[error("Deployment was interrupted")]
+    Interrupted,
 }
 
-type ToDeploy<'a> = Vec<(
-    &'a deploy::DeployFlake<'a>,
-    &'a deploy::data::Data,
-    (&'a str, &'a deploy::data::Node),
-    (&'a str, &'a deploy::data::Profile),
-)>;
+fn format_failed_nodes(failed: &[(String, RunDeployError)]) -> String {
+    failed
+        .iter()
+        .map(|(node_name, e)| format!("{}: {}", node_name, e))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
 
-async fn run_deploy(
-    deploy_flakes: Vec<deploy::DeployFlake<'_>>,
-    data: Vec<deploy::data::Data>,
-    supports_flakes: bool,
-    check_sigs: bool,
-    interactive: bool,
-    cmd_overrides: &deploy::CmdOverrides,
-    keep_result: bool,
-    result_path: Option<&str>,
+/// The settings a profile would be deployed with, after merging the settings of the flake and the command line options
+#[derive(Serialize)]
+struct ResolvedProfile<'a> {
+    flake: &'a str,
+    node: &'a str,
+    profile: &'a str,
+    path: &'a str,
+    ssh_user: &'a str,
+    profile_user: &'a str,
+    profile_path: &'a str,
+    sudo: Option<&'a str>,
+    hostname: &'a str,
+    settings: &'a deploy::data::GenericSettings,
+}
+
+/// Prints the resolved settings of every profile as JSON to stdout, for `--print-deploy-data`
+fn print_deploy_data(parts: &[Part]) -> Result<(), RunDeployError> {
+    let resolved: Vec<ResolvedProfile> = parts
+        .iter()
+        .map(|(deploy_flake, deploy_data, deploy_defs)| ResolvedProfile {
+            flake: deploy_flake.repo,
+            node: deploy_data.node_name,
+            profile: deploy_data.profile_name,
+            path: deploy_data.store_path(),
+            ssh_user: &deploy_defs.ssh_user,
+            profile_user: &deploy_defs.profile_user,
+            profile_path: &deploy_defs.profile_path,
+            sudo: deploy_defs.sudo.as_deref(),
+            hostname: &deploy_defs.hostname,
+            settings: &deploy_data.merged_settings,
+        })
+        .collect();
+
+    println!("{}", serde_json::to_string_pretty(&resolved)?);
+
+    Ok(())
+}
+
+/// Prints a table of what deploying every profile would do to stdout, for `--plan`.
+/// Whether a profile needs to be pushed is checked on its node, all nodes at the same time.
+async fn print_plan(parts: &[Part<'_>], skip_push: bool, dry_activate: bool) {
+    let pushes = join_all(
+        parts
+            .iter()
+            .map(|(_, deploy_data, deploy_defs)| async move {
+                if skip_push {
+                    return "no, skipped".to_string();
+                }
+
+                if deploy_data.is_local() {
+                    return "no, local".to_string();
+                }
+
+                // A missing path can not be told apart from a failed connection by `nix path-info`
+                if let Err(e) = deploy::deploy::check_reachable(deploy_data, deploy_defs).await {
+                    warn!("Node `{}` is not reachable: {}", deploy_data.node_name, e);
+                    return "unknown, unreachable".to_string();
+                }
+
+                match deploy::push::is_present(deploy_data, deploy_defs).await {
+                    Ok(true) => "no, present".to_string(),
+                    Ok(false) if deploy_data.merged_settings.remote_build == Some(true) => {
+                        "yes, build on node".to_string()
+                    }
+                    Ok(false) => "yes".to_string(),
+                    Err(e) => format!("unknown, {}", e),
+                }
+            }),
+    )
+    .await;
+
+    let mut rows: Vec<Vec<String>> = vec![vec![
+        "NODE".to_string(),
+        "PROFILE".to_string(),
+        "HOSTNAME".to_string(),
+        "SSH USER".to_string(),
+        "USER".to_string(),
+        "PUSH".to_string(),
+        "ACTIVATE".to_string(),
+        "PATH".to_string(),
+    ]];
+
+    for ((_, deploy_data, deploy_defs), push) in parts.iter().zip(pushes) {
+        let activation_mode = match dry_activate {
+            true => deploy::data::ActivationMode::DryActivate,
+            false => deploy_data.activation_mode(),
+        };
+
+        rows.push(vec![
+            deploy_data.node_name.to_string(),
+            deploy_data.profile_name.to_string(),
+            deploy_defs.hostname.clone(),
+            deploy_defs.ssh_user.clone(),
+            deploy_defs.profile_user.clone(),
+            push,
+            activation_mode.as_str().to_string(),
+            deploy_data.store_path().to_string(),
+        ]);
+    }
+
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|i| rows.iter().map(|row| row[i].len()).max().unwrap_or(0))
+        .collect();
+
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// Checks that every node is reachable over SSH at the same time, before anything is built
+async fn check_reachable(parts: &[Part<'_>]) -> Result<(), RunDeployError> {
+    // Profiles connecting the same way only need to be checked once
+    let mut to_check: Vec<&Part> = Vec::new();
+    for part in parts {
+        if !to_check.iter().any(|other| same_copy_target(other, part)) {
+            to_check.push(part);
+        }
+    }
+
+    info!(
+        "Checking that {} node(s) are reachable over SSH",
+        to_check.len()
+    );
+
+    let results = join_all(
+        to_check
+            .iter()
+            .map(|(_, deploy_data, deploy_defs)| async move {
+                let result = deploy::deploy::check_reachable(deploy_data, deploy_defs).await;
+                (deploy_data, deploy_defs, result)
+            }),
+    )
+    .await;
+
+    let unreachable: Vec<String> = results
+        .into_iter()
+        .filter_map(|(deploy_data, deploy_defs, result)| {
+            result.err().map(|e| {
+                format!(
+                    "{} ({}@{}): {}",
+                    deploy_data.node_name, deploy_defs.ssh_user, deploy_defs.hostname, e
+                )
+            })
+        })
+        .collect();
+
+    if !unreachable.is_empty() {
+        return Err(RunDeployError::Unreachable(unreachable));
+    }
+
+    Ok(())
+}
+
+/// Exit codes of the `deploy` command, so that wrapping scripts can tell failures apart.
+/// Invalid arguments exit with the same code as those rejected by the argument parser.
+pub mod exit_code {
+    pub const OTHER: i32 = 1;
+    pub const INVALID_ARGUMENTS: i32 = 2;
+    pub const EVALUATION: i32 = 3;
+    pub const PUSH: i32 = 4;
+    pub const ACTIVATION: i32 = 5;
+    pub const ROLLBACK: i32 = 6;
+    pub const INTERRUPTED: i32 = 130;
+}
+
+fn deploy_profile_exit_code(e: &deploy::deploy::DeployProfileError) -> i32 {
+    match e {
+        e if e.rollback_failed() => exit_code::ROLLBACK,
+        _ => exit_code::ACTIVATION,
+    }
+}
+
+impl RunDeployError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunDeployError::DeployProfile(e) => deploy_profile_exit_code(e),
+            RunDeployError::PushProfile(_) | RunDeployError::Unreachable(_) => exit_code::PUSH,
+            RunDeployError::ProfileNotFound(_, _)
+            | RunDeployError::NodeNotFound(_, _)
+            | RunDeployError::SelectedNodeNotFound(_, _)
+            | RunDeployError::NodeSelectionWithNode
+            | RunDeployError::NoNodeTagged(_)
+            | RunDeployError::NodeHasNoProfile(_, _, _)
+            | RunDeployError::InvalidNodeGlob(_, _)
+            | RunDeployError::NoNodeMatched(_, _)
+            | RunDeployError::NodeNameCollision(_, _, _)
+            | RunDeployError::UnknownDependency(_, _)
+            | RunDeployError::DependencyCycle(_) => exit_code::INVALID_ARGUMENTS,
+            RunDeployError::DeployDataDefs(_) => exit_code::EVALUATION,
+            RunDeployError::RevokeProfile(_) => exit_code::ROLLBACK,
+            // The most severe failure of any node, with rollback failures being the most severe
+            RunDeployError::NodesFailed(failed) => failed
+                .iter()
+                .map(|(_, e)| e.exit_code())
+                .max()
+                .unwrap_or(exit_code::OTHER),
+            RunDeployError::TomlFormat(_)
+            | RunDeployError::PromptDeployment(_)
+            | RunDeployError::JsonFormat(_)
+            | RunDeployError::Timeout(_) => exit_code::OTHER,
+            RunDeployError::Interrupted => exit_code::INTERRUPTED,
+        }
+    }
+}
+
+/// The error for a node that is not in `data`, suggesting the names of nodes that it might be a typo of
+fn node_not_found(data: &deploy::data::Data, name: &str) -> RunDeployError {
+    RunDeployError::NodeNotFound(
+        name.to_string(),
+        deploy::did_you_mean(name, data.nodes.keys().map(|x| x.as_str())),
+    )
+}
+
+/// The error for a profile that is not in any of `nodes`, suggesting the names of their profiles
+/// that it might be a typo of
+fn profile_not_found(name: &str, nodes: &[&deploy::data::Node]) -> RunDeployError {
+    RunDeployError::ProfileNotFound(
+        name.to_string(),
+        deploy::did_you_mean(
+            name,
+            nodes
+                .iter()
+                .flat_map(|node| node.node_settings.profiles.keys())
+                .map(|x| x.as_str()),
+        ),
+    )
+}
+
+/// Returns the nodes of `data` that should be deployed to when no node is given in the flake.
+/// If `names` is empty, this is every node, otherwise it is exactly the named nodes, in the given order.
+fn select_nodes<'a>(
+    data: &'a deploy::data::Data,
+    names: &[String],
+) -> Result<Vec<(&'a str, &'a deploy::data::Node)>, RunDeployError> {
+    if names.is_empty() {
+        return Ok(data
+            .nodes
+            .iter()
+            .map(|(node_name, node)| (node_name.as_str(), node))
+            .collect());
+    }
+
+    let mut selected: Vec<(&str, &deploy::data::Node)> = Vec::new();
+
+    let available = || {
+        let mut available: Vec<&str> = data.nodes.keys().map(|x| x.as_str()).collect();
+        available.sort_unstable();
+        available.join(", ")
+    };
+
+    for name in names {
+        let matching: Vec<(&str, &deploy::data::Node)> = match data.nodes.get_key_value(name) {
+            Some((node_name, node)) => vec![(node_name.as_str(), node)],
+            // Names with wildcards select every node they match, in the order of their names
+            None if is_node_glob(name) => {
+                let pattern = glob::Pattern::new(name)
+                    .map_err(|e| RunDeployError::InvalidNodeGlob(name.clone(), e))?;
+
+                let mut matching: Vec<(&str, &deploy::data::Node)> = data
+                    .nodes
+                    .iter()
+                    .filter(|(node_name, _)| pattern.matches(node_name))
+                    .map(|(node_name, node)| (node_name.as_str(), node))
+                    .collect();
+
+                if matching.is_empty() {
+                    return Err(RunDeployError::NoNodeMatched(name.clone(), available()));
+                }
+
+                matching.sort_unstable_by_key(|(node_name, _)| *node_name);
+                matching
+            }
+            None => {
+                let suggestion =
+                    match deploy::did_you_mean(name, data.nodes.keys().map(|x| x.as_str())) {
+                        x if x.is_empty() => format!(", available nodes are: {}", available()),
+                        x => x,
+                    };
+
+                return Err(RunDeployError::SelectedNodeNotFound(
+                    name.clone(),
+                    suggestion,
+                ));
+            }
+        };
+
+        for (node_name, node) in matching {
+            if !selected.iter().any(|(n, _)| *n == node_name) {
+                selected.push((node_name, node));
+            }
+        }
+    }
+
+    Ok(selected)
+}
+
+type NodeProfile<'a> = (
+    (&'a str, &'a deploy::data::Node),
+    (&'a str, &'a deploy::data::Profile),
+);
+
+/// Returns the nodes selected by `--node` and `--tag` that have the profile given with `--profile`, along with it.
+/// Nodes matched by a pattern or a tag are skipped if they do not have it, but nodes named explicitly must have it.
+/// If no node is tagged with the tags at all, nothing is returned, which the caller reports.
+fn nodes_with_profile<'a>(
+    data: &'a deploy::data::Data,
+    names: &[String],
+    profile_name: &str,
+    tags: &[String],
+    all_tags: bool,
+) -> Result<Vec<NodeProfile<'a>>, RunDeployError> {
+    let mut selected = Vec::new();
+    let mut searched: Vec<&deploy::data::Node> = Vec::new();
+
+    for (node_name, node) in select_nodes(data, names)? {
+        if !has_tags(node, tags, all_tags) {
+            continue;
+        }
+
+        searched.push(node);
+
+        match node.node_settings.profiles.get_key_value(profile_name) {
+            Some((profile_name, profile)) => {
+                selected.push(((node_name, node), (profile_name.as_str(), profile)))
+            }
+            None if names.iter().any(|x| x == node_name) => {
+                return Err(RunDeployError::NodeHasNoProfile(
+                    node_name.to_string(),
+                    profile_name.to_string(),
+                    deploy::did_you_mean(
+                        profile_name,
+                        node.node_settings.profiles.keys().map(|x| x.as_str()),
+                    ),
+                ))
+            }
+            None => info!(
+                "Node `{}` has no profile `{}`, skipping it",
+                node_name, profile_name
+            ),
+        }
+    }
+
+    if selected.is_empty() && (!searched.is_empty() || tags.is_empty()) {
+        return Err(profile_not_found(profile_name, &searched));
+    }
+
+    Ok(selected)
+}
+
+#[test]
+fn test_nodes_with_profile() {
+    let data: deploy::data::Data = serde_json::from_value(serde_json::json!({
+        "nodes": {
+            "web1": {
+                "hostname": "web1.example.com",
+                "tags": ["web"],
+                "profiles": { "system": { "path": "/nix/store/a" }, "app": { "path": "/nix/store/b" } },
+            },
+            "web2": {
+                "hostname": "web2.example.com",
+                "tags": ["web"],
+                "profiles": { "system": { "path": "/nix/store/a" } },
+            },
+            "db": {
+                "hostname": "db.example.com",
+                "profiles": { "system": { "path": "/nix/store/a" } },
+            },
+        }
+    }))
+    .unwrap();
+
+    let strings = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    let names_with = |names: &[&str], profile: &str, tags: &[&str]| {
+        nodes_with_profile(&data, &strings(names), profile, &strings(tags), false).map(|x| {
+            let mut names: Vec<&str> = x.into_iter().map(|((node, _), _)| node).collect();
+            names.sort_unstable();
+            names
+        })
+    };
+
+    // Nodes matched by a pattern or tag without the profile are skipped
+    assert_eq!(names_with(&[], "app", &[]).unwrap(), vec!["web1"]);
+    assert_eq!(names_with(&["web*"], "app", &[]).unwrap(), vec!["web1"]);
+    assert_eq!(names_with(&[], "app", &["web"]).unwrap(), vec!["web1"]);
+
+    // A node that was asked for by name has to have it
+    assert!(matches!(
+        names_with(&["web1", "web2"], "app", &[]),
+        Err(RunDeployError::NodeHasNoProfile(node, profile, _)) if node == "web2" && profile == "app"
+    ));
+
+    // Whether or not tags are given, no node with the profile is an error
+    assert!(matches!(
+        names_with(&[], "home", &[]),
+        Err(RunDeployError::ProfileNotFound(_, _))
+    ));
+    assert!(matches!(
+        names_with(&[], "home", &["web"]),
+        Err(RunDeployError::ProfileNotFound(_, _))
+    ));
+
+    // Unless no node is tagged at all, which is reported differently
+    assert!(names_with(&[], "system", &["cache"]).unwrap().is_empty());
+}
+
+/// Returns if the node is selected by the tags given to `--tag`, which is any node when none are given.
+/// Nodes without tags are never selected by them.
+fn has_tags(node: &deploy::data::Node, tags: &[String], all_tags: bool) -> bool {
+    let node_tags = &node.node_settings.tags;
+
+    match (tags.is_empty(), all_tags) {
+        (true, _) => true,
+        (false, true) => tags.iter().all(|x| node_tags.contains(x)),
+        (false, false) => tags.iter().any(|x| node_tags.contains(x)),
+    }
+}
+
+#[test]
+fn test_has_tags() {
+    let node = |tags: &[&str]| -> deploy::data::Node {
+        serde_json::from_value(serde_json::json!({
+            "hostname": "web.example.com",
+            "profiles": {},
+            "tags": tags,
+        }))
+        .unwrap()
+    };
+    let tags = |tags: &[&str]| tags.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+    let web = node(&["web", "eu"]);
+    let untagged = node(&[]);
+
+    // Without `--tag` every node is selected
+    assert!(has_tags(&web, &[], false));
+    assert!(has_tags(&untagged, &[], true));
+
+    // Any of the tags is enough, unless `--all-tags` is given
+    assert!(has_tags(&web, &tags(&["web", "us"]), false));
+    assert!(!has_tags(&web, &tags(&["web", "us"]), true));
+    assert!(has_tags(&web, &tags(&["eu", "web"]), true));
+    assert!(!has_tags(&web, &tags(&["db"]), false));
+
+    assert!(!has_tags(&untagged, &tags(&["web"]), false));
+    assert!(!has_tags(&untagged, &tags(&["web"]), true));
+}
+
+/// Returns if the node name given to `--node` is a glob pattern rather than the name of a node
+fn is_node_glob(name: &str) -> bool {
+    name.contains(['*', '?', '['])
+}
+
+type Part<'a> = (
+    &'a deploy::DeployFlake<'a>,
+    deploy::DeployData<'a>,
+    deploy::DeployDefs,
+);
+
+/// Returns the profiles of a node in the order they should be deployed in,
+/// first those in `profilesOrder`, then all others
+fn node_profiles(
+    node: &deploy::data::Node,
+) -> Result<Vec<(&str, &deploy::data::Profile)>, RunDeployError> {
+    let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
+
+    for profile_name in [
+        node.node_settings.profiles_order.iter().collect(),
+        node.node_settings.profiles.keys().collect::<Vec<&String>>(),
+    ]
+    .concat()
+    {
+        let profile = match node.node_settings.profiles.get(profile_name) {
+            Some(x) => x,
+            None => return Err(profile_not_found(profile_name, &[node])),
+        };
+
+        if !profiles_list.iter().any(|(n, _)| n == profile_name) {
+            profiles_list.push((profile_name, profile));
+        }
+    }
+
+    Ok(profiles_list)
+}
+
+type ToDeploy<'a> = Vec<(
+    &'a deploy::DeployFlake<'a>,
+    &'a deploy::data::Data,
+    (&'a str, &'a deploy::data::Node),
+    (&'a str, &'a deploy::data::Profile),
+)>;
+
+/// Fails if nodes of the same name are deployed from different flakes, as they could not be told apart
+fn check_node_collisions(to_deploy: &ToDeploy) -> Result<(), RunDeployError> {
+    let mut repos: HashMap<&str, &str> = HashMap::new();
+
+    for (deploy_flake, _, (node_name, _), _) in to_deploy {
+        match repos.insert(node_name, deploy_flake.repo) {
+            Some(repo) if repo != deploy_flake.repo => {
+                return Err(RunDeployError::NodeNameCollision(
+                    node_name.to_string(),
+                    repo.to_string(),
+                    deploy_flake.repo.to_string(),
+                ))
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Orders `to_deploy` so that every node comes after the nodes it is deployed `after`, otherwise keeping
+/// the given order. Dependencies on nodes that are not being deployed are ignored.
+/// Also returns the dependency level of every node, nodes of the same level do not depend on each other.
+fn order_by_dependencies<'a>(
+    to_deploy: ToDeploy<'a>,
+) -> Result<(ToDeploy<'a>, HashMap<&'a str, usize>), RunDeployError> {
+    let mut nodes: Vec<(&str, &deploy::data::Node)> = Vec::new();
+
+    for (_, data, (node_name, node), _) in &to_deploy {
+        if nodes.iter().any(|(n, _)| n == node_name) {
+            continue;
+        }
+
+        for dependency in &node.node_settings.after {
+            if !data.nodes.contains_key(dependency)
+                && !to_deploy
+                    .iter()
+                    .any(|(_, _, (n, _), _)| n == &dependency.as_str())
+            {
+                return Err(RunDeployError::UnknownDependency(
+                    node_name.to_string(),
+                    dependency.clone(),
+                ));
+            }
+        }
+
+        nodes.push((node_name, node));
+    }
+
+    let mut levels: HashMap<&str, usize> = HashMap::new();
+
+    while levels.len() < nodes.len() {
+        let mut progressed = false;
+
+        for (node_name, node) in &nodes {
+            if levels.contains_key(node_name) {
+                continue;
+            }
+
+            let mut level = Some(0);
+
+            for dependency in &node.node_settings.after {
+                let dependency = dependency.as_str();
+
+                if !nodes.iter().any(|(n, _)| *n == dependency) {
+                    continue;
+                }
+
+                level = match (level, levels.get(dependency)) {
+                    (Some(level), Some(l)) => Some(level.max(l + 1)),
+                    _ => None,
+                };
+            }
+
+            if let Some(level) = level {
+                levels.insert(node_name, level);
+                progressed = true;
+            }
+        }
+
+        if !progressed {
+            return Err(RunDeployError::DependencyCycle(
+                nodes
+                    .iter()
+                    .filter(|(n, _)| !levels.contains_key(n))
+                    .map(|(n, _)| format!("`{}`", n))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            ));
+        }
+    }
+
+    let mut to_deploy = to_deploy;
+
+    // The sort is stable, so profiles of a node stay in order
+    to_deploy.sort_by_key(|(_, _, (node_name, _), _)| levels[node_name]);
+
+    Ok((to_deploy, levels))
+}
+
+/// Deployment data with a node for every `(name, after, profiles)`, for testing how nodes are ordered
+#[cfg(test)]
+fn nodes_data(nodes: &[(&str, &[&str], &[&str])]) -> deploy::data::Data {
+    let nodes: serde_json::Map<String, serde_json::Value> = nodes
+        .iter()
+        .map(|(name, after, profiles)| {
+            let profiles: serde_json::Map<String, serde_json::Value> = profiles
+                .iter()
+                .map(|x| {
+                    (
+                        x.to_string(),
+                        serde_json::json!({ "path": format!("/nix/store/{}", x) }),
+                    )
+                })
+                .collect();
+
+            (
+                name.to_string(),
+                serde_json::json!({
+                    "hostname": format!("{}.example.com", name),
+                    "after": after,
+                    "profilesOrder": profiles.keys().collect::<Vec<_>>(),
+                    "profiles": profiles,
+                }),
+            )
+        })
+        .collect();
+
+    serde_json::from_value(serde_json::json!({ "nodes": nodes })).unwrap()
+}
+
+/// The profiles of the given nodes of `data`, in the order of `profiles` for each node, as they are to be deployed
+#[cfg(test)]
+fn to_deploy_of<'a>(
+    flake: &'a deploy::DeployFlake<'a>,
+    data: &'a deploy::data::Data,
+    nodes: &[(&'a str, &[&'a str])],
+) -> ToDeploy<'a> {
+    nodes
+        .iter()
+        .flat_map(|(name, profiles)| {
+            let (name, node) = data.nodes.get_key_value(*name).unwrap();
+            profiles.iter().map(move |profile| {
+                let (profile_name, profile) =
+                    node.node_settings.profiles.get_key_value(*profile).unwrap();
+                (
+                    flake,
+                    data,
+                    (name.as_str(), node),
+                    (profile_name.as_str(), profile),
+                )
+            })
+        })
+        .collect()
+}
+
+/// The node and profile names in the order they are deployed in, with the level of each node
+#[cfg(test)]
+fn deploy_order<'a>(
+    to_deploy: &ToDeploy<'a>,
+    levels: &HashMap<&'a str, usize>,
+) -> Vec<(&'a str, &'a str, usize)> {
+    to_deploy
+        .iter()
+        .map(|(_, _, (node, _), (profile, _))| (*node, *profile, levels[node]))
+        .collect()
+}
+
+#[test]
+fn test_order_by_dependencies() {
+    let flake = deploy::DeployFlake {
+        repo: ".",
+        node: None,
+        profile: None,
+    };
+
+    // A linear chain, given in the wrong order
+    let data = nodes_data(&[
+        ("a", &[], &["system"]),
+        ("b", &["a"], &["system"]),
+        ("c", &["b"], &["system"]),
+    ]);
+    let to_deploy = to_deploy_of(
+        &flake,
+        &data,
+        &[("c", &["system"]), ("b", &["system"]), ("a", &["system"])],
+    );
+    let (to_deploy, levels) = order_by_dependencies(to_deploy).unwrap();
+    assert_eq!(
+        deploy_order(&to_deploy, &levels),
+        vec![("a", "system", 0), ("b", "system", 1), ("c", "system", 2)]
+    );
+
+    // A diamond, where the nodes in the middle do not depend on each other
+    let data = nodes_data(&[
+        ("a", &[], &["system"]),
+        ("b", &["a"], &["system"]),
+        ("c", &["a"], &["system"]),
+        ("d", &["b", "c"], &["system"]),
+    ]);
+    let to_deploy = to_deploy_of(
+        &flake,
+        &data,
+        &[
+            ("d", &["system"]),
+            ("b", &["system"]),
+            ("c", &["system"]),
+            ("a", &["system"]),
+        ],
+    );
+    let (to_deploy, levels) = order_by_dependencies(to_deploy).unwrap();
+    assert_eq!(
+        deploy_order(&to_deploy, &levels),
+        vec![
+            ("a", "system", 0),
+            ("b", "system", 1),
+            ("c", "system", 1),
+            ("d", "system", 2)
+        ]
+    );
+
+    let data = nodes_data(&[
+        ("a", &["c"], &["system"]),
+        ("b", &["a"], &["system"]),
+        ("c", &["b"], &["system"]),
+        ("d", &[], &["system"]),
+    ]);
+    let to_deploy = to_deploy_of(
+        &flake,
+        &data,
+        &[
+            ("a", &["system"]),
+            ("b", &["system"]),
+            ("c", &["system"]),
+            ("d", &["system"]),
+        ],
+    );
+    assert!(matches!(
+        order_by_dependencies(to_deploy),
+        Err(RunDeployError::DependencyCycle(nodes)) if nodes == "`a`, `b`, `c`"
+    ));
+
+    let data = nodes_data(&[("a", &["missing"], &["system"])]);
+    let to_deploy = to_deploy_of(&flake, &data, &[("a", &["system"])]);
+    assert!(matches!(
+        order_by_dependencies(to_deploy),
+        Err(RunDeployError::UnknownDependency(node, dependency)) if node == "a" && dependency == "missing"
+    ));
+
+    // Nodes that are not deployed are not waited for
+    let data = nodes_data(&[("a", &[], &["system"]), ("b", &["a"], &["system"])]);
+    let to_deploy = to_deploy_of(&flake, &data, &[("b", &["system"])]);
+    let (to_deploy, levels) = order_by_dependencies(to_deploy).unwrap();
+    assert_eq!(deploy_order(&to_deploy, &levels), vec![("b", "system", 0)]);
+}
+
+#[test]
+fn test_reverse_order() {
+    let flake = deploy::DeployFlake {
+        repo: ".",
+        node: None,
+        profile: None,
+    };
+
+    let data = nodes_data(&[
+        ("a", &[], &["system", "app"]),
+        ("b", &["a"], &["system"]),
+        ("c", &["a"], &["system"]),
+        ("d", &["b", "c"], &["system"]),
+    ]);
+    let to_deploy = to_deploy_of(
+        &flake,
+        &data,
+        &[
+            ("a", &["system", "app"]),
+            ("b", &["system"]),
+            ("c", &["system"]),
+            ("d", &["system"]),
+        ],
+    );
+    let (to_deploy, levels) = order_by_dependencies(to_deploy).unwrap();
+    let (to_deploy, levels) = reverse_order(to_deploy, levels);
+
+    // The nodes depending on others come first, while the profiles of a node keep their order
+    assert_eq!(
+        deploy_order(&to_deploy, &levels),
+        vec![
+            ("d", "system", 0),
+            ("c", "system", 1),
+            ("b", "system", 1),
+            ("a", "system", 2),
+            ("a", "app", 2),
+        ]
+    );
+}
+
+#[test]
+fn test_dependency_failed() {
+    let after = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+    let nodes = [
+        ("a", after(&[])),
+        ("b", after(&["a"])),
+        ("c", after(&["a"])),
+        ("d", after(&["b", "c"])),
+    ];
+    let failed = |node_name: &str, not_succeeded: &[&str], reverse: bool| {
+        dependency_failed(
+            node_name,
+            nodes.iter().map(|(name, after)| (*name, &after[..])),
+            not_succeeded,
+            reverse,
+        )
+    };
+
+    // Nodes are skipped when a node in their `after` failed
+    assert!(failed("d", &["b"], false));
+    assert!(!failed("c", &["b"], false));
+    assert!(!failed("a", &["b"], false));
+
+    // With --reverse, nodes are skipped when a node that is `after` them failed
+    assert!(failed("b", &["d"], true));
+    assert!(failed("c", &["d"], true));
+    assert!(!failed("a", &["d"], true));
+    assert!(failed("a", &["c"], true));
+    assert!(!failed("d", &["b"], true));
+}
+
+/// Reverses the order the nodes are deployed in, as given by `order_by_dependencies`. The levels are reversed as well,
+/// so that nodes are deployed before the nodes they are `after`. The profiles of a node are still deployed in their order.
+fn reverse_order<'a>(
+    to_deploy: ToDeploy<'a>,
+    levels: HashMap<&'a str, usize>,
+) -> (ToDeploy<'a>, HashMap<&'a str, usize>) {
+    let mut nodes: Vec<ToDeploy<'a>> = Vec::new();
+
+    for entry in to_deploy {
+        let (deploy_flake, _, (node_name, _), _) = entry;
+
+        match nodes.iter_mut().find(|entries| {
+            let (other_flake, _, (other_name, _), _) = entries[0];
+            other_flake.repo == deploy_flake.repo && other_name == node_name
+        }) {
+            Some(entries) => entries.push(entry),
+            None => nodes.push(vec![entry]),
+        }
+    }
+
+    let max_level = levels.values().copied().max().unwrap_or(0);
+
+    (
+        nodes.into_iter().rev().flatten().collect(),
+        levels
+            .into_iter()
+            .map(|(node_name, level)| (node_name, max_level - level))
+            .collect(),
+    )
+}
+
+/// Returns if the node is skipped, as a node it is deployed after did not succeed. These are the nodes in its `after`,
+/// or with --reverse the nodes that have it in their `after`. `nodes` has the `after` of every node.
+fn dependency_failed<'a, I: Iterator<Item = (&'a str, &'a [String])>>(
+    node_name: &str,
+    mut nodes: I,
+    not_succeeded: &[&str],
+    reverse: bool,
+) -> bool {
+    match reverse {
+        false => nodes.any(|(name, after)| {
+            name == node_name && after.iter().any(|x| not_succeeded.contains(&x.as_str()))
+        }),
+        true => nodes.any(|(name, after)| {
+            not_succeeded.contains(&name) && after.iter().any(|x| x == node_name)
+        }),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_deploy(
+    deploy_flakes: Vec<deploy::DeployFlake<'_>>,
+    data: Vec<deploy::data::Data>,
+    supports_flakes: bool,
+    check_sigs: bool,
+    allow_unsigned_local: bool,
+    interactive: bool,
+    cmd_overrides: &deploy::CmdOverrides,
+    keep_result: bool,
+    result_path: Option<&str>,
     extra_build_args: &[String],
     debug_logs: bool,
     dry_activate: bool,
     log_dir: &Option<String>,
     rollback_succeeded: bool,
+    max_parallel: Option<usize>,
+    nodes: &[String],
+    profile: Option<&str>,
+    tags: &[String],
+    all_tags: bool,
+    output: OutputFormat,
+    metrics_file: Option<&str>,
+    copy_retries: u32,
+    timeout: Option<u64>,
+    build_timeout: Option<u64>,
+    show_closure_size: bool,
+    events: Option<&mpsc::UnboundedSender<deploy::DeployEvent>>,
+    on_error: Option<OnError>,
+    pipeline: bool,
+    no_activate_if_push_failed: bool,
+    skip_push: bool,
+    prebuilt: bool,
+    skip_preflight: bool,
+    print_resolved: bool,
+    plan: bool,
+    skip_unchanged: bool,
+    reverse: bool,
 ) -> Result<(), RunDeployError> {
     let to_deploy: ToDeploy = deploy_flakes
         .iter()
         .zip(&data)
         .map(|(deploy_flake, data)| {
-            let to_deploys: ToDeploy = match (&deploy_flake.node, &deploy_flake.profile) {
+            let profile = deploy_flake.profile.as_deref().or(profile);
+
+            let to_deploys: ToDeploy = match (&deploy_flake.node, profile) {
+                (Some(_), _) if !nodes.is_empty() || !tags.is_empty() => {
+                    return Err(RunDeployError::NodeSelectionWithNode)
+                }
                 (Some(node_name), Some(profile_name)) => {
                     let node = match data.nodes.get(node_name) {
                         Some(x) => x,
-                        None => return Err(RunDeployError::NodeNotFound(node_name.clone())),
-                    };
-                    let profile = match node.node_settings.profiles.get(profile_name) {
-                        Some(x) => x,
-                        None => return Err(RunDeployError::ProfileNotFound(profile_name.clone())),
+                        None => return Err(node_not_found(data, node_name)),
                     };
+                    let (profile_name, profile) =
+                        match node.node_settings.profiles.get_key_value(profile_name) {
+                            Some(x) => x,
+                            None => return Err(profile_not_found(profile_name, &[node])),
+                        };
 
                     vec![(
                         deploy_flake,
@@ -437,149 +2188,1285 @@ async fn run_deploy(
                 (Some(node_name), None) => {
                     let node = match data.nodes.get(node_name) {
                         Some(x) => x,
-                        None => return Err(RunDeployError::NodeNotFound(node_name.clone())),
+                        None => return Err(node_not_found(data, node_name)),
                     };
 
-                    let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
+                    node_profiles(node)?
+                        .into_iter()
+                        .map(|x| (deploy_flake, data, (node_name.as_str(), node), x))
+                        .collect()
+                }
+                (None, None) => {
+                    let mut l = Vec::new();
+
+                    for (node_name, node) in select_nodes(data, nodes)? {
+                        if !has_tags(node, tags, all_tags) {
+                            continue;
+                        }
+
+                        let ll: ToDeploy = node_profiles(node)?
+                            .into_iter()
+                            .map(|x| (deploy_flake, data, (node_name, node), x))
+                            .collect();
+
+                        l.extend(ll);
+                    }
+
+                    l
+                }
+                (None, Some(profile_name)) => {
+                    nodes_with_profile(data, nodes, profile_name, tags, all_tags)?
+                        .into_iter()
+                        .map(|(node, profile)| (deploy_flake, data, node, profile))
+                        .collect()
+                }
+            };
+            Ok(to_deploys)
+        })
+        .collect::<Result<Vec<ToDeploy>, RunDeployError>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    if to_deploy.is_empty() && !tags.is_empty() {
+        return Err(RunDeployError::NoNodeTagged(
+            tags.iter()
+                .map(|x| format!("`{}`", x))
+                .collect::<Vec<String>>()
+                .join(if all_tags { " and " } else { " or " }),
+        ));
+    }
+
+    check_node_collisions(&to_deploy)?;
+
+    let (to_deploy, levels) = order_by_dependencies(to_deploy)?;
+
+    let (to_deploy, levels) = match reverse {
+        true => reverse_order(to_deploy, levels),
+        false => (to_deploy, levels),
+    };
+
+    let mut parts: Vec<Part> = Vec::new();
+    let mut srv_resolved = HashMap::new();
+
+    for (deploy_flake, data, (node_name, node), (profile_name, profile)) in to_deploy {
+        let mut deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            node,
+            node_name,
+            profile,
+            profile_name,
+            cmd_overrides,
+            debug_logs,
+            log_dir.as_deref(),
+        );
+
+        deploy_data.resolve_srv_hostname(&mut srv_resolved).await?;
+
+        let deploy_defs = deploy_data.defs()?;
+
+        parts.push((deploy_flake, deploy_data, deploy_defs));
+    }
+
+    if print_resolved {
+        return print_deploy_data(&parts);
+    }
+
+    let mut deployed = read_deployed_state();
+
+    let unchanged: Vec<String> = parts
+        .iter()
+        .filter(|(deploy_flake, deploy_data, deploy_defs)| {
+            deployed
+                .get(&deployed_state_key(deploy_flake, deploy_data, deploy_defs))
+                .map(|x| x.as_str())
+                == Some(deploy_data.store_path())
+        })
+        .map(|(_, deploy_data, _)| {
+            format!("`{}.{}`", deploy_data.node_name, deploy_data.profile_name)
+        })
+        .collect();
+
+    if !unchanged.is_empty() {
+        info!(
+            "{} of {} profiles changed since they were last deployed from here, unchanged are: {}",
+            parts.len() - unchanged.len(),
+            parts.len(),
+            unchanged.join(", ")
+        );
+    }
+
+    if skip_unchanged {
+        // What was recorded here is only a hint, as the node may have been deployed to from elsewhere
+        // since, so only the nodes themselves decide which profiles are skipped
+        if cmd_overrides.dry_run {
+            info!(
+                "Dry run, would skip the profiles whose store path is already active on their node"
+            );
+        } else {
+            let current = join_all(parts.iter().map(|(_, deploy_data, deploy_defs)| {
+                deploy::deploy::current_profile_path(deploy_data, deploy_defs)
+            }))
+            .await;
+
+            // Activating needs to know this as well, so the nodes are not asked again then
+            for ((_, deploy_data, _), current) in parts.iter_mut().zip(current) {
+                deploy_data.current_profile_path = Some(current);
+            }
+
+            parts.retain(|(_, deploy_data, _)| {
+                let is_active = deploy_data
+                    .current_profile_path
+                    .as_ref()
+                    .and_then(|x| x.as_deref())
+                    == Some(deploy_data.store_path());
+                if is_active {
+                    info!(
+                        "Profile `{}` is already active on node `{}`, not deploying it",
+                        deploy_data.profile_name, deploy_data.node_name
+                    );
+                }
+                !is_active
+            });
+        }
+
+        if parts.is_empty() {
+            info!("No profile changed, not deploying anything");
+            return Ok(());
+        }
+    }
+
+    if plan {
+        print_plan(&parts, skip_push, dry_activate).await;
+        return Ok(());
+    }
+
+    let results = DeployResults::new(&parts);
+
+    print_deployment(&parts.iter().collect::<Vec<&Part>>())?;
+
+    if skip_preflight {
+        warn!("Skipping the check that all nodes are reachable");
+    } else {
+        check_reachable(&parts).await?;
+    }
+
+    let options = DeployPartsOptions {
+        supports_flakes,
+        check_sigs,
+        allow_unsigned_local,
+        cmd_overrides,
+        keep_result,
+        result_path,
+        extra_build_args,
+        dry_activate,
+        rollback_succeeded,
+        copy_retries,
+        build_timeout,
+        show_closure_size,
+        interactive,
+        pipeline,
+        no_activate_if_push_failed,
+        skip_push,
+        prebuilt,
+        results: &results,
+        events,
+    };
+
+    let deployment = async {
+        match (max_parallel, on_error) {
+            (None, None) | (None, Some(OnError::Abort)) => {
+                let parts: Vec<&Part> = parts.iter().collect();
+
+                deploy_parts(&parts, &options).await
+            }
+            _ => {
+                // Group the profiles by node, so that every node is pushed and activated on its own,
+                // keeping the order of profiles within a node intact
+                let mut nodes: Vec<((&str, &str), Vec<&Part>)> = Vec::new();
+
+                for part in &parts {
+                    let key = (part.0.repo, part.1.node_name);
+
+                    match nodes.iter_mut().find(|(k, _)| *k == key) {
+                        Some((_, node_parts)) => node_parts.push(part),
+                        None => nodes.push((key, vec![part])),
+                    }
+                }
+
+                // Nodes deployed in parallel do not stop each other unless asked to
+                let abort_on_error = on_error == Some(OnError::Abort);
+                let aborted = AtomicBool::new(false);
+
+                // The semaphore is fair, so without parallelism nodes are still deployed in order
+                let semaphore = Semaphore::new(max_parallel.unwrap_or(1).max(1));
+
+                let mut results: Vec<(&str, Option<Result<(), RunDeployError>>)> = Vec::new();
+
+                let max_level = levels.values().copied().max().unwrap_or(0);
+
+                // Every level is only started once the nodes of the levels before are done
+                for level in 0..=max_level {
+                    let not_succeeded: Vec<&str> = results
+                        .iter()
+                        .filter(|(_, result)| !matches!(result, Some(Ok(()))))
+                        .map(|(node_name, _)| *node_name)
+                        .collect();
+
+                    let level_results = join_all(
+                        nodes
+                            .iter()
+                            .filter(|((_, node_name), _)| levels[node_name] == level)
+                            .map(|((_, node_name), node_parts)| {
+                                let semaphore = &semaphore;
+                                let options = &options;
+                                let aborted = &aborted;
+
+                                let dependency_failed = dependency_failed(
+                                    node_name,
+                                    nodes.iter().map(|((_, name), node_parts)| {
+                                        (*name, &node_parts[0].1.node.node_settings.after[..])
+                                    }),
+                                    &not_succeeded,
+                                    reverse,
+                                );
+
+                                async move {
+                                    let _permit = semaphore
+                                        .acquire()
+                                        .await
+                                        .expect("Deployment semaphore should never be closed");
+
+                                    if dependency_failed || aborted.load(Ordering::SeqCst) {
+                                        return (*node_name, None);
+                                    }
+
+                                    let result = deploy_parts(node_parts, options).await;
+
+                                    if result.is_err() && abort_on_error {
+                                        aborted.store(true, Ordering::SeqCst);
+                                    }
+
+                                    (*node_name, Some(result))
+                                }
+                            }),
+                    )
+                    .await;
+
+                    results.extend(level_results);
+                }
+
+                let mut failed: Vec<(&str, RunDeployError)> = Vec::new();
+
+                for (node_name, result) in results {
+                    match result {
+                        Some(Ok(())) => info!("Deployment to node `{}` succeeded", node_name),
+                        Some(Err(e)) => {
+                            error!("Deployment to node `{}` failed: {}", node_name, e);
+                            failed.push((node_name, e));
+                        }
+                        None => warn!(
+                            "Deployment to node `{}` was skipped, as another node failed or was skipped",
+                            node_name
+                        ),
+                    }
+                }
+
+                if failed.is_empty() {
+                    Ok(())
+                } else {
+                    Err(RunDeployError::NodesFailed(
+                        failed
+                            .into_iter()
+                            .map(|(node_name, e)| (node_name.to_string(), e))
+                            .collect(),
+                    ))
+                }
+            }
+        }
+    };
+
+    let started = Instant::now();
+
+    let deployment = async {
+        tokio::select! {
+            result = deployment => result,
+            // Dropping the deployment kills any commands that are still running
+            _ = tokio::signal::ctrl_c() => {
+                error!("Deployment was interrupted");
+                results.log_progress();
+
+                if cmd_overrides.remote_lock {
+                    warn!("Activations already running on the nodes keep running there, holding the lock of their node until they exit");
+                }
+
+                Err(RunDeployError::Interrupted)
+            }
+        }
+    };
+
+    let result = match timeout {
+        None => deployment.await,
+        // Dropping the deployment on expiry kills any commands that are still running
+        Some(timeout) => match tokio::time::timeout(Duration::from_secs(timeout), deployment).await
+        {
+            Ok(result) => result,
+            Err(_) => {
+                error!("Deployment timed out after {} seconds", timeout);
+                results.log_progress();
+
+                Err(RunDeployError::Timeout(timeout))
+            }
+        },
+    };
+
+    results.log_timing(started);
+
+    // Every profile of a node shares the same connection, so only close it once
+    let mut closed: Vec<(&str, &str)> = Vec::new();
+
+    for (deploy_flake, deploy_data, deploy_defs) in &parts {
+        if !closed.contains(&(deploy_flake.repo, deploy_data.node_name)) {
+            deploy::deploy::close_ssh_master(deploy_data, deploy_defs).await;
+            closed.push((deploy_flake.repo, deploy_data.node_name));
+        }
+    }
+
+    let results = results
+        .into_inner()
+        .expect("Results lock should not be poisoned");
+
+    if !cmd_overrides.dry_run && !dry_activate {
+        let activated: Vec<&Part> = parts
+            .iter()
+            .filter(|(deploy_flake, deploy_data, _)| {
+                results.iter().any(|r| {
+                    r.flake == deploy_flake.repo
+                        && r.node == deploy_data.node_name
+                        && r.profile == deploy_data.profile_name
+                        && r.activation.as_ref().is_some_and(|x| x.succeeded)
+                })
+            })
+            .collect();
+
+        if !activated.is_empty() {
+            for (deploy_flake, deploy_data, deploy_defs) in activated {
+                deployed.insert(
+                    deployed_state_key(deploy_flake, deploy_data, deploy_defs),
+                    deploy_data.store_path().to_string(),
+                );
+            }
+
+            write_deployed_state(&deployed);
+        }
+    }
+
+    if let Some(metrics_file) = metrics_file {
+        write_metrics(metrics_file, &results, result.is_ok(), started.elapsed());
+    }
+
+    if output == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&DeployReport {
+                succeeded: result.is_ok(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                profiles: results,
+            })?
+        );
+    }
+
+    result
+}
+
+/// What to do with the remaining nodes once deploying to one of them failed
+#[derive(ArgEnum, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnError {
+    /// Stop deploying, rolling back what was deployed so far if not deploying in parallel
+    Abort,
+    /// Deploy to the remaining nodes anyway, and report all failures at the end
+    Continue,
+}
+
+/// The file recording the store path each profile was last deployed with from this machine,
+/// in `$XDG_STATE_HOME/deploy-rs/deployed.json`
+fn deployed_state_path() -> Option<PathBuf> {
+    let state_dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?)
+            .join(".local")
+            .join("state"),
+    };
+
+    Some(state_dir.join("deploy-rs").join("deployed.json"))
+}
+
+/// The key a profile is recorded under in the deployed state. Besides the node and profile, it has the flake
+/// (with local paths made absolute), and the user, host and profile path it was deployed to, so that the same
+/// names in other flakes, or an overridden hostname, do not match it
+fn deployed_state_key(
+    deploy_flake: &deploy::DeployFlake,
+    deploy_data: &deploy::DeployData,
+    deploy_defs: &deploy::DeployDefs,
+) -> String {
+    let repo = std::fs::canonicalize(deploy_flake.repo)
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| deploy_flake.repo.to_string());
+
+    format!(
+        "{}#{}.{} {}@{}:{}",
+        repo,
+        deploy_data.node_name,
+        deploy_data.profile_name,
+        deploy_defs.ssh_user,
+        deploy_defs.hostname,
+        deploy_defs.profile_path
+    )
+}
+
+#[test]
+fn test_deployed_state_key() {
+    let data = nodes_data(&[("web", &[], &["system"])]);
+    let node = &data.nodes["web"];
+
+    let key_of = |repo: &str, cmd_overrides: &deploy::CmdOverrides| {
+        let deploy_flake = deploy::parse_flake(repo).unwrap();
+        let deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            node,
+            "web",
+            &node.node_settings.profiles["system"],
+            "system",
+            cmd_overrides,
+            false,
+            None,
+        );
+        deployed_state_key(&deploy_flake, &deploy_data, &deploy_data.defs().unwrap())
+    };
+
+    let defaults = deploy::CmdOverrides {
+        ssh_user: Some("admin".to_string()),
+        ..Default::default()
+    };
+    let key = key_of("github:example/fleet", &defaults);
+
+    assert_eq!(
+        key,
+        "github:example/fleet#web.system admin@web.example.com:/nix/var/nix/profiles/per-user/admin/system"
+    );
+    assert_eq!(key_of("github:example/fleet", &defaults), key);
+    assert_ne!(key_of("github:example/other", &defaults), key);
+
+    let staging = deploy::CmdOverrides {
+        ssh_user: Some("admin".to_string()),
+        hostname: Some("staging.example.com".to_string()),
+        ..Default::default()
+    };
+    assert_ne!(key_of("github:example/fleet", &staging), key);
+}
+
+/// Reads the store paths the profiles were last deployed with, which are none if nothing was recorded yet
+fn read_deployed_state() -> HashMap<String, String> {
+    deployed_state_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Records the store paths of the activated profiles, failing to do so only results in a warning
+fn write_deployed_state(state: &HashMap<String, String>) {
+    let path = match deployed_state_path() {
+        Some(path) => path,
+        None => return,
+    };
+
+    let result = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| {
+            std::fs::write(
+                &path,
+                serde_json::to_string_pretty(state).expect("State should be serializable"),
+            )
+        });
+
+    if let Err(e) = result {
+        warn!(
+            "Failed to record the deployed profiles in {}: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+/// How the results of a deployment are reported
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Only log what is happening
+    Human,
+    /// Additionally print a JSON report of the deployment to stdout once it is done
+    Json,
+}
 
-                    for profile_name in [
-                        node.node_settings.profiles_order.iter().collect(),
-                        node.node_settings.profiles.keys().collect::<Vec<&String>>(),
-                    ]
-                    .concat()
-                    {
-                        let profile = match node.node_settings.profiles.get(profile_name) {
-                            Some(x) => x,
-                            None => {
-                                return Err(RunDeployError::ProfileNotFound(profile_name.clone()))
-                            }
-                        };
+/// The outcome of a single step (pushing or activating) of deploying a profile
+#[derive(Serialize, Debug)]
+struct StepResult {
+    succeeded: bool,
+    duration_secs: f64,
+    error: Option<String>,
+}
 
-                        if !profiles_list.iter().any(|(n, _)| n == profile_name) {
-                            profiles_list.push((profile_name, profile));
-                        }
-                    }
+#[derive(Serialize, Debug)]
+struct ProfileResult {
+    flake: String,
+    node: String,
+    profile: String,
+    push: Option<StepResult>,
+    activation: Option<StepResult>,
+    #[serde(skip)]
+    started: Option<Instant>,
+    #[serde(skip)]
+    finished: Option<Instant>,
+}
 
-                    profiles_list
-                        .into_iter()
-                        .map(|x| (deploy_flake, data, (node_name.as_str(), node), x))
-                        .collect()
-                }
-                (None, None) => {
-                    let mut l = Vec::new();
+#[derive(Serialize, Debug)]
+struct DeployReport {
+    succeeded: bool,
+    error: Option<String>,
+    profiles: Vec<ProfileResult>,
+}
 
-                    for (node_name, node) in &data.nodes {
-                        let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
-
-                        for profile_name in [
-                            node.node_settings.profiles_order.iter().collect(),
-                            node.node_settings.profiles.keys().collect::<Vec<&String>>(),
-                        ]
-                        .concat()
-                        {
-                            let profile = match node.node_settings.profiles.get(profile_name) {
-                                Some(x) => x,
-                                None => {
-                                    return Err(RunDeployError::ProfileNotFound(
-                                        profile_name.clone(),
-                                    ))
-                                }
-                            };
+/// Results of every step taken so far, shared between nodes deployed in parallel
+struct DeployResults(Mutex<Vec<ProfileResult>>);
+
+impl DeployResults {
+    /// Creates results for the given profiles, none of which have started deploying yet
+    fn new(parts: &[Part]) -> Self {
+        DeployResults(Mutex::new(
+            parts
+                .iter()
+                .map(|(deploy_flake, deploy_data, _)| ProfileResult {
+                    flake: deploy_flake.repo.to_string(),
+                    node: deploy_data.node_name.to_string(),
+                    profile: deploy_data.profile_name.to_string(),
+                    push: None,
+                    activation: None,
+                    started: None,
+                    finished: None,
+                })
+                .collect(),
+        ))
+    }
 
-                            if !profiles_list.iter().any(|(n, _)| n == profile_name) {
-                                profiles_list.push((profile_name, profile));
-                            }
-                        }
+    fn record<E: std::fmt::Display>(
+        &self,
+        part: &Part,
+        started: Instant,
+        result: &Result<(), E>,
+        step: fn(&mut ProfileResult) -> &mut Option<StepResult>,
+    ) {
+        let step_result = StepResult {
+            succeeded: result.is_ok(),
+            duration_secs: started.elapsed().as_secs_f64(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        };
+
+        let (deploy_flake, deploy_data, _) = part;
+
+        let mut results = self.0.lock().expect("Results lock should not be poisoned");
+
+        if let Some(profile_result) = results.iter_mut().find(|r| {
+            r.flake == deploy_flake.repo
+                && r.node == deploy_data.node_name
+                && r.profile == deploy_data.profile_name
+        }) {
+            *step(profile_result) = Some(step_result);
+
+            profile_result.started = Some(match profile_result.started {
+                Some(x) => x.min(started),
+                None => started,
+            });
+            profile_result.finished = Some(Instant::now());
+        }
+    }
 
-                        let ll: ToDeploy = profiles_list
-                            .into_iter()
-                            .map(|x| (deploy_flake, data, (node_name.as_str(), node), x))
-                            .collect();
+    /// Logs how long the deployment took, and which node took the longest
+    fn log_timing(&self, started: Instant) {
+        let results = self.0.lock().expect("Results lock should not be poisoned");
 
-                        l.extend(ll);
-                    }
+        // How long each node took from starting on its first profile to finishing its last one
+        let mut nodes: Vec<(&str, Instant, Instant)> = Vec::new();
 
-                    l
+        for r in results.iter() {
+            if let (Some(profile_started), Some(profile_finished)) = (r.started, r.finished) {
+                match nodes.iter_mut().find(|(node, _, _)| *node == r.node) {
+                    Some((_, node_started, node_finished)) => {
+                        *node_started = (*node_started).min(profile_started);
+                        *node_finished = (*node_finished).max(profile_finished);
+                    }
+                    None => nodes.push((&r.node, profile_started, profile_finished)),
                 }
-                (None, Some(_)) => return Err(RunDeployError::ProfileWithoutNode),
+            }
+        }
+
+        let slowest = nodes
+            .iter()
+            .map(|(node, node_started, node_finished)| (node, *node_finished - *node_started))
+            .max_by_key(|(_, duration)| *duration);
+
+        match slowest {
+            Some((node, duration)) => info!(
+                "Deployment took {:.1}s, the slowest node was `{}` with {:.1}s",
+                started.elapsed().as_secs_f64(),
+                node,
+                duration.as_secs_f64()
+            ),
+            None => info!("Deployment took {:.1}s", started.elapsed().as_secs_f64()),
+        }
+    }
+
+    /// Logs which profiles have been deployed, and which have not (yet)
+    fn log_progress(&self) {
+        let results = self.0.lock().expect("Results lock should not be poisoned");
+
+        for r in results.iter() {
+            let state = match (&r.push, &r.activation) {
+                (
+                    _,
+                    Some(StepResult {
+                        succeeded: true, ..
+                    }),
+                ) => "activated",
+                (_, Some(_)) => "failed to activate",
+                (
+                    Some(StepResult {
+                        succeeded: true, ..
+                    }),
+                    None,
+                ) => "pushed, not activated",
+                (Some(_), None) => "failed to push",
+                (None, None) => "not started",
             };
-            Ok(to_deploys)
+
+            info!("Profile `{}` of node `{}`: {}", r.profile, r.node, state);
+        }
+    }
+
+    fn into_inner(self) -> std::sync::LockResult<Vec<ProfileResult>> {
+        self.0.into_inner()
+    }
+}
+
+/// Escapes a label value of the Prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Reads the values of the previous metrics file, so that counters and the time of the last success carry over
+fn read_previous_metrics(contents: &str) -> HashMap<String, f64> {
+    contents
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            Some((series.to_string(), value.parse().ok()?))
         })
-        .collect::<Result<Vec<ToDeploy>, RunDeployError>>()?
-        .into_iter()
-        .flatten()
-        .collect();
+        .collect()
+}
 
-    let mut parts: Vec<(
-        &deploy::DeployFlake<'_>,
-        deploy::DeployData,
-        deploy::DeployDefs,
-    )> = Vec::new();
+/// Formats the results of a deployment in the Prometheus text format, for the textfile collector of node-exporter
+fn format_metrics(
+    results: &[ProfileResult],
+    succeeded: bool,
+    duration: Duration,
+    now: f64,
+    previous: &HashMap<String, f64>,
+) -> String {
+    let mut metrics = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, series: Vec<(String, f64)>| {
+        metrics.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, kind
+        ));
+
+        for (labels, value) in series {
+            metrics.push_str(&format!("{}{} {}\n", name, labels, value));
+        }
+    };
 
-    for (deploy_flake, data, (node_name, node), (profile_name, profile)) in to_deploy {
-        let deploy_data = deploy::make_deploy_data(
-            &data.generic_settings,
-            node,
-            node_name,
-            profile,
-            profile_name,
-            cmd_overrides,
-            debug_logs,
-            log_dir.as_deref(),
+    metric(
+        "deploy_last_run_timestamp_seconds",
+        "gauge",
+        "When the last deployment finished",
+        vec![(String::new(), now)],
+    );
+
+    let last_success = match succeeded {
+        true => Some(now),
+        false => previous
+            .get("deploy_last_success_timestamp_seconds")
+            .copied(),
+    };
+    if let Some(last_success) = last_success {
+        metric(
+            "deploy_last_success_timestamp_seconds",
+            "gauge",
+            "When the last successful deployment finished",
+            vec![(String::new(), last_success)],
         );
+    }
 
-        let deploy_defs = deploy_data.defs()?;
+    metric(
+        "deploy_success",
+        "gauge",
+        "Whether the last deployment succeeded",
+        vec![(String::new(), if succeeded { 1.0 } else { 0.0 })],
+    );
+    metric(
+        "deploy_duration_seconds",
+        "gauge",
+        "How long the last deployment took",
+        vec![(String::new(), duration.as_secs_f64())],
+    );
+
+    let step_labels = |r: &ProfileResult, step: Option<&str>| {
+        format!(
+            "{{flake=\"{}\",node=\"{}\",profile=\"{}\"{}}}",
+            escape_label(&r.flake),
+            escape_label(&r.node),
+            escape_label(&r.profile),
+            step.map_or(String::new(), |x| format!(",step=\"{}\"", x))
+        )
+    };
+    let labels = |r: &ProfileResult| step_labels(r, None);
+    let activated = |r: &ProfileResult| {
+        matches!(
+            r.activation,
+            Some(StepResult {
+                succeeded: true,
+                ..
+            })
+        )
+    };
+    // Profiles that were not attempted are neither counted as a success nor a failure
+    let attempted = |r: &ProfileResult| r.push.is_some() || r.activation.is_some();
+
+    let counter = |name: &str, r: &ProfileResult, count: bool| {
+        let previous = previous
+            .get(&format!("{}{}", name, labels(r)))
+            .copied()
+            .unwrap_or(0.0);
+        (labels(r), previous + if count { 1.0 } else { 0.0 })
+    };
 
-        parts.push((deploy_flake, deploy_data, deploy_defs));
+    metric(
+        "deploy_profile_success",
+        "gauge",
+        "Whether the profile was activated by the last deployment",
+        results
+            .iter()
+            .filter(|r| attempted(r))
+            .map(|r| (labels(r), if activated(r) { 1.0 } else { 0.0 }))
+            .collect(),
+    );
+    metric(
+        "deploy_profile_successes_total",
+        "counter",
+        "How many deployments activated the profile",
+        results
+            .iter()
+            .map(|r| counter("deploy_profile_successes_total", r, activated(r)))
+            .collect(),
+    );
+    metric(
+        "deploy_profile_failures_total",
+        "counter",
+        "How many deployments failed to push or activate the profile",
+        results
+            .iter()
+            .map(|r| {
+                counter(
+                    "deploy_profile_failures_total",
+                    r,
+                    attempted(r) && !activated(r),
+                )
+            })
+            .collect(),
+    );
+    metric(
+        "deploy_profile_duration_seconds",
+        "gauge",
+        "How long pushing and activating the profile took in the last deployment",
+        results
+            .iter()
+            .flat_map(|r| {
+                vec![("push", &r.push), ("activation", &r.activation)]
+                    .into_iter()
+                    .filter_map(move |(step, result)| {
+                        Some((step_labels(r, Some(step)), result.as_ref()?.duration_secs))
+                    })
+            })
+            .collect(),
+    );
+
+    metrics
+}
+
+#[test]
+fn test_format_metrics() {
+    let step = |succeeded, duration_secs| {
+        Some(StepResult {
+            succeeded,
+            duration_secs,
+            error: None,
+        })
+    };
+    let result = |node: &str, push, activation| ProfileResult {
+        flake: ".".to_string(),
+        node: node.to_string(),
+        profile: "system".to_string(),
+        push,
+        activation,
+        started: None,
+        finished: None,
+    };
+
+    let results = vec![
+        result("web", step(true, 1.5), step(true, 2.0)),
+        result("we\"b\\", step(false, 0.5), None),
+        result("db", None, None),
+    ];
+
+    let previous = read_previous_metrics(
+        "# HELP deploy_profile_successes_total How many deployments activated the profile\n\
+         deploy_profile_successes_total{flake=\".\",node=\"web\",profile=\"system\"} 2\n\
+         deploy_last_success_timestamp_seconds 100\n",
+    );
+
+    let metrics = format_metrics(&results, true, Duration::from_secs(4), 200.0, &previous);
+
+    assert!(metrics.contains(
+        "# HELP deploy_success Whether the last deployment succeeded\n# TYPE deploy_success gauge\ndeploy_success 1\n"
+    ));
+    assert!(metrics.contains("deploy_last_run_timestamp_seconds 200\n"));
+    assert!(metrics.contains("deploy_last_success_timestamp_seconds 200\n"));
+    assert!(metrics.contains("deploy_duration_seconds 4\n"));
+
+    // Counters carry over from the previous file
+    assert!(metrics.contains(
+        "deploy_profile_successes_total{flake=\".\",node=\"web\",profile=\"system\"} 3\n"
+    ));
+    assert!(metrics.contains(
+        "deploy_profile_duration_seconds{flake=\".\",node=\"web\",profile=\"system\",step=\"push\"} 1.5\n"
+    ));
+
+    // Label values are escaped
+    assert!(metrics.contains(
+        "deploy_profile_failures_total{flake=\".\",node=\"we\\\"b\\\\\",profile=\"system\"} 1\n"
+    ));
+
+    // Profiles that were not attempted have no success gauge
+    assert!(!metrics.contains("deploy_profile_success{flake=\".\",node=\"db\""));
+    assert!(metrics
+        .contains("deploy_profile_failures_total{flake=\".\",node=\"db\",profile=\"system\"} 0\n"));
+
+    // A failed run keeps the time of the last success
+    let failed = format_metrics(
+        &results,
+        false,
+        Duration::from_secs(4),
+        300.0,
+        &read_previous_metrics(&metrics),
+    );
+    assert!(failed.contains("deploy_success 0\n"));
+    assert!(failed.contains("deploy_last_success_timestamp_seconds 200\n"));
+    assert!(failed.contains(
+        "deploy_profile_successes_total{flake=\".\",node=\"web\",profile=\"system\"} 4\n"
+    ));
+
+    let first = format_metrics(
+        &results,
+        false,
+        Duration::from_secs(4),
+        300.0,
+        &HashMap::new(),
+    );
+    assert!(!first.contains("deploy_last_success_timestamp_seconds"));
+}
+
+/// Writes the metrics of the deployment to `path`, replacing it at once so that it is never scraped half written.
+/// Failing to do so only warns, as the deployment itself is done by then.
+fn write_metrics(path: &str, results: &[ProfileResult], succeeded: bool, duration: Duration) {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |x| x.as_secs_f64());
+
+    let previous = std::fs::read_to_string(path)
+        .map(|x| read_previous_metrics(&x))
+        .unwrap_or_default();
+
+    let metrics = format_metrics(results, succeeded, duration, now, &previous);
+
+    let tmp_path = format!("{}.tmp", path);
+    let result = std::fs::write(&tmp_path, metrics).and_then(|_| std::fs::rename(&tmp_path, path));
+
+    if let Err(e) = result {
+        warn!("Failed to write the metrics to {}: {}", path, e);
     }
+}
 
-    if interactive {
-        prompt_deployment(&parts[..])?;
-    } else {
-        print_deployment(&parts[..])?;
+struct DeployPartsOptions<'a> {
+    supports_flakes: bool,
+    check_sigs: bool,
+    allow_unsigned_local: bool,
+    cmd_overrides: &'a deploy::CmdOverrides,
+    keep_result: bool,
+    result_path: Option<&'a str>,
+    extra_build_args: &'a [String],
+    dry_activate: bool,
+    rollback_succeeded: bool,
+    copy_retries: u32,
+    build_timeout: Option<u64>,
+    show_closure_size: bool,
+    interactive: bool,
+    pipeline: bool,
+    /// When pipelining, all profiles of a node have to be copied before any of them is activated
+    no_activate_if_push_failed: bool,
+    skip_push: bool,
+    /// The profiles are already built locally, so they are only copied
+    prebuilt: bool,
+    results: &'a DeployResults,
+    events: Option<&'a mpsc::UnboundedSender<deploy::DeployEvent>>,
+}
+
+impl DeployPartsOptions<'_> {
+    /// Sends a progress event for the given profile, if anyone is listening
+    fn emit(&self, part: &Part, event: impl FnOnce(String, String, String) -> deploy::DeployEvent) {
+        if let Some(events) = self.events {
+            let (deploy_flake, deploy_data, _) = part;
+
+            // A closed channel means nobody is interested anymore, which is not a reason to stop deploying
+            let _ = events.send(event(
+                deploy_flake.repo.to_string(),
+                deploy_data.node_name.to_string(),
+                deploy_data.profile_name.to_string(),
+            ));
+        }
     }
+}
 
-    for (deploy_flake, deploy_data, deploy_defs) in &parts {
-        deploy::push::push_profile(deploy::push::PushProfileData {
-            supports_flakes,
-            check_sigs,
+fn error_string<E: std::fmt::Display>(result: &Result<(), E>) -> Option<String> {
+    result.as_ref().err().map(|e| e.to_string())
+}
+
+/// Returns if both profiles are copied over the same connection, so that they can be copied at once
+fn same_copy_target(a: &Part, b: &Part) -> bool {
+    let (_, a_data, a_defs) = a;
+    let (_, b_data, b_defs) = b;
+
+    a_defs.ssh_user == b_defs.ssh_user
+        && a_defs.hostname == b_defs.hostname
+        && a_data.merged_settings.ssh_opts == b_data.merged_settings.ssh_opts
+        && a_data.merged_settings.fast_connection == b_data.merged_settings.fast_connection
+}
+
+/// Records and reports that pushing the profile finished
+fn finish_push(
+    options: &DeployPartsOptions<'_>,
+    part: &Part,
+    started: Instant,
+    result: &Result<(), deploy::push::PushProfileError>,
+) {
+    options
+        .results
+        .record(part, started, result, |r| &mut r.push);
+
+    let error = error_string(result);
+    options.emit(part, |flake, node, profile| {
+        deploy::DeployEvent::PushFinished {
+            flake,
+            node,
+            profile,
+            error,
+        }
+    });
+}
+
+type CopiedReceiver = mpsc::Receiver<Result<(), deploy::push::PushProfileError>>;
+
+type CopyGroup<'a, 'b> = Vec<(&'b Part<'a>, Instant, deploy::push::PushProfileData<'b>)>;
+
+/// Copies all profiles of the group with a single `nix copy`, or with `--skip-push` checks that they are already there
+async fn copy_group(
+    group: &CopyGroup<'_, '_>,
+    options: &DeployPartsOptions<'_>,
+) -> Result<(), deploy::push::PushProfileError> {
+    let profiles = group
+        .iter()
+        .map(|(_, _, push_data)| push_data)
+        .collect::<Vec<&deploy::push::PushProfileData>>();
+
+    let node_name = group[0].0 .1.node_name;
+
+    let result = deploy::with_log_target(node_name, None, async {
+        match options.skip_push {
+            true => deploy::push::check_present(&profiles).await,
+            false => deploy::push::copy_profiles(&profiles).await,
+        }
+    })
+    .await;
+
+    for (part, started, _) in group {
+        finish_push(options, part, *started, &result);
+    }
+
+    result
+}
+
+/// Pushes all of the given profiles, then activates them in order.
+/// If an activation fails, all previously activated profiles are revoked (if allowed).
+/// With `--pipeline`, the profiles of the next node are copied while the current one is activated instead.
+async fn deploy_parts(
+    parts: &[&Part<'_>],
+    options: &DeployPartsOptions<'_>,
+) -> Result<(), RunDeployError> {
+    // Profiles are built one after another, but copied together with all others going to the same node
+    // over the same connection, so that paths they share are only copied once
+    let mut to_copy: Vec<CopyGroup> = Vec::new();
+
+    for part in parts.iter().copied() {
+        let (deploy_flake, deploy_data, deploy_defs) = part;
+
+        options.emit(part, |flake, node, profile| {
+            deploy::DeployEvent::PushStarted {
+                flake,
+                node,
+                profile,
+            }
+        });
+
+        let started = Instant::now();
+
+        let push_data = deploy::push::PushProfileData {
+            supports_flakes: options.supports_flakes,
+            check_sigs: options.check_sigs,
+            allow_unsigned_local: options.allow_unsigned_local,
             repo: deploy_flake.repo,
             deploy_data,
             deploy_defs,
-            keep_result,
-            result_path,
-            extra_build_args,
-        })
-        .await?;
+            keep_result: options.keep_result,
+            result_path: options.result_path,
+            extra_build_args: options.extra_build_args,
+            copy_retries: options.copy_retries,
+            build_timeout: options.build_timeout,
+            show_closure_size: options.show_closure_size,
+        };
+
+        let build = !options.skip_push && !options.prebuilt;
+
+        let result = match build {
+            true => {
+                deploy::with_log_target(
+                    deploy_data.node_name,
+                    Some(deploy_data.profile_name),
+                    deploy::push::build_profile(&push_data),
+                )
+                .await
+            }
+            false => Ok(()),
+        };
+
+        if result.is_err() || (build && deploy::push::builds_remotely(&push_data)) {
+            finish_push(options, part, started, &result);
+            result?;
+        } else {
+            match to_copy
+                .iter_mut()
+                .find(|group| same_copy_target(group[0].0, part))
+            {
+                Some(group) => group.push((part, started, push_data)),
+                None => to_copy.push(vec![(part, started, push_data)]),
+            }
+        }
+    }
+
+    if !options.pipeline {
+        for group in &to_copy {
+            copy_group(group, options).await?;
+        }
+
+        return activate_parts(parts, options, None).await;
     }
 
-    let mut succeeded: Vec<(&deploy::DeployData, &deploy::DeployDefs)> = vec![];
+    // The copy group of each profile, which has to be copied before the profile can be activated
+    let copy_groups: Vec<Option<usize>> = parts
+        .iter()
+        .map(|part| {
+            to_copy
+                .iter()
+                .position(|group| group.iter().any(|(p, _, _)| std::ptr::eq(*p, *part)))
+        })
+        .collect();
+
+    // Only one finished copy can wait to be activated, so copying stays at most a node ahead
+    let (copied_tx, copied_rx) = mpsc::channel(1);
+
+    let copying = async move {
+        for group in &to_copy {
+            let result = copy_group(group, options).await;
+            let failed = result.is_err();
+
+            // If activating failed, nothing more needs to be copied
+            if copied_tx.send(result).await.is_err() || failed {
+                break;
+            }
+        }
+    };
+
+    let (_, result) = tokio::join!(
+        copying,
+        activate_parts(parts, options, Some((&copy_groups, copied_rx)))
+    );
+
+    result
+}
+
+/// Activates the given profiles in order, revoking all previously activated ones if one fails.
+/// When pipelining, the copy groups of the profiles are given along with the results of copying them,
+/// as they come in.
+async fn activate_parts(
+    parts: &[&Part<'_>],
+    options: &DeployPartsOptions<'_>,
+    mut pipeline: Option<(&[Option<usize>], CopiedReceiver)>,
+) -> Result<(), RunDeployError> {
+    let mut succeeded: Vec<&Part> = vec![];
+    let mut copied = 0;
 
     // Run all deployments
     // In case of an error rollback any previoulsy made deployment.
     // Rollbacks adhere to the global seeting to auto_rollback and secondary
     // the profile's configuration
-    for (_, deploy_data, deploy_defs) in &parts {
-        if let Err(e) = deploy::deploy::deploy_profile(deploy_data, deploy_defs, dry_activate).await
-        {
-            error!("{}", e);
-            if dry_activate {
-                info!("dry run, not rolling back");
-            }
-            info!("Revoking previous deploys");
-            if rollback_succeeded && cmd_overrides.auto_rollback.unwrap_or(true) {
-                // revoking all previous deploys
-                // (adheres to profile configuration if not set explicitely by
-                //  the command line)
-                for (deploy_data, deploy_defs) in &succeeded {
-                    if deploy_data.merged_settings.auto_rollback.unwrap_or(true) {
-                        deploy::deploy::revoke(*deploy_data, *deploy_defs).await?;
+    for (i, part) in parts.iter().copied().enumerate() {
+        let (deploy_flake, deploy_data, deploy_defs) = part;
+
+        let same_node = |other: &&Part| {
+            other.0.repo == deploy_flake.repo && other.1.node_name == deploy_data.node_name
+        };
+
+        if let Some((copy_groups, copied_rx)) = &mut pipeline {
+            // The copy groups are in the order the profiles are copied in, so waiting for the last one
+            // of the node means waiting for all of them
+            let needed = match options.no_activate_if_push_failed {
+                true => parts
+                    .iter()
+                    .zip(copy_groups.iter())
+                    .filter(|(part, _)| same_node(part))
+                    .filter_map(|(_, group)| *group)
+                    .max(),
+                false => copy_groups[i],
+            };
+
+            while needed.is_some_and(|group| copied <= group) {
+                match copied_rx.recv().await {
+                    Some(Ok(())) => copied += 1,
+                    Some(Err(e)) => {
+                        error!("{}", e);
+                        revoke_succeeded(&succeeded, options).await?;
+                        return Err(e.into());
                     }
+                    None => break,
                 }
             }
-            break;
         }
-        succeeded.push((deploy_data, deploy_defs))
+
+        // Ask once per node, right before its first profile is activated
+        if options.interactive && !parts[..i].iter().any(same_node) {
+            let node_parts: Vec<&Part> = parts.iter().copied().filter(same_node).collect();
+
+            prompt_deployment(&node_parts, deploy_data.node_name).await?;
+        }
+
+        options.emit(part, |flake, node, profile| {
+            deploy::DeployEvent::ActivationStarted {
+                flake,
+                node,
+                profile,
+            }
+        });
+
+        let started = Instant::now();
+
+        let result = deploy::with_log_target(
+            deploy_data.node_name,
+            Some(deploy_data.profile_name),
+            deploy::deploy::deploy_profile(deploy_data, deploy_defs, options.dry_activate),
+        )
+        .await;
+
+        options
+            .results
+            .record(part, started, &result, |r| &mut r.activation);
+
+        let error = error_string(&result);
+        options.emit(part, |flake, node, profile| {
+            deploy::DeployEvent::ActivationFinished {
+                flake,
+                node,
+                profile,
+                error,
+            }
+        });
+
+        if let Err(e) = result {
+            error!("{}", e);
+            revoke_succeeded(&succeeded, options).await?;
+            return Err(e.into());
+        }
+        succeeded.push(part)
+    }
+
+    Ok(())
+}
+
+/// Revokes the profiles that were activated before a later one failed, if allowed
+async fn revoke_succeeded(
+    succeeded: &[&Part<'_>],
+    options: &DeployPartsOptions<'_>,
+) -> Result<(), RunDeployError> {
+    if options.dry_activate {
+        info!("dry run, not rolling back");
+    } else if options.rollback_succeeded && options.cmd_overrides.auto_rollback.unwrap_or(true) {
+        info!("Revoking previous deploys");
+        // revoking all previous deploys
+        // (adheres to profile configuration if not set explicitely by
+        //  the command line)
+        for succeeded_part in succeeded.iter().copied() {
+            let (_, deploy_data, deploy_defs) = succeeded_part;
+
+            if deploy_data.merged_settings.auto_rollback.unwrap_or(true)
+                && deploy::deploy::prompt_rollback(deploy_data).await
+            {
+                options.emit(succeeded_part, |flake, node, profile| {
+                    deploy::DeployEvent::RollbackTriggered {
+                        flake,
+                        node,
+                        profile,
+                    }
+                });
+
+                deploy::with_log_target(
+                    deploy_data.node_name,
+                    Some(deploy_data.profile_name),
+                    deploy::deploy::revoke(deploy_data, deploy_defs),
+                )
+                .await?;
+            }
+        }
     }
 
     Ok(())
@@ -593,6 +3480,8 @@ pub enum RunError {
     PushProfile(#[from] deploy::push::PushProfileError),
     #[error("Failed to test for flake support: {0}")]
     FlakeTest(std::io::Error),
+    #[error("Failed to set up the `ssh` binary: {0}")]
+    SetBinaries(std::io::Error),
     #[error("Failed to check deployment: {0}")]
     CheckDeployment(#[from] CheckDeploymentError),
     #[error("Failed to evaluate deployment data: {0}")]
@@ -603,71 +3492,530 @@ pub enum RunError {
     Logger(#[from] flexi_logger::FlexiLoggerError),
     #[error("{0}")]
     RunDeploy(#[from] RunDeployError),
+    #[error("The deployment data has the following problems:\n{0}")]
+    InvalidDeploymentData(String),
+    #[error("Failed to read the deployment data file: {0}")]
+    DeployDataFile(std::io::Error),
+    #[error("Failed to parse the deployment data file: {0}")]
+    DeployDataFileParse(serde_json::Error),
+    #[error("Activation environment variable `{0}` is not given as `KEY=VALUE`")]
+    InvalidActivationEnv(String),
+    #[error("Target `{0}` is not given as `user@host` or `host`")]
+    InvalidTarget(String),
+    #[error("Invalid compression for copying: {0}")]
+    InvalidCopyCompression(&'static str),
+    #[error("Failed to read the config file {0}: {1}")]
+    ConfigFile(String, std::io::Error),
+    #[error("Failed to read the node file {0}: {1}")]
+    NodeFile(String, std::io::Error),
+    #[error("Failed to read the build arguments file {0}: {1}")]
+    BuildArgsFile(String, std::io::Error),
+    #[error("Failed to parse the build arguments file {0} as a JSON array of strings: {1}")]
+    BuildArgsFileParse(String, serde_json::Error),
+    #[error("Failed to parse the config file {0}: {1}")]
+    ConfigFileParse(String, toml::de::Error),
+    #[error("No node to roll back was given for flake {0}, use `<flake>#<node>`")]
+    RollbackNoNode(String),
+    #[error("Deploying a store path needs exactly one --node, a --profile and a --hostname")]
+    StorePathArguments,
+    #[error("`{0}` is not a path in the Nix store")]
+    InvalidStorePath(String),
+    #[error("Store path `{0}` does not exist locally, it has to be built or copied here first")]
+    StorePathMissing(String),
+}
+
+impl RunError {
+    /// The exit code the `deploy` command should exit with because of this error, see `exit_code`
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunError::DeployProfile(e) => deploy_profile_exit_code(e),
+            RunError::PushProfile(_) => exit_code::PUSH,
+            RunError::CheckDeployment(_)
+            | RunError::GetDeploymentData(_)
+            | RunError::InvalidDeploymentData(_)
+            | RunError::DeployDataFileParse(_) => exit_code::EVALUATION,
+            RunError::ParseFlake(_)
+            | RunError::DeployDataFile(_)
+            | RunError::InvalidActivationEnv(_)
+            | RunError::InvalidTarget(_)
+            | RunError::InvalidCopyCompression(_)
+            | RunError::RollbackNoNode(_)
+            | RunError::StorePathArguments
+            | RunError::InvalidStorePath(_)
+            | RunError::StorePathMissing(_)
+            | RunError::ConfigFile(_, _)
+            | RunError::NodeFile(_, _)
+            | RunError::BuildArgsFile(_, _)
+            | RunError::BuildArgsFileParse(_, _)
+            | RunError::ConfigFileParse(_, _)
+            | RunError::SetBinaries(_) => exit_code::INVALID_ARGUMENTS,
+            RunError::RunDeploy(e) => e.exit_code(),
+            RunError::FlakeTest(_) | RunError::Logger(_) => exit_code::OTHER,
+        }
+    }
+}
+
+/// Reads the arguments from the file given with `--build-args-file`, which is either a JSON array of them,
+/// or one per line. As arguments can contain spaces, lines are taken as they are, except that blank lines
+/// and lines starting with `#` are ignored
+fn read_build_args_file(path: &str) -> Result<Vec<String>, RunError> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| RunError::BuildArgsFile(path.to_string(), e))?;
+
+    if contents.trim_start().starts_with('[') {
+        return serde_json::from_str(&contents)
+            .map_err(|e| RunError::BuildArgsFileParse(path.to_string(), e));
+    }
+
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect())
+}
+
+#[test]
+fn test_read_build_args_file() {
+    let dir = deploy::PrivateDir::new("deploy-rs-test").unwrap();
+    let path = dir.path().join("args");
+    let path = path.to_str().unwrap();
+
+    // Lines are kept as they are, spaces included
+    std::fs::write(
+        path,
+        "# from CI\n--option\nsubstituters https://cache.example.com\n\n  \n--show-trace\n",
+    )
+    .unwrap();
+    assert_eq!(
+        read_build_args_file(path).unwrap(),
+        vec![
+            "--option",
+            "substituters https://cache.example.com",
+            "--show-trace"
+        ]
+    );
+
+    std::fs::write(path, "  [\"--option\", \"# not a comment\", \"\"]\n").unwrap();
+    assert_eq!(
+        read_build_args_file(path).unwrap(),
+        vec!["--option", "# not a comment", ""]
+    );
+
+    std::fs::write(path, "[\"--option\",").unwrap();
+    assert!(matches!(
+        read_build_args_file(path),
+        Err(RunError::BuildArgsFileParse(_, _))
+    ));
+
+    assert!(matches!(
+        read_build_args_file(&format!("{}.missing", path)),
+        Err(RunError::BuildArgsFile(_, _))
+    ));
+}
+
+/// Reads the node names from the file given with `--node-file`, one per line, ignoring blank lines and `#` comments
+fn read_node_file(path: &str) -> Result<Vec<String>, RunError> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| RunError::NodeFile(path.to_string(), e))?;
+
+    Ok(contents
+        .lines()
+        .map(|line| match line.split_once('#') {
+            Some((name, _)) => name.trim(),
+            None => line.trim(),
+        })
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect())
+}
+
+#[test]
+fn test_read_node_file() {
+    let dir = deploy::PrivateDir::new("deploy-rs-test").unwrap();
+    let path = dir.path().join("nodes");
+    let path = path.to_str().unwrap();
+
+    std::fs::write(
+        path,
+        "# the web servers\nweb1\n  web2  # in the other rack\n\n   \ndb\n",
+    )
+    .unwrap();
+    assert_eq!(read_node_file(path).unwrap(), vec!["web1", "web2", "db"]);
+
+    std::fs::write(path, "").unwrap();
+    assert!(read_node_file(path).unwrap().is_empty());
+
+    assert!(matches!(
+        read_node_file(&format!("{}.missing", path)),
+        Err(RunError::NodeFile(_, _))
+    ));
+}
+
+/// Makes the deployment data for deploying an already built store path as a single profile of a single node
+fn store_path_data(
+    store_path: &str,
+    nodes: &[String],
+    profile: Option<&str>,
+    hostname: Option<&str>,
+) -> Result<deploy::data::Data, RunError> {
+    let (node, profile, hostname) = match (nodes, profile, hostname) {
+        ([node], Some(profile), Some(hostname)) => (node, profile, hostname),
+        _ => return Err(RunError::StorePathArguments),
+    };
+
+    if !deploy::is_store_path(store_path) {
+        return Err(RunError::InvalidStorePath(store_path.to_string()));
+    }
+    if !std::path::Path::new(store_path).exists() {
+        return Err(RunError::StorePathMissing(store_path.to_string()));
+    }
+
+    info!(
+        "Deploying {} as profile `{}` of node `{}`, without evaluating a flake",
+        store_path, profile, node
+    );
+
+    serde_json::from_value(serde_json::json!({
+        "nodes": {
+            node: {
+                "hostname": hostname,
+                "profiles": { profile: { "path": store_path } }
+            }
+        }
+    }))
+    .map_err(RunError::DeployDataFileParse)
 }
 
 pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
-    let opts = match args {
+    run_with_events(args, None).await
+}
+
+/// Like `run`, but additionally sends the progress of the deployment to `events` as it happens
+pub async fn run_with_events(
+    args: Option<&ArgMatches>,
+    events: Option<mpsc::UnboundedSender<deploy::DeployEvent>>,
+) -> Result<(), RunError> {
+    let mut opts = match args {
         Some(o) => <Opts as FromArgMatches>::from_arg_matches(o),
         None => Opts::parse(),
     };
 
+    // The config file can set the log level, but errors reading it are only logged once the logger is set up
+    let config = match load_config(opts.config.as_deref()) {
+        Ok(Some((path, config))) => {
+            opts.apply_config(config);
+            Ok(Some(path))
+        }
+        result => result.map(|_| None),
+    };
+
     deploy::init_logger(
         opts.debug_logs,
         opts.log_dir.as_deref(),
+        opts.log_level
+            .or_else(|| deploy::verbosity_log_level(opts.verbose)),
         &deploy::LoggerType::Deploy,
     )?;
 
-    let deploys = opts
-        .clone()
-        .targets
-        .unwrap_or_else(|| vec![opts.clone().target.unwrap_or_else(|| ".".to_string())]);
+    if let Some(path) = config? {
+        info!("Using the defaults from {}", path);
+    }
+
+    // Deleted once everything is deployed, when this is dropped
+    let _ssh_dir = deploy::set_binaries(opts.nix_bin.as_deref(), opts.ssh_bin.as_deref())
+        .map_err(RunError::SetBinaries)?;
+
+    if let Some(ref build_args_file) = opts.build_args_file {
+        let build_args = read_build_args_file(build_args_file)?;
+        opts.extra_build_args.extend(build_args);
+    }
+
+    let rollback = matches!(opts.subcommand, Some(Subcommand::Rollback(_)));
+
+    let deploys = match opts.subcommand {
+        Some(Subcommand::Rollback(ref rollback_opts)) => vec![rollback_opts.target.clone()],
+        None => opts
+            .clone()
+            .targets
+            .unwrap_or_else(|| vec![opts.clone().target.unwrap_or_else(|| ".".to_string())]),
+    };
 
     let deploy_flakes: Vec<DeployFlake> = deploys
         .iter()
         .map(|f| deploy::parse_flake(f.as_str()))
         .collect::<Result<Vec<DeployFlake>, ParseFlakeError>>()?;
 
+    let activation_env = opts
+        .activation_env
+        .iter()
+        .map(|x| match x.split_once('=') {
+            Some((key, value)) => Ok((key.to_string(), value.to_string())),
+            None => Err(RunError::InvalidActivationEnv(x.clone())),
+        })
+        .collect::<Result<Vec<(String, String)>, RunError>>()?;
+
+    // Nix can not compress what it sends over SSH, and binary caches are not written over SSH
+    match (opts.copy_compression, opts.to_cache.is_some()) {
+        (Some(deploy::CopyCompression::Zstd), false)
+        | (Some(deploy::CopyCompression::Xz), false) => {
+            return Err(RunError::InvalidCopyCompression(
+                "`zstd` and `xz` only apply to --to-cache, use `ssh` for copies over SSH",
+            ))
+        }
+        (Some(deploy::CopyCompression::Ssh), true) => {
+            return Err(RunError::InvalidCopyCompression(
+                "`ssh` does not apply to --to-cache, use `zstd` or `xz`",
+            ))
+        }
+        _ => (),
+    }
+
+    if opts.copy_compression_level.is_some()
+        && !matches!(
+            opts.copy_compression,
+            Some(deploy::CopyCompression::Zstd) | Some(deploy::CopyCompression::Xz)
+        )
+    {
+        return Err(RunError::InvalidCopyCompression(
+            "--copy-compression-level needs --copy-compression `zstd` or `xz`",
+        ));
+    }
+
+    let (ssh_user, hostname) = match opts.ssh_target {
+        Some(ref target) => match target.split_once('@') {
+            Some((user, host)) if !user.is_empty() && !host.is_empty() && !host.contains('@') => {
+                (Some(user.to_string()), Some(host.to_string()))
+            }
+            None if !target.is_empty() => (opts.ssh_user, Some(target.clone())),
+            _ => return Err(RunError::InvalidTarget(target.clone())),
+        },
+        None => (opts.ssh_user, opts.hostname),
+    };
+
+    // There is nobody to answer the prompts if the input is not a terminal
+    let interactive = opts.interactive && !opts.yes && stdin().is_terminal();
+
     let cmd_overrides = deploy::CmdOverrides {
-        ssh_user: opts.ssh_user,
+        ssh_user,
         profile_user: opts.profile_user,
         ssh_opts: opts.ssh_opts,
         fast_connection: opts.fast_connection,
         auto_rollback: opts.auto_rollback,
-        hostname: opts.hostname,
+        hostname,
         magic_rollback: opts.magic_rollback,
         temp_path: opts.temp_path,
+        profile_path_template: opts.profile_path_template,
         confirm_timeout: opts.confirm_timeout,
-        dry_activate: opts.dry_activate,
+        dry_activate: opts.dry_activate
+            || opts.activation_mode == Some(deploy::data::ActivationMode::DryActivate),
+        dry_run: opts.dry_run,
+        remote_build: opts.remote_build,
+        local: opts.local,
+        ssh_multiplexing: opts.ssh_multiplexing,
+        jump_host: opts.jump_host,
+        ssh_connect_timeout: opts.ssh_connect_timeout,
+        ssh_keepalive_interval: opts.ssh_keepalive_interval,
+        ssh_keepalive_count_max: opts.ssh_keepalive_count_max,
+        ssh_forward_agent: opts.forward_agent,
+        ssh_gssapi: opts.gssapi,
+        verbose_ssh: opts.verbose_ssh,
+        keep_generations: opts.keep_generations,
+        remote_lock: opts.remote_lock || opts.lock_timeout.is_some(),
+        lock_timeout: opts.lock_timeout,
+        on_failure: opts.on_failure,
+        reboot: opts.reboot || opts.reboot_timeout.is_some(),
+        reboot_timeout: opts.reboot_timeout,
+        confirm_retries: opts.confirm_retries.unwrap_or(0),
+        confirm_via_new_ssh: opts.confirm_via_new_ssh,
+        confirm_retry_delay: opts.confirm_retry_delay.unwrap_or(5),
+        rollback_prompt_timeout: if interactive {
+            Some(opts.rollback_prompt_timeout.unwrap_or(60))
+        } else {
+            None
+        },
+        activation_mode: opts.activation_mode,
+        ssh_config_file: opts.ssh_config_file,
+        activation_env,
+        verify_copy: opts.verify_copy,
+        copy_bwlimit: opts.copy_bwlimit,
+        copy_compression: opts.copy_compression,
+        copy_compression_level: opts.copy_compression_level,
+        to_cache: opts.to_cache,
+        systemd_run: opts.systemd_run,
+        diff_closures: opts.diff_closures,
+        force: opts.force,
+        substituters: opts.substituter,
+        trusted_public_keys: opts.trusted_public_key,
         sudo: opts.sudo,
     };
 
-    let supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
+    let mut nodes: Vec<String> = opts
+        .node
+        .iter()
+        .flat_map(|x| x.split(','))
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect();
+
+    if let Some(ref node_file) = opts.node_file {
+        nodes.extend(read_node_file(node_file)?);
+    }
+
+    let result_path = opts.result_path.as_deref();
+
+    let (supports_flakes, data) = match (&opts.store_path, &opts.deploy_data_file) {
+        (Some(store_path), _) => {
+            let data = store_path_data(
+                store_path,
+                &nodes,
+                opts.profile.as_deref(),
+                cmd_overrides.hostname.as_deref(),
+            )?;
+
+            (true, vec![data])
+        }
+        (None, Some(deploy_data_file)) => {
+            // The flake was evaluated (and checked) beforehand, so only building and deploying is left
+            info!("Loading deployment data from {}", deploy_data_file);
+
+            let data: deploy::data::Data = serde_json::from_str(
+                &std::fs::read_to_string(deploy_data_file).map_err(RunError::DeployDataFile)?,
+            )
+            .map_err(RunError::DeployDataFileParse)?;
+
+            (true, deploy_flakes.iter().map(|_| data.clone()).collect())
+        }
+        (None, None) => {
+            let mut supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
+
+            if !supports_flakes {
+                warn!("A Nix version without flakes support was detected, support for this is work in progress");
+            } else if let Some(deploy_flake) =
+                deploy_flakes.iter().find(|x| is_classic_repo(x.repo))
+            {
+                // Profiles are built the same way for all repositories, so a single one without flake.nix decides it
+                info!(
+                    "{} has no flake.nix, evaluating and building the classic way (with nix-instantiate and nix-build)",
+                    deploy_flake.repo
+                );
+                supports_flakes = false;
+            }
+
+            let mut eval_args = opts.eval_arg.clone();
+
+            if opts.impure {
+                eval_args.push("--impure".to_string());
+            }
+
+            for deploy_flake in &deploy_flakes {
+                if rollback {
+                    // Nothing is built when rolling back, and a broken flake should not prevent it
+                    debug!(
+                        "Not running the checks of `{}` to roll back",
+                        deploy_flake.repo
+                    );
+                } else if opts.skip_checks {
+                    warn!(
+                        "Skipping the pre-build checks (`nix flake check`) of `{}`",
+                        deploy_flake.repo
+                    );
+                } else {
+                    check_deployment(
+                        supports_flakes,
+                        deploy_flake.repo,
+                        &opts.extra_build_args,
+                        &eval_args,
+                    )
+                    .await?;
+                }
+            }
+
+            let data = get_deployment_data(
+                supports_flakes,
+                &deploy_flakes,
+                &opts.extra_build_args,
+                &eval_args,
+                !opts.no_eval_cache,
+            )
+            .await?;
+
+            (supports_flakes, data)
+        }
+    };
 
-    if !supports_flakes {
-        warn!("A Nix version without flakes support was detected, support for this is work in progress");
+    if rollback {
+        return run_rollback(
+            &deploy_flakes[0],
+            &data[0],
+            &cmd_overrides,
+            opts.debug_logs,
+            opts.log_dir.as_deref(),
+        )
+        .await;
     }
 
-    if !opts.skip_checks {
-        for deploy_flake in &deploy_flakes {
-            check_deployment(supports_flakes, deploy_flake.repo, &opts.extra_build_args).await?;
+    let problems: Vec<String> = deploy_flakes
+        .iter()
+        .zip(&data)
+        .flat_map(|(deploy_flake, data)| {
+            validate_deployment_data(deploy_flake, data, &cmd_overrides)
+                .into_iter()
+                .map(move |problem| format!("{}: {}", deploy_flake.repo, problem))
+        })
+        .collect();
+
+    if opts.skip_checks {
+        // The problems are still logged, so that deploying despite them is visible afterwards
+        for problem in &problems {
+            warn!("Ignoring failed deployment data check: {}", problem);
         }
+    } else if !problems.is_empty() {
+        return Err(RunError::InvalidDeploymentData(problems.join("\n")));
     }
-    let result_path = opts.result_path.as_deref();
-    let data = get_deployment_data(supports_flakes, &deploy_flakes, &opts.extra_build_args).await?;
+
+    if opts.interactive && !interactive {
+        info!(
+            "Not prompting before activation, as `--yes` was given or the input is not a terminal"
+        );
+    }
+
     run_deploy(
         deploy_flakes,
         data,
         supports_flakes,
-        opts.checksigs,
-        opts.interactive,
+        opts.checksigs || opts.allow_unsigned_local,
+        opts.allow_unsigned_local,
+        interactive,
         &cmd_overrides,
         opts.keep_result,
         result_path,
         &opts.extra_build_args,
         opts.debug_logs,
-        opts.dry_activate,
+        cmd_overrides.dry_activate,
         &opts.log_dir,
         opts.rollback_succeeded.unwrap_or(true),
+        opts.max_parallel,
+        &nodes,
+        opts.profile.as_deref(),
+        &opts.tag,
+        opts.all_tags,
+        opts.output,
+        opts.metrics_file.as_deref(),
+        opts.copy_retries.unwrap_or(0),
+        opts.timeout,
+        opts.build_timeout,
+        opts.show_closure_size,
+        events.as_ref(),
+        opts.on_error,
+        opts.pipeline,
+        opts.no_activate_if_push_failed,
+        opts.skip_push,
+        opts.store_path.is_some(),
+        opts.skip_preflight,
+        opts.print_deploy_data,
+        opts.plan,
+        opts.skip_unchanged,
+        opts.reverse,
     )
     .await?;
 
@@ -3,10 +3,62 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use merge::Merge;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Deserialize, Debug, Clone, Merge)]
+/// How a profile is activated, following the actions of NixOS' `switch-to-configuration`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, clap::ArgEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ActivationMode {
+    /// Activate the profile now and make it the default
+    Switch,
+    /// Make the profile the default, but only activate it on the next boot
+    Boot,
+    /// Activate the profile now, without making it the default
+    Test,
+    /// Only show what activating the profile would do
+    DryActivate,
+}
+
+impl ActivationMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActivationMode::Switch => "switch",
+            ActivationMode::Boot => "boot",
+            ActivationMode::Test => "test",
+            ActivationMode::DryActivate => "dry-activate",
+        }
+    }
+}
+
+/// Whose profile is deployed, which decides where it is installed and if `sudo` is used
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProfileType {
+    /// A profile in `/nix/var/nix/profiles`, activated as `user` (with `sudo` if that is not the SSH user)
+    System,
+    /// A profile of the SSH user in `~/.local/state/nix/profiles`, like one of home-manager, activated without `sudo`
+    User,
+}
+
+/// The operating system of a node, which decides how its profiles can be activated
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeSystem {
+    /// NixOS, or any other Linux
+    Nixos,
+    /// macOS with nix-darwin, which can only switch to profiles and has no `flock`
+    Darwin,
+}
+
+/// Merges environment variables, keeping the value of `left` for variables set in both
+fn merge_env(left: &mut HashMap<String, String>, right: HashMap<String, String>) {
+    for (key, value) in right {
+        left.entry(key).or_insert(value);
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Merge)]
 pub struct GenericSettings {
     #[serde(rename(deserialize = "sshUser"))]
     pub ssh_user: Option<String>,
@@ -26,10 +78,65 @@ pub struct GenericSettings {
     pub confirm_timeout: Option<u16>,
     #[serde(rename(deserialize = "tempPath"))]
     pub temp_path: Option<String>,
+    #[serde(rename(deserialize = "profilePathTemplate"))]
+    pub profile_path_template: Option<String>,
     #[serde(rename(deserialize = "magicRollback"))]
     pub magic_rollback: Option<bool>,
     #[serde(rename(deserialize = "sudo"))]
     pub sudo: Option<String>,
+    #[serde(rename(deserialize = "remoteBuild"))]
+    pub remote_build: Option<bool>,
+    #[serde(rename(deserialize = "sshMultiplexing"))]
+    pub ssh_multiplexing: Option<bool>,
+    #[serde(rename(deserialize = "jumpHost"))]
+    pub jump_host: Option<String>,
+    #[serde(rename(deserialize = "sshConnectTimeout"))]
+    pub ssh_connect_timeout: Option<u16>,
+    #[serde(rename(deserialize = "sshKeepaliveInterval"))]
+    pub ssh_keepalive_interval: Option<u16>,
+    #[serde(rename(deserialize = "sshKeepaliveCountMax"))]
+    pub ssh_keepalive_count_max: Option<u16>,
+    #[serde(rename(deserialize = "sshForwardAgent"))]
+    pub ssh_forward_agent: Option<bool>,
+    #[serde(rename(deserialize = "sshGssapi"))]
+    pub ssh_gssapi: Option<bool>,
+    #[serde(rename(deserialize = "systemdRun"))]
+    pub systemd_run: Option<bool>,
+    #[serde(rename(deserialize = "systemdRunArgs"))]
+    pub systemd_run_args: Option<Vec<String>>,
+    #[serde(rename(deserialize = "activationMode"))]
+    pub activation_mode: Option<ActivationMode>,
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        default,
+        rename(deserialize = "extraBuildArgs")
+    )]
+    #[merge(strategy = merge::vec::append)]
+    pub extra_build_args: Vec<String>,
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        default,
+        rename(deserialize = "sshIdentities")
+    )]
+    #[merge(strategy = merge::vec::append)]
+    pub ssh_identities: Vec<String>,
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        default,
+        rename(deserialize = "substituters")
+    )]
+    #[merge(strategy = merge::vec::append)]
+    pub substituters: Vec<String>,
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        default,
+        rename(deserialize = "trustedPublicKeys")
+    )]
+    #[merge(strategy = merge::vec::append)]
+    pub trusted_public_keys: Vec<String>,
+    #[serde(default, rename(deserialize = "activationEnv"))]
+    #[merge(strategy = merge_env)]
+    pub activation_env: HashMap<String, String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -42,6 +149,12 @@ pub struct NodeSettings {
         rename(deserialize = "profilesOrder")
     )]
     pub profiles_order: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub after: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub system: Option<NodeSystem>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -49,6 +162,17 @@ pub struct ProfileSettings {
     pub path: String,
     #[serde(rename(deserialize = "profilePath"))]
     pub profile_path: Option<String>,
+    #[serde(rename(deserialize = "confirmCommand"))]
+    pub confirm_command: Option<String>,
+    pub activate: Option<String>,
+    #[serde(rename(deserialize = "type"))]
+    pub profile_type: Option<ProfileType>,
+    #[serde(rename(deserialize = "preActivate"))]
+    pub pre_activate: Option<String>,
+    #[serde(rename(deserialize = "postActivate"))]
+    pub post_activate: Option<String>,
+    #[serde(rename(deserialize = "postActivateRollback"))]
+    pub post_activate_rollback: Option<bool>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
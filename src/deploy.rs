@@ -4,12 +4,15 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use std::borrow::Cow;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::process::Command;
 
-use crate::DeployDataDefsError;
+use crate::data::{ActivationMode, NodeSystem};
+use crate::{DeployDataDefsError, LogCommand};
 
 struct ActivateCommandData<'a> {
     sudo: &'a Option<String>,
@@ -22,6 +25,62 @@ struct ActivateCommandData<'a> {
     debug_logs: bool,
     log_dir: Option<&'a str>,
     dry_activate: bool,
+    activation_mode: ActivationMode,
+    env: &'a [(&'a str, &'a str)],
+    systemd_run: Option<&'a [String]>,
+    script: Option<&'a str>,
+    timeout: Option<u16>,
+}
+
+/// Seconds `timeout` waits on the node for the activation to stop after asking it to, before killing it
+const ACTIVATE_TIMEOUT_KILL_AFTER: u16 = 10;
+
+/// Exit codes of `timeout` on the node if it stopped the activation, by asking it to or by killing it
+const ACTIVATE_TIMEOUT_EXIT_CODES: [i32; 2] = [124, 128 + 9];
+
+/// Seconds to wait for SSH beyond the timeout of the activation, for the node to stop it and report back
+const ACTIVATE_TIMEOUT_GRACE: u64 = ACTIVATE_TIMEOUT_KILL_AFTER as u64 + 20;
+
+/// Quotes a string so that the shell on the node passes it on as a single argument, as is
+pub(crate) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Makes the command that runs a shell command (given as its last argument) on the node, which is `ssh`
+/// with the options of the node, or just `sh -c` when the node is the local machine
+pub(crate) fn node_command(
+    deploy_data: &super::DeployData<'_>,
+    ssh_addr: &str,
+) -> std::process::Command {
+    node_command_with_opts(deploy_data, ssh_addr, &[])
+}
+
+/// Like `node_command`, with SSH options that take precedence over those of the node
+fn node_command_with_opts(
+    deploy_data: &super::DeployData<'_>,
+    ssh_addr: &str,
+    ssh_opts: &[&str],
+) -> std::process::Command {
+    if deploy_data.is_local() {
+        let mut sh_command = std::process::Command::new("sh");
+        sh_command.arg("-c");
+
+        // Like over SSH, paths like those of user profiles are relative to the home directory
+        if let Some(home) = std::env::var_os("HOME") {
+            sh_command.current_dir(home);
+        }
+
+        return sh_command;
+    }
+
+    // SSH uses the first value given for an option
+    let mut ssh_command = std::process::Command::new(crate::ssh_bin());
+    ssh_command
+        .arg(ssh_addr)
+        .args(ssh_opts)
+        .args(&deploy_data.merged_settings.ssh_opts);
+
+    ssh_command
 }
 
 fn build_activate_command(data: &ActivateCommandData) -> String {
@@ -55,6 +114,39 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
 
     if data.dry_activate {
         self_activate_command = format!("{} --dry-activate", self_activate_command);
+    } else if data.activation_mode != ActivationMode::Switch {
+        self_activate_command = format!(
+            "{} --mode {}",
+            self_activate_command,
+            data.activation_mode.as_str()
+        );
+    }
+
+    if let Some(script) = data.script {
+        self_activate_command =
+            format!("{} --script {}", self_activate_command, shell_quote(script));
+    }
+
+    // Run by the user activating, so that it can stop everything the activation started
+    if let Some(timeout) = data.timeout {
+        self_activate_command = format!(
+            "timeout -k {} {} {}",
+            ACTIVATE_TIMEOUT_KILL_AFTER, timeout, self_activate_command
+        );
+    }
+
+    // Set by `env` after sudo, which would reset the environment otherwise
+    if !data.env.is_empty() {
+        let env: Vec<String> = data
+            .env
+            .iter()
+            .map(|(key, value)| shell_quote(&format!("{}={}", key, value)))
+            .collect();
+        self_activate_command = format!("env {} {}", env.join(" "), self_activate_command);
+    }
+
+    if let Some(systemd_run_args) = data.systemd_run {
+        self_activate_command = build_systemd_run_command(&self_activate_command, systemd_run_args);
     }
 
     if let Some(sudo_cmd) = &data.sudo {
@@ -88,11 +180,160 @@ fn test_activation_command_builder() {
             magic_rollback,
             debug_logs,
             log_dir,
-            dry_activate
+            dry_activate,
+            activation_mode: ActivationMode::Switch,
+            env: &[],
+            systemd_run: None,
+            script: None,
+            timeout: None,
         }),
         "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt activate '/nix/store/blah/etc' '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --magic-rollback --auto-rollback"
             .to_string(),
     );
+
+    assert_eq!(
+        build_activate_command(&ActivateCommandData {
+            sudo: &None,
+            profile_path,
+            closure,
+            auto_rollback,
+            temp_path,
+            confirm_timeout,
+            magic_rollback: false,
+            debug_logs: false,
+            log_dir: None,
+            dry_activate,
+            activation_mode: ActivationMode::Boot,
+            env: &[("DEPLOY_ID", "it's 42")],
+            systemd_run: None,
+            script: None,
+            timeout: None,
+        }),
+        "env 'DEPLOY_ID=it'\\''s 42' /nix/store/blah/etc/activate-rs activate '/nix/store/blah/etc' '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --auto-rollback --mode boot"
+            .to_string(),
+    );
+
+    assert_eq!(
+        build_activate_command(&ActivateCommandData {
+            sudo: &None,
+            profile_path,
+            closure,
+            auto_rollback,
+            temp_path,
+            confirm_timeout,
+            magic_rollback: false,
+            debug_logs: false,
+            log_dir: None,
+            dry_activate,
+            activation_mode: ActivationMode::Switch,
+            env: &[],
+            systemd_run: None,
+            script: Some("bin/activate"),
+            timeout: None,
+        }),
+        "/nix/store/blah/etc/activate-rs activate '/nix/store/blah/etc' '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --auto-rollback --script 'bin/activate'"
+            .to_string(),
+    );
+
+    assert_eq!(
+        build_activate_command(&ActivateCommandData {
+            sudo: &sudo,
+            profile_path,
+            closure,
+            auto_rollback,
+            temp_path,
+            confirm_timeout,
+            magic_rollback: false,
+            debug_logs: false,
+            log_dir: None,
+            dry_activate,
+            activation_mode: ActivationMode::Switch,
+            env: &[("DEPLOY_ID", "42")],
+            systemd_run: None,
+            script: None,
+            timeout: Some(120),
+        }),
+        "sudo -u test env 'DEPLOY_ID=42' timeout -k 10 120 /nix/store/blah/etc/activate-rs activate '/nix/store/blah/etc' '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --auto-rollback"
+            .to_string(),
+    );
+}
+
+/// Exit code of the locked activation command if the lock of the node is held by another deployment
+const NODE_LOCKED_EXIT_CODE: i32 = 75;
+
+/// Exit code of `activate-rs` if activating failed and rolling back afterwards failed as well
+pub const ROLLBACK_FAILED_EXIT_CODE: i32 = 76;
+
+/// Wraps a command so that it only runs while holding a `flock` on `lock_path` on the node,
+/// waiting up to `lock_timeout` seconds for it, or failing immediately if not given.
+/// Who holds the lock is recorded next to it, so that a waiting deployment can report it.
+fn build_locked_command(command: &str, lock_path: &str, lock_timeout: Option<u16>) -> String {
+    let flock_args = match lock_timeout {
+        Some(timeout) => format!("-w {}", timeout),
+        None => "-n".to_string(),
+    };
+
+    let script = format!(
+        "lock='{lock}'; touch \"$lock\" 2>/dev/null; exec 9<\"$lock\" || exit {code}; \
+         if ! flock {flock_args} 9; then \
+         echo \"Node is locked by another deployment ($(cat \"$lock.holder\" 2>/dev/null))\" >&2; exit {code}; fi; \
+         echo \"$(whoami) from ${{SSH_CLIENT%% *}} since $(date)\" > \"$lock.holder\" 2>/dev/null; \
+         {command}",
+        lock = lock_path,
+        code = NODE_LOCKED_EXIT_CODE,
+        flock_args = flock_args,
+        command = command,
+    );
+
+    format!("sh -c {}", shell_quote(&script))
+}
+
+#[test]
+fn test_locked_command_builder() {
+    let locked = build_locked_command("activate 'x'", "/tmp/deploy-rs.lock", Some(10));
+
+    assert!(locked.starts_with("sh -c 'lock='\\''/tmp/deploy-rs.lock'\\''; "));
+    assert!(locked.contains("flock -w 10 9"));
+    assert!(locked.ends_with("; activate '\\''x'\\'''"));
+
+    assert!(build_locked_command("activate", "/tmp/deploy-rs.lock", None).contains("flock -n 9"));
+}
+
+/// Wraps a command so that it runs in a transient systemd scope (with `args` passed on to `systemd-run`),
+/// where it keeps running if the SSH session drops. Nodes without `systemd-run` (or not running systemd) run the command as is.
+fn build_systemd_run_command(command: &str, args: &[String]) -> String {
+    let systemd_run: Vec<String> = ["systemd-run", "--scope", "--quiet"]
+        .iter()
+        .map(|x| x.to_string())
+        .chain(args.iter().map(|x| shell_quote(x)))
+        .collect();
+
+    let script = format!(
+        "if [ -d /run/systemd/system ] && command -v systemd-run >/dev/null 2>&1; then \
+             exec {systemd_run} -- {command}; \
+         else \
+             echo 'systemd-run is not available on this node, activating without it' >&2; \
+             exec {command}; \
+         fi",
+        systemd_run = systemd_run.join(" "),
+        command = command,
+    );
+
+    format!("sh -c {}", shell_quote(&script))
+}
+
+#[test]
+fn test_systemd_run_command_builder() {
+    let wrapped = build_systemd_run_command(
+        "activate 'x'",
+        &["-p".to_string(), "MemoryMax=1G".to_string()],
+    );
+
+    assert!(wrapped.starts_with("sh -c 'if [ -d /run/systemd/system ] && command -v systemd-run "));
+    assert!(wrapped.contains(
+        "exec systemd-run --scope --quiet '\\''-p'\\'' '\\''MemoryMax=1G'\\'' -- activate '\\''x'\\''; else"
+    ));
+    assert!(wrapped.ends_with("exec activate '\\''x'\\''; fi'"));
 }
 
 struct WaitCommandData<'a> {
@@ -153,6 +394,8 @@ struct RevokeCommandData<'a> {
     profile_path: &'a str,
     debug_logs: bool,
     log_dir: Option<&'a str>,
+    activation_mode: ActivationMode,
+    script: Option<&'a str>,
 }
 
 fn build_revoke_command(data: &RevokeCommandData) -> String {
@@ -168,6 +411,19 @@ fn build_revoke_command(data: &RevokeCommandData) -> String {
 
     self_activate_command = format!("{} revoke '{}'", self_activate_command, data.profile_path);
 
+    if let Some(script) = data.script {
+        self_activate_command =
+            format!("{} --script {}", self_activate_command, shell_quote(script));
+    }
+
+    if data.activation_mode != ActivationMode::Switch {
+        self_activate_command = format!(
+            "{} --mode {}",
+            self_activate_command,
+            data.activation_mode.as_str()
+        );
+    }
+
     if let Some(sudo_cmd) = &data.sudo {
         self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
     }
@@ -189,7 +445,9 @@ fn test_revoke_command_builder() {
             closure,
             profile_path,
             debug_logs,
-            log_dir
+            log_dir,
+            activation_mode: ActivationMode::Switch,
+            script: None,
         }),
         "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt revoke '/nix/var/nix/per-user/user/profile'"
             .to_string(),
@@ -212,14 +470,18 @@ pub async fn confirm_profile(
     temp_path: Cow<'_, str>,
     ssh_addr: &str,
 ) -> Result<(), ConfirmProfileError> {
-    let mut ssh_confirm_command = Command::new("ssh");
-    ssh_confirm_command.arg(ssh_addr);
+    // Over a new connection, confirming fails if the activation broke connecting to the node, even if the
+    // commands before could still use an existing (multiplexed) connection that was not affected
+    let ssh_opts: &[&str] = match deploy_data.cmd_overrides.confirm_via_new_ssh {
+        true => &["-o", "ControlMaster=no", "-o", "ControlPath=none"],
+        false => &[],
+    };
 
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_confirm_command.arg(ssh_opt);
-    }
+    let mut ssh_confirm_command =
+        Command::from(node_command_with_opts(deploy_data, ssh_addr, ssh_opts));
+    ssh_confirm_command.kill_on_drop(true);
 
-    let lock_path = super::make_lock_path(&temp_path, &deploy_data.profile.profile_settings.path);
+    let lock_path = super::make_lock_path(&temp_path, deploy_data.store_path());
 
     let mut confirm_command = format!("rm {}", lock_path);
     if let Some(sudo_cmd) = &deploy_defs.sudo {
@@ -233,6 +495,7 @@ pub async fn confirm_profile(
 
     let ssh_confirm_exit_status = ssh_confirm_command
         .arg(confirm_command)
+        .logged()
         .status()
         .await
         .map_err(ConfirmProfileError::SSHConfirm)?;
@@ -247,6 +510,231 @@ pub async fn confirm_profile(
     Ok(())
 }
 
+/// Runs the confirmation command of the profile (if any) on the node, to check that the activated profile works
+async fn run_confirm_command(
+    deploy_data: &super::DeployData<'_>,
+    ssh_addr: &str,
+) -> Result<(), DeployProfileError> {
+    let confirm_command = match deploy_data.profile.profile_settings.confirm_command {
+        Some(ref x) => x,
+        None => return Ok(()),
+    };
+
+    info!(
+        "Running confirmation command for profile `{}` of node `{}`",
+        deploy_data.profile_name, deploy_data.node_name
+    );
+
+    debug!("Confirmation command: {}", confirm_command);
+
+    let started = Instant::now();
+
+    let retries = deploy_data.cmd_overrides.confirm_retries;
+    let mut attempt: u32 = 0;
+
+    loop {
+        let mut ssh_command = Command::from(node_command(deploy_data, ssh_addr));
+        ssh_command.kill_on_drop(true);
+
+        let ssh_exit_status = ssh_command
+            .arg(confirm_command)
+            .logged()
+            .status()
+            .await
+            .map_err(DeployProfileError::SSHConfirmCommand)?;
+
+        match ssh_exit_status.code() {
+            Some(0) => {
+                info!(
+                    "Confirmation command for profile `{}` of node `{}` succeeded in {:.1}s",
+                    deploy_data.profile_name,
+                    deploy_data.node_name,
+                    started.elapsed().as_secs_f64()
+                );
+
+                return Ok(());
+            }
+            a if attempt < retries => {
+                attempt += 1;
+
+                let delay = deploy_data.cmd_overrides.confirm_retry_delay;
+
+                warn!(
+                    "Confirmation command for profile `{}` of node `{}` failed with exit code {:?}, retrying in {}s (attempt {} of {})",
+                    deploy_data.profile_name,
+                    deploy_data.node_name,
+                    a,
+                    delay,
+                    attempt,
+                    retries
+                );
+
+                tokio::time::sleep(Duration::from_secs(delay.into())).await;
+            }
+            a => return Err(DeployProfileError::ConfirmCommandExit(a)),
+        }
+    }
+}
+
+/// Runs a hook of the profile on the node, before or after activating it
+async fn run_hook(
+    deploy_data: &super::DeployData<'_>,
+    ssh_addr: &str,
+    hook: &str,
+    command: &str,
+) -> Result<Option<i32>, std::io::Error> {
+    info!(
+        "Running {} hook for profile `{}` of node `{}`",
+        hook, deploy_data.profile_name, deploy_data.node_name
+    );
+
+    debug!("{} hook: {}", hook, command);
+
+    let mut ssh_command = Command::from(node_command(deploy_data, ssh_addr));
+    ssh_command.kill_on_drop(true);
+
+    let ssh_exit_status = ssh_command.arg(command).logged().status().await?;
+
+    Ok(ssh_exit_status.code())
+}
+
+/// Runs a command on the node over SSH, returning its output if it succeeded
+async fn ssh_output(
+    deploy_data: &super::DeployData<'_>,
+    ssh_addr: &str,
+    command: &str,
+) -> Result<String, String> {
+    let mut ssh_command = Command::from(node_command(deploy_data, ssh_addr));
+    ssh_command.kill_on_drop(true);
+
+    let output = ssh_command
+        .arg(command)
+        .stdin(Stdio::null())
+        .logged()
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    match output.status.code() {
+        Some(0) => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+        a => Err(format!(
+            "bad exit code {:?}: {}",
+            a,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// Returns the store path the profile currently points to on the node, if there is one, which is taken
+/// to be none if that can not be told (like when the node can not be reached). The node is only asked
+/// if that is not already known from `DeployData::current_profile_path`.
+pub async fn current_profile_path(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Option<String> {
+    if let Some(ref known) = deploy_data.current_profile_path {
+        return known.clone();
+    }
+
+    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, deploy_defs.hostname);
+
+    let profile_path = shell_quote(&deploy_defs.profile_path);
+
+    match ssh_output(
+        deploy_data,
+        &ssh_addr,
+        &format!("test -e {0} && readlink -f {0}", profile_path),
+    )
+    .await
+    {
+        Ok(path) if !path.trim().is_empty() => Some(path.trim().to_string()),
+        // The profile does not exist yet on the first deployment
+        Ok(_) | Err(_) => None,
+    }
+}
+
+/// Logs what changed between the previous and the new closure of the profile, as shown by `nix store diff-closures`.
+/// This is only informational, so not being able to tell is no error.
+async fn log_closure_diff(
+    deploy_data: &super::DeployData<'_>,
+    ssh_addr: &str,
+    previous_path: &str,
+) {
+    if previous_path == deploy_data.store_path() {
+        info!(
+            "Profile `{}` for node `{}` did not change",
+            deploy_data.profile_name, deploy_data.node_name
+        );
+        return;
+    }
+
+    let diff_command = format!(
+        "nix --extra-experimental-features nix-command store diff-closures {} {}",
+        shell_quote(previous_path),
+        shell_quote(deploy_data.store_path())
+    );
+
+    match ssh_output(deploy_data, ssh_addr, &diff_command).await {
+        Ok(diff) if diff.trim().is_empty() => info!(
+            "No package versions changed in profile `{}` for node `{}`",
+            deploy_data.profile_name, deploy_data.node_name
+        ),
+        Ok(diff) => {
+            info!(
+                "Changes in profile `{}` for node `{}`:",
+                deploy_data.profile_name, deploy_data.node_name
+            );
+            for line in diff.lines() {
+                info!("  {}", line);
+            }
+        }
+        Err(e) => warn!(
+            "Failed to get the changes in profile `{}` for node `{}`: {}",
+            deploy_data.profile_name, deploy_data.node_name, e
+        ),
+    }
+}
+
+/// Deletes all but the `keep` most recent generations of the profile on the node.
+/// `nix-env` never deletes the current generation, even if it is not among the most recent ones.
+async fn prune_generations(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    ssh_addr: &str,
+    keep: u32,
+) -> Result<(), DeployProfileError> {
+    info!(
+        "Deleting all but the {} most recent generations of profile `{}` for node `{}`",
+        keep, deploy_data.profile_name, deploy_data.node_name
+    );
+
+    let mut prune_command = format!(
+        "nix-env -p {} --delete-generations +{}",
+        shell_quote(&deploy_defs.profile_path),
+        keep.max(1)
+    );
+    if let Some(sudo_cmd) = &deploy_defs.sudo {
+        prune_command = format!("{} {}", sudo_cmd, prune_command);
+    }
+
+    debug!("Constructed prune command: {}", prune_command);
+
+    let mut ssh_command = Command::from(node_command(deploy_data, ssh_addr));
+    ssh_command.kill_on_drop(true);
+
+    let ssh_exit_status = ssh_command
+        .arg(prune_command)
+        .logged()
+        .status()
+        .await
+        .map_err(DeployProfileError::SSHPruneGenerations)?;
+
+    match ssh_exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(DeployProfileError::PruneGenerationsExit(a)),
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum DeployProfileError {
     #[error("Failed to spawn activation command over SSH: {0}")]
@@ -264,6 +752,223 @@ pub enum DeployProfileError {
 
     #[error("Error confirming deployment: {0}")]
     Confirm(#[from] ConfirmProfileError),
+
+    #[error("Failed to run confirmation command over SSH: {0}")]
+    SSHConfirmCommand(std::io::Error),
+    #[error("Confirmation command resulted in a bad exit code: {0:?}")]
+    ConfirmCommandExit(Option<i32>),
+
+    #[error("Failed to run pre-activation hook over SSH: {0}")]
+    SSHPreActivate(std::io::Error),
+    #[error("Pre-activation hook resulted in a bad exit code: {0:?}")]
+    PreActivateExit(Option<i32>),
+    #[error("Failed to run post-activation hook over SSH: {0}")]
+    SSHPostActivate(std::io::Error),
+    #[error("Post-activation hook resulted in a bad exit code: {0:?}")]
+    PostActivateExit(Option<i32>),
+
+    #[error("Activation did not finish within {0} seconds")]
+    ActivateTimeout(u16),
+    #[error("Failed to revoke profile after activation failed: {0}")]
+    Revoke(#[from] RevokeProfileError),
+    #[error("{0}, and revoking the profile failed as well: {1}")]
+    RevokeAfter(Box<DeployProfileError>, RevokeProfileError),
+    #[error("Activating failed and rolling back on the node failed as well, see the output of the node above")]
+    NodeRollback,
+
+    #[error("Node is locked by another deployment")]
+    NodeLocked,
+
+    #[error("Failed to run reboot command over SSH: {0}")]
+    SSHReboot(std::io::Error),
+    #[error("Node did not return within {0} seconds after rebooting")]
+    RebootTimeout(u16),
+    #[error("Refusing to reboot the local machine that is deploying")]
+    RebootLocal,
+
+    #[error("Failed to run generation pruning command over SSH: {0}")]
+    SSHPruneGenerations(std::io::Error),
+    #[error("Pruning generations over SSH resulted in a bad exit code: {0:?}")]
+    PruneGenerationsExit(Option<i32>),
+}
+
+impl DeployProfileError {
+    /// Whether rolling back failed after activating failed, leaving the node in an unknown state
+    pub fn rollback_failed(&self) -> bool {
+        matches!(
+            self,
+            DeployProfileError::Revoke(_)
+                | DeployProfileError::RevokeAfter(_, _)
+                | DeployProfileError::NodeRollback
+        )
+    }
+}
+
+/// Revokes the profile after activating it failed with `error`, keeping both errors if revoking fails as well
+async fn revoke_after(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    error: DeployProfileError,
+) -> DeployProfileError {
+    if !prompt_rollback(deploy_data).await {
+        return error;
+    }
+
+    match revoke(deploy_data, deploy_defs).await {
+        Ok(()) => error,
+        Err(e) => DeployProfileError::RevokeAfter(Box::new(error), e),
+    }
+}
+
+/// Asks whether to roll back the profile when the deployment is interactive, returning whether to go ahead.
+/// The rollback is performed if nobody answers in time, so that unattended runs still roll back.
+pub async fn prompt_rollback(deploy_data: &super::DeployData<'_>) -> bool {
+    let timeout = match deploy_data.cmd_overrides.rollback_prompt_timeout {
+        Some(timeout) if !deploy_data.cmd_overrides.dry_run => timeout,
+        _ => return true,
+    };
+
+    info!(
+        "About to roll back profile `{}` of node `{}`. Roll back, or leave the new generation active? (rolling back in {}s without an answer) [Y/n]",
+        deploy_data.profile_name, deploy_data.node_name, timeout
+    );
+    // Like the logs, the prompt goes to stderr, so that it does not end up in the output of `--output json`
+    eprint!("> ");
+    let _ = std::io::Write::flush(&mut std::io::stderr());
+
+    match tokio::time::timeout(Duration::from_secs(timeout as u64), crate::read_answer()).await {
+        Ok(Ok(s)) if yn::no(&s) => {
+            warn!(
+                "Not rolling back, leaving the new generation of profile `{}` active on node `{}`",
+                deploy_data.profile_name, deploy_data.node_name
+            );
+            false
+        }
+        Ok(_) => true,
+        Err(_) => {
+            info!("No answer after {}s, rolling back", timeout);
+            true
+        }
+    }
+}
+
+/// Reboots the node, then waits up to `timeout` seconds for it to be reachable over SSH again,
+/// and runs the confirmation command (if any) once more to check that the profile survived the reboot
+async fn reboot_node(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    ssh_addr: &str,
+    timeout: u16,
+) -> Result<(), DeployProfileError> {
+    if deploy_data.is_local() {
+        return Err(DeployProfileError::RebootLocal);
+    }
+
+    info!("Rebooting node `{}`", deploy_data.node_name);
+
+    let mut reboot_command = "reboot".to_string();
+    if let Some(sudo_cmd) = &deploy_defs.sudo {
+        reboot_command = format!("{} {}", sudo_cmd, reboot_command);
+    }
+
+    let mut ssh_reboot_command = Command::from(node_command(deploy_data, ssh_addr));
+    ssh_reboot_command.kill_on_drop(true);
+
+    // The connection usually drops while the node is going down, so the exit code means nothing
+    ssh_reboot_command
+        .arg(reboot_command)
+        .logged()
+        .status()
+        .await
+        .map_err(DeployProfileError::SSHReboot)?;
+
+    let started = std::time::Instant::now();
+
+    // Give the node time to actually go down, so that it is not mistaken for being back already
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    // Attempts should not hang while the node is down, unless a connect timeout was configured for it
+    let ssh_opts: &[&str] = match deploy_data
+        .merged_settings
+        .ssh_opts
+        .iter()
+        .any(|x| x.contains("ConnectTimeout"))
+    {
+        true => &[],
+        false => &["-o", "ConnectTimeout=5"],
+    };
+
+    loop {
+        let mut ssh_check_command =
+            Command::from(node_command_with_opts(deploy_data, ssh_addr, ssh_opts));
+        ssh_check_command.kill_on_drop(true);
+
+        ssh_check_command.arg("true").stdout(Stdio::null());
+
+        // The errors are expected while the node is rebooting, unless asked for
+        if deploy_data.cmd_overrides.verbose_ssh == 0 {
+            ssh_check_command.stderr(Stdio::null());
+        }
+
+        let reachable = ssh_check_command
+            .logged()
+            .status()
+            .await
+            .map(|status| status.success())
+            .unwrap_or(false);
+
+        if reachable {
+            break;
+        }
+
+        if started.elapsed() >= Duration::from_secs(timeout as u64) {
+            return Err(DeployProfileError::RebootTimeout(timeout));
+        }
+
+        debug!(
+            "Node `{}` is not reachable yet, trying again",
+            deploy_data.node_name
+        );
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+
+    info!("Node `{}` is back after rebooting", deploy_data.node_name);
+
+    run_confirm_command(deploy_data, ssh_addr).await
+}
+
+/// Runs the failure command (if any) locally, with the node, profile and error in its environment.
+/// Its own failure is only logged, so that it does not hide the error of the deployment.
+async fn run_failure_command(deploy_data: &super::DeployData<'_>, error: &DeployProfileError) {
+    let failure_command = match deploy_data.cmd_overrides.on_failure {
+        Some(ref x) => x,
+        None => return,
+    };
+
+    info!(
+        "Running failure command for profile `{}` of node `{}`",
+        deploy_data.profile_name, deploy_data.node_name
+    );
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(failure_command)
+        .env("DEPLOY_RS_NODE", deploy_data.node_name)
+        .env("DEPLOY_RS_PROFILE", deploy_data.profile_name)
+        .env("DEPLOY_RS_ERROR", error.to_string())
+        .logged()
+        .status()
+        .await;
+
+    match status {
+        Ok(status) if status.success() => (),
+        Ok(status) => warn!(
+            "Failure command resulted in a bad exit code: {:?}",
+            status.code()
+        ),
+        Err(e) => warn!("Failed to run failure command: {}", e),
+    }
 }
 
 pub async fn deploy_profile(
@@ -271,6 +976,33 @@ pub async fn deploy_profile(
     deploy_defs: &super::DeployDefs,
     dry_activate: bool,
 ) -> Result<(), DeployProfileError> {
+    let result = activate_profile(deploy_data, deploy_defs, dry_activate).await;
+
+    if let Err(ref e) = result {
+        if e.rollback_failed() {
+            error!(
+                "!!! Rolling back profile `{}` of node `{}` failed after activating it failed !!!",
+                deploy_data.profile_name, deploy_data.node_name
+            );
+            error!("!!! The node is in an unknown state and must be checked and fixed by hand !!!");
+        }
+        if !dry_activate {
+            run_failure_command(deploy_data, e).await;
+        }
+    }
+
+    result
+}
+
+async fn activate_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    dry_activate: bool,
+) -> Result<(), DeployProfileError> {
+    let activation_mode = deploy_data.activation_mode();
+
+    let dry_activate = dry_activate || activation_mode == ActivationMode::DryActivate;
+
     if !dry_activate {
         info!(
             "Activating profile `{}` for node `{}`",
@@ -285,14 +1017,24 @@ pub async fn deploy_profile(
 
     let confirm_timeout = deploy_data.merged_settings.confirm_timeout.unwrap_or(30);
 
-    let magic_rollback = deploy_data.merged_settings.magic_rollback.unwrap_or(true);
+    // When booting into the profile later, nothing changes now that could be confirmed
+    let magic_rollback = deploy_data.merged_settings.magic_rollback.unwrap_or(true)
+        && activation_mode != ActivationMode::Boot;
 
     let auto_rollback = deploy_data.merged_settings.auto_rollback.unwrap_or(true);
 
-    let self_activate_command = build_activate_command(&ActivateCommandData {
+    let mut activation_env: Vec<(&str, &str)> = deploy_data
+        .merged_settings
+        .activation_env
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    activation_env.sort_unstable();
+
+    let mut self_activate_command = build_activate_command(&ActivateCommandData {
         sudo: &deploy_defs.sudo,
         profile_path: &deploy_defs.profile_path,
-        closure: &deploy_data.profile.profile_settings.path,
+        closure: deploy_data.store_path(),
         auto_rollback,
         temp_path: &temp_path,
         confirm_timeout,
@@ -300,45 +1042,260 @@ pub async fn deploy_profile(
         debug_logs: deploy_data.debug_logs,
         log_dir: deploy_data.log_dir,
         dry_activate,
+        activation_mode,
+        env: &activation_env,
+        script: deploy_data.activate_script(),
+        // Without magic rollback, a configured confirmation timeout bounds the activation. It is stopped on the
+        // node, as killing SSH would leave it running there, racing with revoking the profile.
+        // macOS has no `timeout`, so the activation can only be given up on there
+        timeout: match deploy_data.merged_settings.confirm_timeout {
+            Some(timeout)
+                if !magic_rollback
+                    && !dry_activate
+                    && deploy_data.node_system() != NodeSystem::Darwin =>
+            {
+                Some(timeout)
+            }
+            _ => None,
+        },
+        // macOS has no systemd, so there is no scope to run in
+        systemd_run: match deploy_data.merged_settings.systemd_run {
+            Some(true) if deploy_data.node_system() != NodeSystem::Darwin => Some(
+                deploy_data
+                    .merged_settings
+                    .systemd_run_args
+                    .as_deref()
+                    .unwrap_or_default(),
+            ),
+            _ => None,
+        },
     });
 
-    debug!("Constructed activation command: {}", self_activate_command);
+    // Dry activation does not switch anything, so it can not race with another deployment
+    if deploy_data.cmd_overrides.remote_lock && !dry_activate {
+        self_activate_command = build_locked_command(
+            &self_activate_command,
+            &format!("{}/deploy-rs.lock", temp_path.trim_end_matches('/')),
+            deploy_data.cmd_overrides.lock_timeout,
+        );
+    }
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
-    };
+    if dry_activate {
+        info!(
+            "Dry-activating profile `{}` for node `{}` with: {}",
+            deploy_data.profile_name, deploy_data.node_name, self_activate_command
+        );
+    } else {
+        debug!("Constructed activation command: {}", self_activate_command);
+    }
+
+    let hostname = &deploy_defs.hostname;
 
     let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
 
-    let mut ssh_activate_command = Command::new("ssh");
-    ssh_activate_command.arg(&ssh_addr);
+    let mut ssh_activate_command = node_command(deploy_data, &ssh_addr);
 
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_activate_command.arg(&ssh_opt);
+    let profile_settings = &deploy_data.profile.profile_settings;
+
+    // Hooks are meant to prepare for and follow up on changes, of which dry activation makes none
+    let (pre_activate, post_activate) = match dry_activate {
+        true => (None, None),
+        false => (
+            profile_settings.pre_activate.as_deref(),
+            profile_settings.post_activate.as_deref(),
+        ),
+    };
+
+    // Only switching makes the profile and the running system agree, so a profile deployed with
+    // `boot` or `test` before may well point at the store path without it running
+    let skip_current =
+        !deploy_data.cmd_overrides.force && activation_mode == ActivationMode::Switch;
+
+    if deploy_data.cmd_overrides.dry_run {
+        ssh_activate_command.arg(self_activate_command);
+
+        if skip_current && !dry_activate {
+            info!("Dry run, would not activate if the profile on the node already points at the store path");
+        }
+
+        if let Some(pre_activate) = pre_activate {
+            info!("Dry run, would run pre-activation hook: {}", pre_activate);
+        }
+
+        info!("Dry run, would activate with: {:?}", ssh_activate_command);
+
+        if magic_rollback && !dry_activate {
+            info!(
+                "Dry run, would then wait for activation over SSH and confirm it by removing {}",
+                super::make_lock_path(&temp_path, deploy_data.store_path())
+            );
+
+            if auto_rollback
+                && deploy_data.cmd_overrides.rollback_prompt_timeout.is_some()
+                && deploy_data
+                    .profile
+                    .profile_settings
+                    .confirm_command
+                    .is_some()
+            {
+                info!("Dry run, would ask whether to let the node roll back if the confirmation command fails, confirming anyway if not");
+            }
+        }
+
+        if deploy_data.cmd_overrides.diff_closures && !dry_activate {
+            info!("Dry run, would then show the changes with `nix store diff-closures` over SSH");
+        }
+
+        if let Some(post_activate) = post_activate {
+            info!(
+                "Dry run, would then run post-activation hook: {}",
+                post_activate
+            );
+        }
+
+        if deploy_data.cmd_overrides.reboot && !dry_activate {
+            info!(
+                "Dry run, would then reboot node `{}` and wait for it to come back",
+                deploy_data.node_name
+            );
+        }
+
+        return Ok(());
     }
 
-    if !magic_rollback || dry_activate {
-        let ssh_activate_exit_status = ssh_activate_command
-            .arg(self_activate_command)
-            .status()
-            .await
-            .map_err(DeployProfileError::SSHActivate)?;
+    let previous_path =
+        match (deploy_data.cmd_overrides.diff_closures || skip_current) && !dry_activate {
+            true => current_profile_path(deploy_data, deploy_defs).await,
+            false => None,
+        };
+
+    if skip_current && !dry_activate && previous_path.as_deref() == Some(deploy_data.store_path()) {
+        info!(
+            "Profile `{}` for node `{}` is already up to date, not activating it (pass --force to activate it anyway)",
+            deploy_data.profile_name, deploy_data.node_name
+        );
 
-        match ssh_activate_exit_status.code() {
+        return Ok(());
+    }
+
+    // Nothing has changed yet if this fails, so there is nothing to roll back
+    if let Some(pre_activate) = pre_activate {
+        match run_hook(deploy_data, &ssh_addr, "pre-activation", pre_activate)
+            .await
+            .map_err(DeployProfileError::SSHPreActivate)?
+        {
             Some(0) => (),
-            a => return Err(DeployProfileError::SSHActivateExit(a)),
+            a => return Err(DeployProfileError::PreActivateExit(a)),
+        }
+    }
+
+    let started = Instant::now();
+
+    let mut ssh_activate_command = Command::from(ssh_activate_command);
+    ssh_activate_command.kill_on_drop(true);
+
+    if !magic_rollback || dry_activate {
+        ssh_activate_command.arg(self_activate_command);
+
+        // Without magic rollback nothing else bounds the activation, so an explicitly configured
+        // confirmation timeout is applied here. The node stops the activation itself (see `timeout` above),
+        // while this only gives up on SSH. As activate-rs ignores SIGHUP, killing SSH does not stop the
+        // activation, so the profile can not be revoked then without racing with it.
+        let ssh_activate_exit_status = match deploy_data.merged_settings.confirm_timeout {
+            Some(timeout) if !dry_activate => {
+                let grace = match deploy_data.node_system() {
+                    NodeSystem::Darwin => 0,
+                    _ => ACTIVATE_TIMEOUT_GRACE,
+                };
+
+                let status = tokio::time::timeout(
+                    Duration::from_secs(timeout as u64 + grace),
+                    ssh_activate_command.logged().status(),
+                )
+                .await;
+
+                match status {
+                    Ok(status) => status.map_err(DeployProfileError::SSHActivate)?,
+                    Err(_) => {
+                        warn!(
+                            "Activation of profile `{}` for node `{}` did not finish within {} seconds and could not be stopped on the node, \
+                             not revoking it as the node may still be switching to it",
+                            deploy_data.profile_name, deploy_data.node_name, timeout
+                        );
+
+                        return Err(DeployProfileError::ActivateTimeout(timeout));
+                    }
+                }
+            }
+            _ => ssh_activate_command
+                .logged()
+                .status()
+                .await
+                .map_err(DeployProfileError::SSHActivate)?,
+        };
+
+        match (
+            ssh_activate_exit_status.code(),
+            deploy_data.merged_settings.confirm_timeout,
+        ) {
+            (Some(0), _) => (),
+            (Some(NODE_LOCKED_EXIT_CODE), _) if deploy_data.cmd_overrides.remote_lock => {
+                return Err(DeployProfileError::NodeLocked)
+            }
+            (Some(ROLLBACK_FAILED_EXIT_CODE), _) if auto_rollback => {
+                return Err(DeployProfileError::NodeRollback)
+            }
+            (Some(code), Some(timeout))
+                if !dry_activate
+                    && deploy_data.node_system() != NodeSystem::Darwin
+                    && ACTIVATE_TIMEOUT_EXIT_CODES.contains(&code) =>
+            {
+                if auto_rollback {
+                    warn!(
+                        "Activation timed out after {} seconds and was stopped on the node, revoking profile `{}` for node `{}`",
+                        timeout, deploy_data.profile_name, deploy_data.node_name
+                    );
+                    return Err(revoke_after(
+                        deploy_data,
+                        deploy_defs,
+                        DeployProfileError::ActivateTimeout(timeout),
+                    )
+                    .await);
+                }
+
+                return Err(DeployProfileError::ActivateTimeout(timeout));
+            }
+            (a, _) => return Err(DeployProfileError::SSHActivateExit(a)),
         };
 
         if dry_activate {
             info!("Completed dry-activate!");
         } else {
+            info!(
+                "Activated profile `{}` for node `{}` in {:.1}s",
+                deploy_data.profile_name,
+                deploy_data.node_name,
+                started.elapsed().as_secs_f64()
+            );
+
+            if let Err(e) = run_confirm_command(deploy_data, &ssh_addr).await {
+                if auto_rollback {
+                    warn!(
+                        "Confirmation command failed, revoking profile `{}` for node `{}`",
+                        deploy_data.profile_name, deploy_data.node_name
+                    );
+                    return Err(revoke_after(deploy_data, deploy_defs, e).await);
+                }
+
+                return Err(e);
+            }
+
             info!("Success activating, done!");
         }
     } else {
         let self_wait_command = build_wait_command(&WaitCommandData {
             sudo: &deploy_defs.sudo,
-            closure: &deploy_data.profile.profile_settings.path,
+            closure: deploy_data.store_path(),
             temp_path: &temp_path,
             debug_logs: deploy_data.debug_logs,
             log_dir: deploy_data.log_dir,
@@ -348,20 +1305,18 @@ pub async fn deploy_profile(
 
         let ssh_activate = ssh_activate_command
             .arg(self_activate_command)
+            .logged()
             .spawn()
             .map_err(DeployProfileError::SSHSpawnActivate)?;
 
         info!("Creating activation waiter");
 
-        let mut ssh_wait_command = Command::new("ssh");
-        ssh_wait_command.arg(&ssh_addr);
-
-        for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-            ssh_wait_command.arg(ssh_opt);
-        }
+        let mut ssh_wait_command = Command::from(node_command(deploy_data, &ssh_addr));
+        ssh_wait_command.kill_on_drop(true);
 
         let (send_activate, recv_activate) = tokio::sync::oneshot::channel();
-        let (send_activated, recv_activated) = tokio::sync::oneshot::channel();
+
+        let remote_lock = deploy_data.cmd_overrides.remote_lock;
 
         let thread = tokio::spawn(async move {
             let o = ssh_activate.wait_with_output().await;
@@ -370,39 +1325,138 @@ pub async fn deploy_profile(
                 Err(x) => Some(DeployProfileError::SSHActivate(x)),
                 Ok(ref x) => match x.status.code() {
                     Some(0) => None,
+                    Some(NODE_LOCKED_EXIT_CODE) if remote_lock => {
+                        Some(DeployProfileError::NodeLocked)
+                    }
+                    Some(ROLLBACK_FAILED_EXIT_CODE) => Some(DeployProfileError::NodeRollback),
                     a => Some(DeployProfileError::SSHActivateExit(a)),
                 },
             };
 
-            if let Some(err) = maybe_err {
-                send_activate.send(err).unwrap();
+            match maybe_err {
+                // If waiting has already finished nobody is listening anymore,
+                // so the error is handed back when the task is joined instead
+                Some(err) => send_activate.send(err),
+                None => Ok(()),
             }
-
-            send_activated.send(()).unwrap();
         });
         tokio::select! {
-            x = ssh_wait_command.arg(self_wait_command).status() => {
+            x = ssh_wait_command.arg(self_wait_command).logged().status() => {
                 debug!("Wait command ended");
                 match x.map_err(DeployProfileError::SSHWait)?.code() {
                     Some(0) => (),
                     a => return Err(DeployProfileError::SSHWaitExit(a)),
                 };
             },
-            x = recv_activate => {
+            // The sender is dropped without sending if activation succeeded,
+            // in which case this branch is disabled and we keep waiting
+            Ok(x) = recv_activate => {
                 debug!("Activate command exited with an error");
-                return Err(x.unwrap());
+                return Err(x);
             },
         }
 
+        info!(
+            "Activated profile `{}` for node `{}` in {:.1}s",
+            deploy_data.profile_name,
+            deploy_data.node_name,
+            started.elapsed().as_secs_f64()
+        );
+
+        if let Err(e) = run_confirm_command(deploy_data, &ssh_addr).await {
+            if !auto_rollback {
+                warn!(
+                    "Confirmation command failed, but auto-rollback is disabled: {}",
+                    e
+                );
+            } else if prompt_rollback(deploy_data).await {
+                // Not confirming makes the node roll back by itself once the confirmation times out
+                warn!(
+                    "Confirmation command failed, not confirming profile `{}` for node `{}` so it is rolled back",
+                    deploy_data.profile_name, deploy_data.node_name
+                );
+
+                thread
+                    .await
+                    .map_err(|x| DeployProfileError::SSHActivate(x.into()))??;
+
+                return Err(e);
+            } else {
+                // Confirming fails if the answer came after the node already rolled back by itself
+                warn!(
+                    "Confirmation command failed, confirming profile `{}` for node `{}` anyway: {}",
+                    deploy_data.profile_name, deploy_data.node_name, e
+                );
+            }
+        }
+
         info!("Success activating, attempting to confirm activation");
 
+        let confirm_started = Instant::now();
+
         let c = confirm_profile(deploy_data, deploy_defs, temp_path, &ssh_addr).await;
-        recv_activated.await.unwrap();
-        c?;
 
-        thread
+        if c.is_ok() {
+            info!(
+                "Confirmed profile `{}` for node `{}` in {:.1}s",
+                deploy_data.profile_name,
+                deploy_data.node_name,
+                confirm_started.elapsed().as_secs_f64()
+            );
+        }
+        let activate_result = thread
             .await
             .map_err(|x| DeployProfileError::SSHActivate(x.into()))?;
+        c?;
+        activate_result?;
+    }
+
+    if let Some(ref previous_path) = previous_path {
+        log_closure_diff(deploy_data, &ssh_addr, previous_path).await;
+    }
+
+    if let Some(post_activate) = post_activate {
+        let error = match run_hook(deploy_data, &ssh_addr, "post-activation", post_activate).await {
+            Ok(Some(0)) => None,
+            Ok(a) => Some(DeployProfileError::PostActivateExit(a)),
+            Err(e) => Some(DeployProfileError::SSHPostActivate(e)),
+        };
+
+        if let Some(error) = error {
+            // The profile itself was activated and confirmed, so it is only revoked if asked for
+            if profile_settings.post_activate_rollback == Some(true) {
+                warn!(
+                    "Post-activation hook failed, revoking profile `{}` for node `{}`",
+                    deploy_data.profile_name, deploy_data.node_name
+                );
+
+                return Err(revoke_after(deploy_data, deploy_defs, error).await);
+            }
+
+            return Err(error);
+        }
+    }
+
+    // The activation is already confirmed at this point, so the node does not roll back by itself if it
+    // does not come back, which can only be reported
+    if deploy_data.cmd_overrides.reboot && !dry_activate {
+        reboot_node(
+            deploy_data,
+            deploy_defs,
+            &ssh_addr,
+            deploy_data.cmd_overrides.reboot_timeout.unwrap_or(300),
+        )
+        .await?;
+    }
+
+    // Only pruned once activation is confirmed, so that a failed activation can still be rolled back
+    if let (Some(keep), false) = (deploy_data.cmd_overrides.keep_generations, dry_activate) {
+        if let Err(e) = prune_generations(deploy_data, deploy_defs, &ssh_addr, keep).await {
+            warn!(
+                "Failed to prune old generations of profile `{}` for node `{}`: {}",
+                deploy_data.profile_name, deploy_data.node_name, e
+            );
+        }
     }
 
     Ok(())
@@ -424,33 +1478,85 @@ pub enum RevokeProfileError {
 pub async fn revoke(
     deploy_data: &crate::DeployData<'_>,
     deploy_defs: &crate::DeployDefs,
+) -> Result<(), RevokeProfileError> {
+    revoke_with(deploy_data, deploy_defs, deploy_data.store_path()).await
+}
+
+/// Rolls the profile on the node back to the generation before the current one, the same way as when
+/// a later activation fails, and runs the confirmation command of the profile (if any) afterwards.
+/// The `activate-rs` of the currently deployed generation is used, so the profile does not need to be built.
+pub async fn rollback(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<(), DeployProfileError> {
+    let profile_path = &deploy_defs.profile_path;
+
+    info!(
+        "Rolling back profile `{}` of node `{}` to its previous generation",
+        deploy_data.profile_name, deploy_data.node_name
+    );
+
+    if deploy_data.cmd_overrides.dry_run {
+        info!(
+            "Dry run, would roll back with: {}",
+            build_revoke_command(&RevokeCommandData {
+                sudo: &deploy_defs.sudo,
+                closure: profile_path,
+                profile_path,
+                debug_logs: deploy_data.debug_logs,
+                log_dir: deploy_data.log_dir,
+                activation_mode: deploy_data.activation_mode(),
+                script: deploy_data.activate_script(),
+            })
+        );
+
+        return Ok(());
+    }
+
+    revoke_with(deploy_data, deploy_defs, profile_path).await?;
+
+    run_confirm_command(
+        deploy_data,
+        &format!("{}@{}", deploy_defs.ssh_user, deploy_defs.hostname),
+    )
+    .await?;
+
+    info!(
+        "Rolled back profile `{}` of node `{}`",
+        deploy_data.profile_name, deploy_data.node_name
+    );
+
+    Ok(())
+}
+
+/// Revokes the profile on the node using the `activate-rs` of `closure`
+async fn revoke_with(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+    closure: &str,
 ) -> Result<(), RevokeProfileError> {
     let self_revoke_command = build_revoke_command(&RevokeCommandData {
         sudo: &deploy_defs.sudo,
-        closure: &deploy_data.profile.profile_settings.path,
-        profile_path: &deploy_data.get_profile_path()?,
+        closure,
+        profile_path: &deploy_defs.profile_path,
         debug_logs: deploy_data.debug_logs,
         log_dir: deploy_data.log_dir,
+        activation_mode: deploy_data.activation_mode(),
+        script: deploy_data.activate_script(),
     });
 
     debug!("Constructed revoke command: {}", self_revoke_command);
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
-    };
+    let hostname = &deploy_defs.hostname;
 
     let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
 
-    let mut ssh_activate_command = Command::new("ssh");
-    ssh_activate_command.arg(&ssh_addr);
-
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_activate_command.arg(&ssh_opt);
-    }
+    let mut ssh_activate_command = Command::from(node_command(deploy_data, &ssh_addr));
+    ssh_activate_command.kill_on_drop(true);
 
     let ssh_revoke = ssh_activate_command
         .arg(self_revoke_command)
+        .logged()
         .spawn()
         .map_err(RevokeProfileError::SSHSpawnRevoke)?;
 
@@ -464,3 +1570,97 @@ pub async fn revoke(
         },
     }
 }
+
+#[derive(Error, Debug)]
+pub enum CheckReachableError {
+    #[error("Failed to run SSH: {0}")]
+    SSH(std::io::Error),
+    #[error("SSH resulted in a bad exit code: {0:?}")]
+    SSHExit(Option<i32>),
+}
+
+/// Checks that the node can be connected to over SSH, with the same user, options and hostname as when deploying
+pub async fn check_reachable(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<(), CheckReachableError> {
+    if deploy_data.is_local() {
+        return Ok(());
+    }
+
+    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, deploy_defs.hostname);
+
+    let mut ssh_command = std::process::Command::new(crate::ssh_bin());
+
+    // SSH uses the first value given for an option, so the ones of the node take precedence
+    ssh_command
+        .args(&deploy_data.merged_settings.ssh_opts)
+        .arg("-o")
+        .arg("ConnectTimeout=10")
+        .arg(&ssh_addr)
+        .arg("true");
+
+    if deploy_data.cmd_overrides.dry_run {
+        info!(
+            "Dry run, would check that the node is reachable with: {:?}",
+            ssh_command
+        );
+
+        return Ok(());
+    }
+
+    let mut ssh_command = Command::from(ssh_command);
+    ssh_command
+        .kill_on_drop(true)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+
+    let ssh_exit_status = ssh_command
+        .logged()
+        .status()
+        .await
+        .map_err(CheckReachableError::SSH)?;
+
+    match ssh_exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(CheckReachableError::SSHExit(a)),
+    }
+}
+
+/// Closes the SSH master connection to the node, if SSH multiplexing is enabled for it
+pub async fn close_ssh_master(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) {
+    if deploy_data.merged_settings.ssh_multiplexing != Some(true) || deploy_data.is_local() {
+        return;
+    }
+
+    let hostname = &deploy_defs.hostname;
+
+    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+
+    debug!("Closing SSH master connection to {}", ssh_addr);
+
+    let mut ssh_exit_command = Command::new(crate::ssh_bin());
+    ssh_exit_command.kill_on_drop(true);
+    ssh_exit_command.arg("-O").arg("exit").arg(&ssh_addr);
+
+    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
+        ssh_exit_command.arg(ssh_opt);
+    }
+
+    ssh_exit_command.stdout(Stdio::null());
+
+    // There might not be a master connection (anymore), which is fine
+    if deploy_data.cmd_overrides.verbose_ssh == 0 {
+        ssh_exit_command.stderr(Stdio::null());
+    }
+
+    if let Err(e) = ssh_exit_command.logged().status().await {
+        debug!(
+            "Failed to close SSH master connection to {}: {}",
+            ssh_addr, e
+        );
+    }
+}
@@ -4,6 +4,15 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
+//! The deployment logic of deploy-rs, for use from other programs. The `deploy` and `activate-rs`
+//! binaries are thin wrappers around it.
+//!
+//! - `cli::run` (or `cli::run_with_events`, to follow the progress as `DeployEvent`s) deploys the same
+//!   way as the `deploy` command, taking its arguments as `clap` matches.
+//! - `data` has the deployment data of a flake, which `make_deploy_data` resolves for a single profile,
+//!   with `CmdOverrides` taking the place of command line options.
+//! - `push` builds and copies profiles, `deploy` activates, confirms and revokes them.
+
 use rnix::{types::*, SyntaxKind::*};
 
 use merge::Merge;
@@ -12,9 +21,14 @@ use thiserror::Error;
 
 use flexi_logger::*;
 
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
 pub fn make_lock_path(temp_path: &str, closure: &str) -> String {
-    let lock_hash =
-        &closure["/nix/store/".len()..closure.find('-').unwrap_or_else(|| closure.len())];
+    let lock_hash = &closure["/nix/store/".len()..closure.find('-').unwrap_or(closure.len())];
     format!("{}/deploy-rs-canary-{}", temp_path, lock_hash)
 }
 
@@ -76,6 +90,56 @@ pub fn logger_formatter_revoke(
     )
 }
 
+tokio::task_local! {
+    /// The node, and the profile if there is one, that the logs of the current task are about
+    static LOG_TARGET: String;
+}
+
+/// Runs `f`, prefixing the logs it makes with `[<node>/<profile>]`, or `[<node>]` without a profile,
+/// so that the logs of nodes deployed at the same time can be told apart
+pub async fn with_log_target<F: Future>(node: &str, profile: Option<&str>, f: F) -> F::Output {
+    let target = match profile {
+        Some(profile) => format!("{}/{}", node, profile),
+        None => node.to_string(),
+    };
+
+    LOG_TARGET.scope(target, f).await
+}
+
+/// The lines of stdin, read by a thread of its own so that a prompt that stops waiting does not leave a read behind
+static STDIN_LINES: OnceLock<
+    tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<std::io::Result<String>>>,
+> = OnceLock::new();
+
+/// Reads the answer to a prompt from stdin, which is empty at the end of the input.
+/// This can be given up on (like with a timeout) without taking the answer to the next prompt.
+pub async fn read_answer() -> std::io::Result<String> {
+    use futures_util::FutureExt;
+
+    let lines = STDIN_LINES.get_or_init(|| {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        std::thread::spawn(move || loop {
+            let mut s = String::new();
+            let line = std::io::stdin().read_line(&mut s).map(|_| s);
+            let done = !matches!(line, Ok(ref s) if !s.is_empty());
+
+            if sender.send(line).is_err() || done {
+                break;
+            }
+        });
+
+        tokio::sync::Mutex::new(receiver)
+    });
+
+    let mut lines = lines.lock().await;
+
+    // Lines typed while nothing was asked, like a late answer to a prompt that timed out, do not answer this one
+    while let Some(Some(_)) = lines.recv().now_or_never() {}
+
+    lines.recv().await.unwrap_or_else(|| Ok(String::new()))
+}
+
 pub fn logger_formatter_deploy(
     w: &mut dyn std::io::Write,
     _now: &mut DeferredNow,
@@ -83,11 +147,16 @@ pub fn logger_formatter_deploy(
 ) -> Result<(), std::io::Error> {
     let level = record.level();
 
+    let target = LOG_TARGET
+        .try_with(|target| format!("[{}] ", target))
+        .unwrap_or_default();
+
     write!(
         w,
-        "🚀 {} [deploy] [{}] {}",
+        "🚀 {} [deploy] [{}] {}{}",
         make_emoji(level),
         style(level, level.to_string()),
+        target,
         record.args()
     )
 }
@@ -99,9 +168,76 @@ pub enum LoggerType {
     Revoke,
 }
 
+/// How `nix copy` compresses what it sends, as given by `--copy-compression`
+#[derive(Debug, Clone, Copy, PartialEq, clap::ArgEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CopyCompression {
+    /// No compression
+    None,
+    /// The compression of SSH itself (`ssh -C`), for copies over SSH
+    Ssh,
+    /// Compressing with zstd, for the binary cache of `--to-cache`
+    Zstd,
+    /// Compressing with xz, for the binary cache of `--to-cache`
+    Xz,
+}
+
+impl CopyCompression {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CopyCompression::None => "none",
+            CopyCompression::Ssh => "ssh",
+            CopyCompression::Zstd => "zstd",
+            CopyCompression::Xz => "xz",
+        }
+    }
+}
+
+/// The most verbose level of logs to print, as given by `--log-level`
+#[derive(Debug, Clone, Copy, PartialEq, clap::ArgEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+
+    fn duplicate(&self) -> Duplicate {
+        match self {
+            LogLevel::Error => Duplicate::Error,
+            LogLevel::Warn => Duplicate::Warn,
+            LogLevel::Info => Duplicate::Info,
+            LogLevel::Debug => Duplicate::Debug,
+            LogLevel::Trace => Duplicate::Trace,
+        }
+    }
+}
+
+/// Makes a logger for `log_level` if it is given, or else for `RUST_LOG` falling back to `default`
+fn make_logger(log_level: Option<LogLevel>, default: &str) -> Logger {
+    match log_level {
+        Some(log_level) => Logger::with_str(log_level.as_str()),
+        None => Logger::with_env_or_str(default),
+    }
+}
+
 pub fn init_logger(
     debug_logs: bool,
     log_dir: Option<&str>,
+    log_level: Option<LogLevel>,
     logger_type: &LoggerType,
 ) -> Result<(), FlexiLoggerError> {
     let logger_formatter = match &logger_type {
@@ -112,14 +248,15 @@ pub fn init_logger(
     };
 
     if let Some(log_dir) = log_dir {
-        let mut logger = Logger::with_env_or_str("debug")
+        let mut logger = make_logger(log_level, "debug")
             .log_to_file()
             .format_for_stderr(logger_formatter)
             .set_palette("196;208;51;7;8".to_string())
             .directory(log_dir)
-            .duplicate_to_stderr(match debug_logs {
-                true => Duplicate::Debug,
-                false => Duplicate::Info,
+            .duplicate_to_stderr(match (log_level, debug_logs) {
+                (Some(log_level), _) => log_level.duplicate(),
+                (None, true) => Duplicate::Debug,
+                (None, false) => Duplicate::Info,
             })
             .print_message();
 
@@ -132,25 +269,81 @@ pub fn init_logger(
 
         logger.start()?;
     } else {
-        Logger::with_env_or_str(match debug_logs {
-            true => "debug",
-            false => "info",
-        })
+        make_logger(
+            log_level,
+            match debug_logs {
+                true => "debug",
+                false => "info",
+            },
+        )
         .log_target(LogTarget::StdErr)
         .format(logger_formatter)
         .set_palette("196;208;51;7;8".to_string())
         .start()?;
     }
 
+    log_panics();
+
     Ok(())
 }
 
+/// Logs panics like any other error, along with where in the source they happened,
+/// so that they also end up in the log files instead of only on stderr
+fn log_panics() {
+    std::panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        let message = match (
+            payload.downcast_ref::<&str>(),
+            payload.downcast_ref::<String>(),
+        ) {
+            (Some(x), _) => x,
+            (_, Some(x)) => x.as_str(),
+            (None, None) => "unknown error",
+        };
+
+        let location = match info.location() {
+            Some(x) => format!("{}:{}:{}", x.file(), x.line(), x.column()),
+            None => "an unknown location".to_string(),
+        };
+
+        let thread = std::thread::current();
+
+        log::error!(
+            "Panicked at {} (in thread `{}`): {}",
+            location,
+            thread.name().unwrap_or("unnamed"),
+            message
+        );
+    }));
+}
+
+/// Returns the log level selected by repeating `-v`, with `-v` for debug logs and `-vv` for trace logs
+pub fn verbosity_log_level(verbose: u64) -> Option<LogLevel> {
+    match verbose {
+        0 => None,
+        1 => Some(LogLevel::Debug),
+        _ => Some(LogLevel::Trace),
+    }
+}
+
+/// Logs the whole command line of a command at trace level, right before it is run
+pub trait LogCommand {
+    fn logged(&mut self) -> &mut Self;
+}
+
+impl LogCommand for tokio::process::Command {
+    fn logged(&mut self) -> &mut Self {
+        log::trace!("Running {:?}", self);
+        self
+    }
+}
+
 pub mod data;
 pub mod deploy;
 pub mod push;
 pub mod cli;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CmdOverrides {
     pub ssh_user: Option<String>,
     pub profile_user: Option<String>,
@@ -160,9 +353,77 @@ pub struct CmdOverrides {
     pub hostname: Option<String>,
     pub magic_rollback: Option<bool>,
     pub temp_path: Option<String>,
+    pub profile_path_template: Option<String>,
     pub confirm_timeout: Option<u16>,
     pub sudo: Option<String>,
     pub dry_activate: bool,
+    pub dry_run: bool,
+    pub remote_build: Option<bool>,
+    pub local: Option<bool>,
+    pub ssh_multiplexing: Option<bool>,
+    pub jump_host: Option<String>,
+    pub ssh_connect_timeout: Option<u16>,
+    pub ssh_keepalive_interval: Option<u16>,
+    pub ssh_keepalive_count_max: Option<u16>,
+    pub ssh_forward_agent: Option<bool>,
+    pub ssh_gssapi: Option<bool>,
+    pub verbose_ssh: u8,
+    pub keep_generations: Option<u32>,
+    pub remote_lock: bool,
+    pub lock_timeout: Option<u16>,
+    pub on_failure: Option<String>,
+    pub reboot: bool,
+    pub reboot_timeout: Option<u16>,
+    pub confirm_retries: u32,
+    pub confirm_via_new_ssh: bool,
+    pub confirm_retry_delay: u16,
+    pub rollback_prompt_timeout: Option<u16>,
+    pub activation_mode: Option<data::ActivationMode>,
+    pub ssh_config_file: Option<String>,
+    pub activation_env: Vec<(String, String)>,
+    pub verify_copy: bool,
+    pub copy_bwlimit: Option<u32>,
+    pub copy_compression: Option<CopyCompression>,
+    pub copy_compression_level: Option<u32>,
+    pub to_cache: Option<String>,
+    pub systemd_run: bool,
+    pub diff_closures: bool,
+    pub force: bool,
+    pub substituters: Vec<String>,
+    pub trusted_public_keys: Vec<String>,
+}
+
+/// Progress of a deployment, for library users that want to show it themselves instead of reading the logs
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeployEvent {
+    PushStarted {
+        flake: String,
+        node: String,
+        profile: String,
+    },
+    PushFinished {
+        flake: String,
+        node: String,
+        profile: String,
+        error: Option<String>,
+    },
+    ActivationStarted {
+        flake: String,
+        node: String,
+        profile: String,
+    },
+    ActivationFinished {
+        flake: String,
+        node: String,
+        profile: String,
+        error: Option<String>,
+    },
+    /// A previously activated profile is revoked because a later activation failed
+    RollbackTriggered {
+        flake: String,
+        node: String,
+        profile: String,
+    },
 }
 
 #[derive(PartialEq, Debug)]
@@ -179,8 +440,32 @@ pub enum ParseFlakeError {
     #[error("Unrecognized node or token encountered")]
     Unrecognized,
 }
-pub fn parse_flake(flake: &str) -> Result<DeployFlake, ParseFlakeError> {
-    let flake_fragment_start = flake.find('#');
+
+/// Finds the `#` separating the flake reference from the `<node>.<profile>` attribute path.
+/// This is the last `#` outside of quotes, so that quoted node and profile names may contain
+/// a `#` as well as the flake reference (like a URL with a fragment of its own).
+fn flake_fragment_start(flake: &str) -> Option<usize> {
+    let mut fragment_start = None;
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for (i, c) in flake.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '#' if !in_quotes => fragment_start = Some(i),
+            _ => (),
+        }
+    }
+
+    fragment_start
+}
+
+/// Parses a flake reference followed by an optional `#<node>.<profile>` attribute path, where
+/// the flake reference can use the full flake reference syntax (like `git+https://...?ref=main`)
+pub fn parse_flake(flake: &str) -> Result<DeployFlake<'_>, ParseFlakeError> {
+    let flake_fragment_start = flake_fragment_start(flake);
     let (repo, maybe_fragment) = match flake_fragment_start {
         Some(s) => (&flake[..s], Some(&flake[s + 1..])),
         None => (flake, None),
@@ -308,6 +593,51 @@ fn test_parse_flake() {
             profile: None,
         }
     );
+    assert_eq!(
+        parse_flake("git+https://example.com/infra.git?ref=release&rev=0123abcd#web.system")
+            .unwrap(),
+        DeployFlake {
+            repo: "git+https://example.com/infra.git?ref=release&rev=0123abcd",
+            node: Some("web".to_string()),
+            profile: Some("system".to_string()),
+        }
+    );
+
+    assert_eq!(
+        parse_flake("git+ssh://git@example.com/infra#main?dir=deploy#\"web#1\".system").unwrap(),
+        DeployFlake {
+            repo: "git+ssh://git@example.com/infra#main?dir=deploy",
+            node: Some("web#1".to_string()),
+            profile: Some("system".to_string()),
+        }
+    );
+
+    assert_eq!(
+        parse_flake("https://example.com/infra/archive/main.tar.gz#web").unwrap(),
+        DeployFlake {
+            repo: "https://example.com/infra/archive/main.tar.gz",
+            node: Some("web".to_string()),
+            profile: None,
+        }
+    );
+
+    assert_eq!(
+        parse_flake("path:./infra/nodes?dir=deploy#web.\"system\"").unwrap(),
+        DeployFlake {
+            repo: "path:./infra/nodes?dir=deploy",
+            node: Some("web".to_string()),
+            profile: Some("system".to_string()),
+        }
+    );
+
+    assert_eq!(
+        parse_flake("./infra/nodes/web").unwrap(),
+        DeployFlake {
+            repo: "./infra/nodes/web",
+            node: None,
+            profile: None,
+        }
+    );
 }
 
 #[derive(Debug, Clone)]
@@ -323,6 +653,13 @@ pub struct DeployData<'a> {
 
     pub debug_logs: bool,
     pub log_dir: Option<&'a str>,
+
+    /// What an `srv:` hostname was resolved to by `resolve_srv_hostname`
+    pub resolved_hostname: Option<String>,
+
+    /// What `deploy::current_profile_path` found the profile to point at on the node, if it was already asked,
+    /// which is then used instead of asking the node again
+    pub current_profile_path: Option<Option<String>>,
 }
 
 #[derive(Debug)]
@@ -331,12 +668,35 @@ pub struct DeployDefs {
     pub profile_user: String,
     pub profile_path: String,
     pub sudo: Option<String>,
+    pub hostname: String,
 }
 
 #[derive(Error, Debug)]
 pub enum DeployDataDefsError {
     #[error("Neither `user` nor `sshUser` are set for profile {0} of node {1}")]
     NoProfileUser(String, String),
+    #[error("Unknown placeholder `{{{0}}}` in hostname of node {1}, only `{{node}}` is supported")]
+    UnknownHostnamePlaceholder(String, String),
+    #[error(
+        "Unknown placeholder `{{{0}}}` in profile path template of node {1}, only `{{user}}` and `{{profile}}` are supported"
+    )]
+    UnknownProfilePathPlaceholder(String, String),
+    #[error(
+        "Invalid name `{0}` of an activation environment variable for profile {1} of node {2}"
+    )]
+    InvalidActivationEnv(String, String, String),
+    #[error(
+        "Profile {0} of node {1} is a user profile, so its `user` has to be the SSH user `{2}`"
+    )]
+    UserProfileUser(String, String, String),
+    #[error(
+        "Profile {0} of node {1} can not be activated with `{2}`, nix-darwin can only switch to it"
+    )]
+    DarwinActivationMode(String, String, &'static str),
+    #[error("Node {0} runs nix-darwin, which has no `flock` to lock it with --remote-lock")]
+    DarwinRemoteLock(String),
+    #[error("Failed to look up the SRV record `{1}` for the hostname of node {0}: {2}")]
+    SrvLookup(String, String, String),
 }
 
 impl<'a> DeployData<'a> {
@@ -346,34 +706,200 @@ impl<'a> DeployData<'a> {
             None => whoami::username(),
         };
 
-        let profile_user = self.get_profile_user()?;
+        let profile_user = match self.profile_type() {
+            data::ProfileType::System => self.get_profile_user()?,
+            // User profiles always belong to the user they are deployed as
+            data::ProfileType::User => match self.merged_settings.user {
+                Some(ref user) if user != &ssh_user => {
+                    return Err(DeployDataDefsError::UserProfileUser(
+                        self.profile_name.to_owned(),
+                        self.node_name.to_owned(),
+                        ssh_user,
+                    ))
+                }
+                _ => ssh_user.clone(),
+            },
+        };
+
+        let profile_path = self.get_profile_path(&profile_user)?;
 
-        let profile_path = self.get_profile_path()?;
+        if self.node_system() == data::NodeSystem::Darwin {
+            match self.activation_mode() {
+                data::ActivationMode::Boot | data::ActivationMode::Test => {
+                    return Err(DeployDataDefsError::DarwinActivationMode(
+                        self.profile_name.to_owned(),
+                        self.node_name.to_owned(),
+                        self.activation_mode().as_str(),
+                    ))
+                }
+                data::ActivationMode::Switch | data::ActivationMode::DryActivate => (),
+            }
+
+            if self.cmd_overrides.remote_lock {
+                return Err(DeployDataDefsError::DarwinRemoteLock(
+                    self.node_name.to_owned(),
+                ));
+            }
+        }
 
         let sudo: Option<String> = match self.merged_settings.user {
-            Some(ref user) if user != &ssh_user => Some(format!("{} {}", self.get_sudo(), user)),
+            Some(ref user) if user != &ssh_user => Some(format_sudo(&self.get_sudo(), user)),
             _ => None,
         };
 
+        for key in self.merged_settings.activation_env.keys() {
+            if !is_env_name(key) {
+                return Err(DeployDataDefsError::InvalidActivationEnv(
+                    key.clone(),
+                    self.profile_name.to_owned(),
+                    self.node_name.to_owned(),
+                ));
+            }
+        }
+
+        let hostname = match self.resolved_hostname {
+            Some(ref x) => x.clone(),
+            None => expand_hostname(self.hostname_setting(), self.node_name)?,
+        };
+
         Ok(DeployDefs {
             ssh_user,
             profile_user,
             profile_path,
             sudo,
+            hostname,
         })
     }
 
-    fn get_profile_path(&'a self) -> Result<String, DeployDataDefsError> {
-        let profile_user = self.get_profile_user()?;
-        let profile_path = match self.profile.profile_settings.profile_path {
-            None => match &profile_user[..] {
+    /// Returns how the profile is activated, which is switching to it unless configured otherwise
+    pub fn activation_mode(&self) -> data::ActivationMode {
+        self.merged_settings
+            .activation_mode
+            .unwrap_or(data::ActivationMode::Switch)
+    }
+
+    /// Returns if the node is the machine that is deploying, in which case commands are run on it directly
+    /// instead of over SSH, and nothing has to be copied. Unless overridden, this is detected from the hostname.
+    pub fn is_local(&self) -> bool {
+        self.cmd_overrides
+            .local
+            .unwrap_or_else(|| is_local_hostname(self.hostname_setting()))
+    }
+
+    /// Returns the hostname of the node as configured (or overridden), before expanding or resolving it
+    fn hostname_setting(&self) -> &str {
+        match self.cmd_overrides.hostname {
+            Some(ref x) => x,
+            None => &self.node.node_settings.hostname,
+        }
+    }
+
+    /// Resolves a hostname starting with `srv:` with a DNS SRV lookup of the rest of it, so that the node is
+    /// connected to at the target and port of the record instead. Other hostnames are left as they are.
+    /// Every name is only looked up once, with what it resolved to kept in `resolved`.
+    pub async fn resolve_srv_hostname(
+        &mut self,
+        resolved: &mut HashMap<String, (String, u16)>,
+    ) -> Result<(), DeployDataDefsError> {
+        let hostname = expand_hostname(self.hostname_setting(), self.node_name)?;
+
+        let name = match hostname.strip_prefix("srv:") {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+
+        let (target, port) = match resolved.get(name) {
+            Some(x) => x.clone(),
+            None => {
+                let x = lookup_srv(name).await.map_err(|reason| {
+                    DeployDataDefsError::SrvLookup(
+                        self.node_name.to_owned(),
+                        name.to_owned(),
+                        reason,
+                    )
+                })?;
+
+                log::info!(
+                    "Resolved `{}` of node `{}` to {} port {}",
+                    hostname,
+                    self.node_name,
+                    x.0,
+                    x.1
+                );
+
+                resolved.insert(name.to_string(), x.clone());
+                x
+            }
+        };
+
+        // SSH uses the first port it is given, so the one of the record goes before `sshOpts`
+        let ssh_opts = &self.merged_settings.ssh_opts;
+        if ssh_opts
+            .iter()
+            .any(|x| x.starts_with("-p") || x.contains("Port="))
+        {
+            log::warn!(
+                "The `sshOpts` of node `{}` set a port, which is ignored for the port {} of its SRV record",
+                self.node_name,
+                port
+            );
+        }
+
+        self.merged_settings
+            .ssh_opts
+            .splice(0..0, vec!["-p".to_string(), port.to_string()]);
+        self.resolved_hostname = Some(target);
+
+        Ok(())
+    }
+
+    /// Returns the activation script of the profile (relative to it) if it is not the default `deploy-rs-activate`
+    pub fn activate_script(&self) -> Option<&'a str> {
+        self.profile.profile_settings.activate.as_deref()
+    }
+
+    /// Returns the Nix store path that activating this profile would switch the node's profile to,
+    /// without building or copying anything
+    pub fn store_path(&self) -> &'a str {
+        &self.profile.profile_settings.path
+    }
+
+    /// Returns the operating system of the node, which is NixOS unless configured otherwise
+    pub fn node_system(&self) -> data::NodeSystem {
+        self.node
+            .node_settings
+            .system
+            .unwrap_or(data::NodeSystem::Nixos)
+    }
+
+    /// Returns whose profile is deployed, which is a system profile unless configured otherwise
+    pub fn profile_type(&self) -> data::ProfileType {
+        self.profile
+            .profile_settings
+            .profile_type
+            .unwrap_or(data::ProfileType::System)
+    }
+
+    fn get_profile_path(&'a self, profile_user: &str) -> Result<String, DeployDataDefsError> {
+        let profile_path = match (
+            &self.profile.profile_settings.profile_path,
+            &self.merged_settings.profile_path_template,
+        ) {
+            (Some(x), _) => x.clone(),
+            (None, Some(template)) => {
+                expand_profile_path(template, profile_user, self.profile_name, self.node_name)?
+            }
+            // Relative to the home directory, which commands run over SSH start in
+            (None, None) if self.profile_type() == data::ProfileType::User => {
+                format!(".local/state/nix/profiles/{}", self.profile_name)
+            }
+            (None, None) => match profile_user {
                 "root" => format!("/nix/var/nix/profiles/{}", self.profile_name),
                 _ => format!(
                     "/nix/var/nix/profiles/per-user/{}/{}",
                     profile_user, self.profile_name
                 ),
             },
-            Some(ref x) => x.clone(),
         };
         Ok(profile_path)
     }
@@ -395,23 +921,586 @@ impl<'a> DeployData<'a> {
     }
 
     fn get_sudo(&'a self) -> String {
-        return match self.merged_settings.sudo {
-           Some(ref x) => x.clone(),
-           None => "sudo -u".to_string()
+        match self.merged_settings.sudo {
+            Some(ref x) => x.clone(),
+            None => "sudo -u".to_string(),
+        }
+    }
+}
+
+/// The `nix` binary to run instead of the one in the `PATH`
+static NIX_BIN: OnceLock<PathBuf> = OnceLock::new();
+/// The `ssh` binary to run instead of the one in the `PATH`, and a directory with it named `ssh`
+static SSH_BIN: OnceLock<(PathBuf, PathBuf)> = OnceLock::new();
+
+/// Sets the `nix` and `ssh` binaries to run instead of those in the `PATH`, for every command started afterwards.
+/// The other Nix tools, like `nix-build`, are run from the directory of `nix_bin`.
+/// Binaries can only be set once, later calls do not change them.
+/// The directory `ssh` is linked into for Nix is returned, and has to be kept until nothing is run anymore.
+pub fn set_binaries(
+    nix_bin: Option<&str>,
+    ssh_bin: Option<&str>,
+) -> std::io::Result<Option<PrivateDir>> {
+    if let Some(nix_bin) = nix_bin {
+        let _ = NIX_BIN.set(PathBuf::from(nix_bin));
+    }
+
+    if let Some(ssh_bin) = ssh_bin {
+        let ssh_bin = match ssh_bin.contains('/') {
+            true => std::fs::canonicalize(ssh_bin)?,
+            false => find_in_path(ssh_bin).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("`{}` was not found in the PATH", ssh_bin),
+                )
+            })?,
         };
+
+        if SSH_BIN.get().is_some() {
+            return Ok(None);
+        }
+
+        // Nix runs whichever `ssh` comes first in the `PATH`, so it gets a directory with only the binary, named `ssh`
+        let ssh_dir = PrivateDir::new("deploy-rs-ssh")?;
+        std::os::unix::fs::symlink(&ssh_bin, ssh_dir.path().join("ssh"))?;
+
+        let _ = SSH_BIN.set((ssh_bin, ssh_dir.path().to_path_buf()));
+
+        return Ok(Some(ssh_dir));
     }
+
+    Ok(None)
 }
 
-pub fn make_deploy_data<'a, 's>(
-    top_settings: &'s data::GenericSettings,
-    node: &'a data::Node,
-    node_name: &'a str,
-    profile: &'a data::Profile,
-    profile_name: &'a str,
-    cmd_overrides: &'a CmdOverrides,
-    debug_logs: bool,
-    log_dir: Option<&'a str>,
-) -> DeployData<'a> {
+/// The Nix tool to run (like `nix` or `nix-build`), from the directory of the configured `nix` binary if there is one
+pub fn nix_tool(tool: &str) -> PathBuf {
+    match NIX_BIN.get() {
+        Some(nix_bin) if tool == "nix" => nix_bin.clone(),
+        Some(nix_bin) => match nix_bin.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.join(tool),
+            _ => PathBuf::from(tool),
+        },
+        None => PathBuf::from(tool),
+    }
+}
+
+/// The `ssh` binary to run
+pub fn ssh_bin() -> &'static Path {
+    match SSH_BIN.get() {
+        Some((ssh_bin, _)) => ssh_bin,
+        None => Path::new("ssh"),
+    }
+}
+
+/// The `PATH` for Nix commands connecting to nodes, so that they run the configured `ssh` binary, if there is one
+pub fn nix_ssh_path() -> Option<OsString> {
+    let (_, ssh_dir) = SSH_BIN.get()?;
+
+    let mut path = OsString::from(ssh_dir);
+    if let Some(x) = std::env::var_os("PATH") {
+        path.push(":");
+        path.push(x);
+    }
+
+    Some(path)
+}
+
+/// Returns the first executable called `name` in the `PATH`
+pub fn find_in_path(name: &str) -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// A new directory in the temporary directory that only the current user can access, deleted again when dropped.
+/// It is never one that already exists, so that nobody else can have put anything into it.
+#[derive(Debug)]
+pub struct PrivateDir(PathBuf);
+
+impl PrivateDir {
+    pub fn new(prefix: &str) -> std::io::Result<Self> {
+        use std::os::unix::fs::DirBuilderExt;
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+        loop {
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|x| x.subsec_nanos())
+                .unwrap_or(0);
+
+            let path = std::env::temp_dir().join(format!(
+                "{}-{}-{}-{:08x}",
+                prefix,
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst),
+                nanos
+            ));
+
+            // Unlike `create_dir_all`, this fails if the directory is already there
+            match std::fs::DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => return Ok(PrivateDir(path)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The `-v` flag given `verbosity` times (like `-vv`), for SSH and Nix to print what they are doing
+pub fn verbosity_flag(verbosity: u8) -> Option<String> {
+    match verbosity {
+        0 => None,
+        _ => Some(format!("-{}", "v".repeat(verbosity as usize))),
+    }
+}
+
+/// Returns if the hostname refers to the local machine
+pub fn is_local_hostname(hostname: &str) -> bool {
+    matches!(hostname, "localhost" | "127.0.0.1" | "::1")
+}
+
+/// Returns if the name can be used for a shell environment variable
+pub fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[test]
+fn test_is_env_name() {
+    assert!(is_env_name("DEPLOY_ID"));
+    assert!(is_env_name("_flag2"));
+    assert!(!is_env_name("2FLAG"));
+    assert!(!is_env_name("FOO;rm"));
+    assert!(!is_env_name(""));
+}
+
+/// Returns if the path is a top-level path in the Nix store, like `/nix/store/<hash>-<name>`
+pub fn is_store_path(path: &str) -> bool {
+    let name = match path.strip_prefix("/nix/store/") {
+        Some(x) => x,
+        None => return false,
+    };
+
+    match (name.get(..32), name.get(32..)) {
+        (Some(hash), Some(rest)) => {
+            hash.chars()
+                .all(|c| "0123456789abcdfghijklmnpqrsvwxyz".contains(c))
+                && rest.len() > 1
+                && rest.starts_with('-')
+                && !rest.contains('/')
+        }
+        _ => false,
+    }
+}
+
+#[test]
+fn test_is_store_path() {
+    assert!(is_store_path(
+        "/nix/store/0c8l1lj5dwcl4m3srh4nbk9b4cnhwvkd-nixos-system-web-23.05"
+    ));
+    assert!(!is_store_path(
+        "/nix/store/0c8l1lj5dwcl4m3srh4nbk9b4cnhwvkd-nixos-system-web-23.05/bin"
+    ));
+    assert!(!is_store_path("/nix/store/not-a-hash"));
+    assert!(!is_store_path(
+        "/tmp/0c8l1lj5dwcl4m3srh4nbk9b4cnhwvkd-hello"
+    ));
+}
+
+/// Looks up the SRV record `name`, returning the target and port to connect to
+async fn lookup_srv(name: &str) -> Result<(String, u16), String> {
+    let resolver = hickory_resolver::TokioAsyncResolver::tokio_from_system_conf()
+        .map_err(|e| e.to_string())?;
+
+    let lookup = resolver.srv_lookup(name).await.map_err(|e| e.to_string())?;
+
+    pick_srv_record(
+        lookup
+            .iter()
+            .map(|x| (x.priority(), x.weight(), x.port(), x.target().to_utf8())),
+    )
+    .ok_or_else(|| "no usable records".to_string())
+}
+
+/// Picks the SRV record to connect to from `(priority, weight, port, target)`, which is the one with the lowest
+/// priority, and the highest weight among those. A target of `.` means that there is no such service.
+pub fn pick_srv_record<I: Iterator<Item = (u16, u16, u16, String)>>(
+    records: I,
+) -> Option<(String, u16)> {
+    records
+        .min_by_key(|(priority, weight, _, _)| (*priority, std::cmp::Reverse(*weight)))
+        .and_then(|(_, _, port, target)| match target.trim_end_matches('.') {
+            "" => None,
+            target => Some((target.to_string(), port)),
+        })
+}
+
+/// Replaces `{node}` in the hostname template with the name of the node.
+/// Any other placeholder is an error, rather than ending up in the hostname as is.
+pub fn expand_hostname(template: &str, node_name: &str) -> Result<String, DeployDataDefsError> {
+    let hostname = template.replace("{node}", node_name);
+
+    if let Some(start) = hostname.find('{') {
+        let placeholder = match hostname[start + 1..].find('}') {
+            Some(end) => &hostname[start + 1..start + 1 + end],
+            None => &hostname[start + 1..],
+        };
+
+        return Err(DeployDataDefsError::UnknownHostnamePlaceholder(
+            placeholder.to_string(),
+            node_name.to_string(),
+        ));
+    }
+
+    Ok(hostname)
+}
+
+#[test]
+fn test_expand_hostname() {
+    assert_eq!(
+        expand_hostname("{node}.internal", "web01").unwrap(),
+        "web01.internal"
+    );
+    assert_eq!(
+        expand_hostname("host.example.com", "web01").unwrap(),
+        "host.example.com"
+    );
+    assert!(matches!(
+        expand_hostname("{name}.internal", "web01"),
+        Err(DeployDataDefsError::UnknownHostnamePlaceholder(p, _)) if p == "name"
+    ));
+}
+
+/// Replaces `{user}` and `{profile}` in the profile path template with the profile's user and name.
+/// Like in hostnames, any other placeholder is an error.
+pub fn expand_profile_path(
+    template: &str,
+    profile_user: &str,
+    profile_name: &str,
+    node_name: &str,
+) -> Result<String, DeployDataDefsError> {
+    let profile_path = template
+        .replace("{user}", profile_user)
+        .replace("{profile}", profile_name);
+
+    if let Some(start) = profile_path.find('{') {
+        let placeholder = match profile_path[start + 1..].find('}') {
+            Some(end) => &profile_path[start + 1..start + 1 + end],
+            None => &profile_path[start + 1..],
+        };
+
+        return Err(DeployDataDefsError::UnknownProfilePathPlaceholder(
+            placeholder.to_string(),
+            node_name.to_string(),
+        ));
+    }
+
+    Ok(profile_path)
+}
+
+#[test]
+fn test_expand_profile_path() {
+    assert_eq!(
+        expand_profile_path(
+            "/nix/var/nix/profiles/custom/{user}/{profile}",
+            "alice",
+            "web",
+            "host"
+        )
+        .unwrap(),
+        "/nix/var/nix/profiles/custom/alice/web"
+    );
+    assert!(matches!(
+        expand_profile_path("/profiles/{node}/{profile}", "alice", "web", "host"),
+        Err(DeployDataDefsError::UnknownProfilePathPlaceholder(p, _)) if p == "node"
+    ));
+}
+
+/// The directory for the sockets of shared SSH connections, which only the current user may access, so that nobody
+/// else can put a socket there that would be used as the connection to a node. This is `$XDG_RUNTIME_DIR`,
+/// or else `~/.ssh` (made if it does not exist yet). Without such a directory, connections are not shared.
+pub fn ssh_control_dir() -> Option<&'static Path> {
+    static CONTROL_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+    CONTROL_DIR
+        .get_or_init(|| {
+            use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+            let is_private = |dir: &Path| {
+                std::fs::metadata(dir)
+                    .map(|x| x.is_dir() && x.permissions().mode() & 0o077 == 0)
+                    .unwrap_or(false)
+            };
+
+            if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+                if is_private(&dir) {
+                    return Some(dir);
+                }
+            }
+
+            if let Some(dir) = std::env::var_os("HOME").map(|x| PathBuf::from(x).join(".ssh")) {
+                let _ = std::fs::DirBuilder::new().mode(0o700).create(&dir);
+
+                if is_private(&dir) {
+                    return Some(dir);
+                }
+            }
+
+            log::warn!("Not sharing SSH connections, as neither `$XDG_RUNTIME_DIR` nor `~/.ssh` are private directories for their sockets");
+            None
+        })
+        .as_deref()
+}
+
+/// Adds the SSH options needed to share a single connection to a node between every command run over SSH.
+/// The master connection's socket is put into `control_dir`, unless the options already configure multiplexing.
+pub fn add_ssh_multiplexing_opts(ssh_opts: &[String], control_dir: &str) -> Vec<String> {
+    let mut ssh_opts = ssh_opts.to_vec();
+
+    if ssh_opts
+        .iter()
+        .any(|x| x.contains("ControlMaster") || x.contains("ControlPath"))
+    {
+        return ssh_opts;
+    }
+
+    ssh_opts.extend(
+        [
+            "-o".to_string(),
+            "ControlMaster=auto".to_string(),
+            "-o".to_string(),
+            format!(
+                "ControlPath={}/deploy-rs-{}-%C",
+                control_dir.trim_end_matches('/'),
+                std::process::id()
+            ),
+            "-o".to_string(),
+            "ControlPersist=60".to_string(),
+        ]
+        .iter()
+        .cloned(),
+    );
+
+    ssh_opts
+}
+
+#[test]
+fn test_add_ssh_multiplexing_opts() {
+    let ssh_opts = vec!["-p".to_string(), "2121".to_string()];
+
+    assert_eq!(
+        add_ssh_multiplexing_opts(&ssh_opts, "/tmp/"),
+        vec![
+            "-p".to_string(),
+            "2121".to_string(),
+            "-o".to_string(),
+            "ControlMaster=auto".to_string(),
+            "-o".to_string(),
+            format!("ControlPath=/tmp/deploy-rs-{}-%C", std::process::id()),
+            "-o".to_string(),
+            "ControlPersist=60".to_string(),
+        ]
+    );
+
+    let ssh_opts = vec!["-o".to_string(), "ControlPath=/run/ssh-%C".to_string()];
+
+    assert_eq!(add_ssh_multiplexing_opts(&ssh_opts, "/tmp"), ssh_opts);
+}
+
+/// SSH options for the connection timeout and keepalives of `settings`. Unset values are left to SSH, so that
+/// they can still come from the SSH config, as options on the command line take precedence over it.
+/// These come after `sshOpts`, and SSH uses the first value given for an option, so options set there take precedence.
+/// Every option is a single argument, so that `NIX_SSHOPTS` (which is split on spaces) keeps it intact.
+pub fn ssh_timeout_opts(settings: &data::GenericSettings) -> Vec<String> {
+    let opts = vec![
+        ("ConnectTimeout", settings.ssh_connect_timeout),
+        ("ServerAliveInterval", settings.ssh_keepalive_interval),
+        ("ServerAliveCountMax", settings.ssh_keepalive_count_max),
+    ];
+
+    opts.into_iter()
+        .filter_map(|(option, value)| value.map(|value| format!("-o{}={}", option, value)))
+        .collect()
+}
+
+#[test]
+fn test_ssh_timeout_opts() {
+    let mut settings: data::GenericSettings = serde_json::from_str("{}").unwrap();
+
+    assert_eq!(ssh_timeout_opts(&settings), Vec::<String>::new());
+
+    settings.ssh_connect_timeout = Some(5);
+    settings.ssh_keepalive_interval = Some(0);
+
+    assert_eq!(
+        ssh_timeout_opts(&settings),
+        vec![
+            "-oConnectTimeout=5".to_string(),
+            "-oServerAliveInterval=0".to_string(),
+        ]
+    );
+}
+
+/// SSH options for `sshForwardAgent` and `sshGssapi`, each passed as a single argument like the timeouts.
+/// Unset toggles leave the option to SSH and its config.
+pub fn ssh_auth_opts(settings: &data::GenericSettings) -> Vec<String> {
+    let opts = vec![
+        ("ForwardAgent", settings.ssh_forward_agent),
+        ("GSSAPIAuthentication", settings.ssh_gssapi),
+    ];
+
+    opts.into_iter()
+        .filter_map(|(option, value)| {
+            value.map(|value| format!("-o{}={}", option, if value { "yes" } else { "no" }))
+        })
+        .collect()
+}
+
+#[test]
+fn test_ssh_auth_opts() {
+    let mut settings: data::GenericSettings = serde_json::from_str("{}").unwrap();
+
+    assert_eq!(ssh_auth_opts(&settings), Vec::<String>::new());
+
+    settings.ssh_forward_agent = Some(true);
+    settings.ssh_gssapi = Some(false);
+
+    assert_eq!(
+        ssh_auth_opts(&settings),
+        vec![
+            "-oForwardAgent=yes".to_string(),
+            "-oGSSAPIAuthentication=no".to_string(),
+        ]
+    );
+}
+
+/// Builds the command prefix used to run commands as `user`.
+/// Every `{user}` in `sudo` is replaced by the user, if there is none the user is appended instead.
+pub fn format_sudo(sudo: &str, user: &str) -> String {
+    if sudo.contains("{user}") {
+        sudo.replace("{user}", user)
+    } else {
+        format!("{} {}", sudo, user)
+    }
+}
+
+/// How many single character insertions, deletions, substitutions or swaps of neighbouring characters
+/// it takes to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // `d[i][j]` is the distance between the first `i` characters of `a` and the first `j` of `b`
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for i in 0..=a.len() {
+        for j in 0..=b.len() {
+            d[i][j] = match (i, j) {
+                (0, _) => j,
+                (_, 0) => i,
+                _ => {
+                    let substitution = d[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+                    let mut distance = substitution.min(d[i - 1][j] + 1).min(d[i][j - 1] + 1);
+
+                    if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                        distance = distance.min(d[i - 2][j - 2] + 1);
+                    }
+
+                    distance
+                }
+            };
+        }
+    }
+
+    d[a.len()][b.len()]
+}
+
+/// Suggests the names among `candidates` that `name` was probably meant to be, as `, did you mean ...?`
+/// to be appended to an error, or nothing if none are close enough
+pub fn did_you_mean<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> String {
+    let max_distance = (name.chars().count() / 3).max(1);
+
+    let mut close: Vec<(usize, &str)> = candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, candidate)| {
+            *distance <= max_distance || (!name.is_empty() && candidate.starts_with(name))
+        })
+        .collect();
+
+    close.sort_unstable();
+    close.dedup_by_key(|(_, candidate)| *candidate);
+
+    let names: Vec<String> = close
+        .iter()
+        .take(3)
+        .map(|(_, candidate)| format!("`{}`", candidate))
+        .collect();
+
+    match names.split_last() {
+        None => String::new(),
+        Some((last, [])) => format!(", did you mean {}?", last),
+        Some((last, rest)) => format!(", did you mean {} or {}?", rest.join(", "), last),
+    }
+}
+
+#[test]
+fn test_did_you_mean() {
+    let nodes = vec!["web1", "web2", "database", "cache"];
+
+    assert_eq!(
+        did_you_mean("web", nodes.clone()),
+        ", did you mean `web1` or `web2`?"
+    );
+    assert_eq!(
+        did_you_mean("databse", nodes.clone()),
+        ", did you mean `database`?"
+    );
+    assert_eq!(
+        did_you_mean("wbe1", nodes.clone()),
+        ", did you mean `web1`?"
+    );
+    assert_eq!(did_you_mean("xyzzy", nodes), "");
+}
+
+#[test]
+fn test_format_sudo() {
+    assert_eq!(format_sudo("sudo -u", "test"), "sudo -u test");
+    assert_eq!(format_sudo("doas -u", "test"), "doas -u test");
+    assert_eq!(
+        format_sudo("sudo -A -u {user} --", "test"),
+        "sudo -A -u test --"
+    );
+}
+
+/// Resolves the settings a profile is deployed with. Every setting is taken from the first of these that sets it:
+/// the command line (`cmd_overrides`), the profile, the node and the top level of the deployment.
+/// Lists like `sshOpts` are combined instead, in the same order, while `--ssh-opts` replaces them.
+/// Options derived from the settings, like those for `sshIdentities` or `jumpHost`, are added to `sshOpts` last.
+pub fn resolve_settings(
+    top_settings: &data::GenericSettings,
+    node: &data::Node,
+    profile: &data::Profile,
+    cmd_overrides: &CmdOverrides,
+) -> data::GenericSettings {
     let mut merged_settings = profile.generic_settings.clone();
     merged_settings.merge(node.generic_settings.clone());
     merged_settings.merge(top_settings.clone());
@@ -434,6 +1523,112 @@ pub fn make_deploy_data<'a, 's>(
     if let Some(magic_rollback) = cmd_overrides.magic_rollback {
         merged_settings.magic_rollback = Some(magic_rollback);
     }
+    if cmd_overrides.temp_path.is_some() {
+        merged_settings.temp_path = cmd_overrides.temp_path.clone();
+    }
+    if cmd_overrides.profile_path_template.is_some() {
+        merged_settings.profile_path_template = cmd_overrides.profile_path_template.clone();
+    }
+    if let Some(confirm_timeout) = cmd_overrides.confirm_timeout {
+        merged_settings.confirm_timeout = Some(confirm_timeout);
+    }
+    if cmd_overrides.sudo.is_some() {
+        merged_settings.sudo = cmd_overrides.sudo.clone();
+    }
+    if let Some(remote_build) = cmd_overrides.remote_build {
+        merged_settings.remote_build = Some(remote_build);
+    }
+    merged_settings
+        .substituters
+        .extend(cmd_overrides.substituters.iter().cloned());
+    merged_settings
+        .trusted_public_keys
+        .extend(cmd_overrides.trusted_public_keys.iter().cloned());
+    if cmd_overrides.systemd_run {
+        merged_settings.systemd_run = Some(true);
+    }
+    if let Some(ssh_multiplexing) = cmd_overrides.ssh_multiplexing {
+        merged_settings.ssh_multiplexing = Some(ssh_multiplexing);
+    }
+
+    for (key, value) in &cmd_overrides.activation_env {
+        merged_settings
+            .activation_env
+            .insert(key.clone(), value.clone());
+    }
+    if let Some(activation_mode) = cmd_overrides.activation_mode {
+        merged_settings.activation_mode = Some(activation_mode);
+    }
+    if cmd_overrides.jump_host.is_some() {
+        merged_settings.jump_host = cmd_overrides.jump_host.clone();
+    }
+    if let Some(ssh_connect_timeout) = cmd_overrides.ssh_connect_timeout {
+        merged_settings.ssh_connect_timeout = Some(ssh_connect_timeout);
+    }
+    if let Some(ssh_keepalive_interval) = cmd_overrides.ssh_keepalive_interval {
+        merged_settings.ssh_keepalive_interval = Some(ssh_keepalive_interval);
+    }
+    if let Some(ssh_keepalive_count_max) = cmd_overrides.ssh_keepalive_count_max {
+        merged_settings.ssh_keepalive_count_max = Some(ssh_keepalive_count_max);
+    }
+    if let Some(ssh_forward_agent) = cmd_overrides.ssh_forward_agent {
+        merged_settings.ssh_forward_agent = Some(ssh_forward_agent);
+    }
+    if let Some(ssh_gssapi) = cmd_overrides.ssh_gssapi {
+        merged_settings.ssh_gssapi = Some(ssh_gssapi);
+    }
+
+    if let Some(ref ssh_config_file) = cmd_overrides.ssh_config_file {
+        merged_settings.ssh_opts.push("-F".to_string());
+        merged_settings.ssh_opts.push(ssh_config_file.clone());
+    }
+
+    // SSH tries every identity in order, also passed as single arguments for `NIX_SSHOPTS`
+    for identity in &merged_settings.ssh_identities {
+        merged_settings.ssh_opts.push(format!("-i{}", identity));
+    }
+
+    // Passed as a single argument, so that `NIX_SSHOPTS` (which is split on spaces) keeps it intact
+    if let Some(ref jump_host) = merged_settings.jump_host {
+        merged_settings
+            .ssh_opts
+            .push(format!("-oProxyJump={}", jump_host));
+    }
+
+    let timeout_opts = ssh_timeout_opts(&merged_settings);
+    merged_settings.ssh_opts.extend(timeout_opts);
+
+    let auth_opts = ssh_auth_opts(&merged_settings);
+    merged_settings.ssh_opts.extend(auth_opts);
+
+    merged_settings
+        .ssh_opts
+        .extend(verbosity_flag(cmd_overrides.verbose_ssh));
+
+    if merged_settings.ssh_multiplexing == Some(true) {
+        if let Some(control_dir) = ssh_control_dir() {
+            merged_settings.ssh_opts = add_ssh_multiplexing_opts(
+                &merged_settings.ssh_opts,
+                &control_dir.to_string_lossy(),
+            );
+        }
+    }
+
+    merged_settings
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn make_deploy_data<'a>(
+    top_settings: &data::GenericSettings,
+    node: &'a data::Node,
+    node_name: &'a str,
+    profile: &'a data::Profile,
+    profile_name: &'a str,
+    cmd_overrides: &'a CmdOverrides,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+) -> DeployData<'a> {
+    let merged_settings = resolve_settings(top_settings, node, profile, cmd_overrides);
 
     DeployData {
         node_name,
@@ -444,5 +1639,326 @@ pub fn make_deploy_data<'a, 's>(
         merged_settings,
         debug_logs,
         log_dir,
+        resolved_hostname: None,
+        current_profile_path: None,
     }
 }
+
+#[test]
+fn test_resolve_settings() {
+    let data: data::Data = serde_json::from_str(
+        r#"{
+            "sshUser": "top",
+            "tempPath": "/top",
+            "confirmTimeout": 10,
+            "magicRollback": false,
+            "sshOpts": ["-p", "22"],
+            "nodes": {
+                "host": {
+                    "hostname": "host.example.com",
+                    "sshUser": "node",
+                    "tempPath": "/node",
+                    "sshOpts": ["-4"],
+                    "profiles": {
+                        "system": {
+                            "path": "/nix/store/system",
+                            "tempPath": "/profile",
+                            "sshOpts": ["-C"]
+                        }
+                    }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let node = &data.nodes["host"];
+    let profile = &node.node_settings.profiles["system"];
+
+    let settings = resolve_settings(
+        &data.generic_settings,
+        node,
+        profile,
+        &CmdOverrides::default(),
+    );
+
+    assert_eq!(settings.ssh_user.as_deref(), Some("node"));
+    assert_eq!(settings.temp_path.as_deref(), Some("/profile"));
+    assert_eq!(settings.confirm_timeout, Some(10));
+    assert_eq!(settings.magic_rollback, Some(false));
+    assert_eq!(settings.ssh_opts[..4], ["-C", "-4", "-p", "22"]);
+
+    let cmd_overrides = CmdOverrides {
+        ssh_user: Some("cli".to_string()),
+        temp_path: Some("/cli".to_string()),
+        magic_rollback: Some(true),
+        ssh_opts: Some("-6".to_string()),
+        ..Default::default()
+    };
+
+    let settings = resolve_settings(&data.generic_settings, node, profile, &cmd_overrides);
+
+    assert_eq!(settings.ssh_user.as_deref(), Some("cli"));
+    assert_eq!(settings.temp_path.as_deref(), Some("/cli"));
+    assert_eq!(settings.confirm_timeout, Some(10));
+    assert_eq!(settings.magic_rollback, Some(true));
+    assert_eq!(settings.ssh_opts[0], "-6");
+}
+
+#[test]
+fn test_ssh_opts_list() {
+    let data: data::Data = serde_json::from_str(
+        r#"{
+            "nodes": {
+                "host": {
+                    "hostname": "host.example.com",
+                    "sshOpts": ["-oProxyCommand=ssh -W %h:%p bastion"],
+                    "profiles": {
+                        "system": { "path": "/nix/store/system" }
+                    }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let node = &data.nodes["host"];
+    let profile = &node.node_settings.profiles["system"];
+
+    let settings = resolve_settings(
+        &data.generic_settings,
+        node,
+        profile,
+        &CmdOverrides::default(),
+    );
+
+    assert_eq!(settings.ssh_opts[0], "-oProxyCommand=ssh -W %h:%p bastion");
+
+    let cmd_overrides = CmdOverrides {
+        ssh_opts: Some("-p 2121".to_string()),
+        ..Default::default()
+    };
+
+    let settings = resolve_settings(&data.generic_settings, node, profile, &cmd_overrides);
+
+    assert_eq!(settings.ssh_opts[..2], ["-p", "2121"]);
+}
+
+/// The deployment data of `profile` on `node` in the deployment data `json`, without any options
+/// given on the command line
+#[cfg(test)]
+fn deploy_data_for(json: &str, node: &'static str, profile: &'static str) -> DeployData<'static> {
+    deploy_data_with(json, node, profile, CmdOverrides::default())
+}
+
+/// The deployment data of `profile` on `node` in the deployment data `json`, with `cmd_overrides`.
+/// Both are leaked, as the deployment data borrows from them
+#[cfg(test)]
+fn deploy_data_with(
+    json: &str,
+    node: &'static str,
+    profile: &'static str,
+    cmd_overrides: CmdOverrides,
+) -> DeployData<'static> {
+    let data: &'static data::Data = Box::leak(Box::new(serde_json::from_str(json).unwrap()));
+    let cmd_overrides: &'static CmdOverrides = Box::leak(Box::new(cmd_overrides));
+
+    let node_data = &data.nodes[node];
+
+    make_deploy_data(
+        &data.generic_settings,
+        node_data,
+        node,
+        &node_data.node_settings.profiles[profile],
+        profile,
+        cmd_overrides,
+        false,
+        None,
+    )
+}
+
+#[test]
+fn test_profile_user_precedence() {
+    let data = r#"{
+        "sshUser": "admin",
+        "user": "nobody",
+        "nodes": {
+            "host": {
+                "hostname": "host.example.com",
+                "user": "root",
+                "profiles": {
+                    "system": { "path": "/nix/store/system" },
+                    "app": { "path": "/nix/store/app", "user": "app" }
+                }
+            }
+        }
+    }"#;
+
+    let system_defs = deploy_data_for(data, "host", "system").defs().unwrap();
+    assert_eq!(system_defs.profile_user, "root");
+    assert_eq!(system_defs.sudo.as_deref(), Some("sudo -u root"));
+
+    let app_defs = deploy_data_for(data, "host", "app").defs().unwrap();
+    assert_eq!(app_defs.ssh_user, "admin");
+    assert_eq!(app_defs.profile_user, "app");
+    assert_eq!(app_defs.sudo.as_deref(), Some("sudo -u app"));
+    assert_eq!(
+        app_defs.profile_path,
+        "/nix/var/nix/profiles/per-user/app/app"
+    );
+}
+
+#[test]
+fn test_confirm_timeout_precedence() {
+    let data = r#"{
+        "confirmTimeout": 30,
+        "nodes": {
+            "host": {
+                "hostname": "host.example.com",
+                "confirmTimeout": 60,
+                "profiles": {
+                    "system": { "path": "/nix/store/system" },
+                    "database": { "path": "/nix/store/database", "confirmTimeout": 600 }
+                }
+            }
+        }
+    }"#;
+
+    let confirm_timeout_of = |deploy_data: DeployData| deploy_data.merged_settings.confirm_timeout;
+
+    assert_eq!(
+        confirm_timeout_of(deploy_data_for(data, "host", "system")),
+        Some(60)
+    );
+    assert_eq!(
+        confirm_timeout_of(deploy_data_for(data, "host", "database")),
+        Some(600)
+    );
+
+    let cmd_overrides = CmdOverrides {
+        confirm_timeout: Some(10),
+        ..Default::default()
+    };
+    assert_eq!(
+        confirm_timeout_of(deploy_data_with(data, "host", "database", cmd_overrides)),
+        Some(10)
+    );
+}
+
+#[test]
+fn test_darwin_node() {
+    let data = r#"{
+        "sshUser": "admin",
+        "nodes": {
+            "mac": {
+                "hostname": "mac.example.com",
+                "system": "darwin",
+                "profiles": {
+                    "system": { "path": "/nix/store/system", "user": "root" },
+                    "staged": { "path": "/nix/store/staged", "user": "root", "activationMode": "boot" }
+                }
+            }
+        }
+    }"#;
+
+    let deploy_data = deploy_data_for(data, "mac", "system");
+    assert_eq!(deploy_data.node_system(), data::NodeSystem::Darwin);
+    assert_eq!(
+        deploy_data.defs().unwrap().profile_path,
+        "/nix/var/nix/profiles/system"
+    );
+
+    assert!(matches!(
+        deploy_data_for(data, "mac", "staged").defs(),
+        Err(DeployDataDefsError::DarwinActivationMode(_, _, "boot"))
+    ));
+}
+
+#[test]
+fn test_user_profile() {
+    let data = r#"{
+        "sshUser": "alice",
+        "nodes": {
+            "host": {
+                "hostname": "host.example.com",
+                "profiles": {
+                    "home": { "path": "/nix/store/home", "type": "user" },
+                    "other": { "path": "/nix/store/other", "type": "user", "user": "bob" }
+                }
+            }
+        }
+    }"#;
+
+    let deploy_defs = deploy_data_for(data, "host", "home").defs().unwrap();
+    assert_eq!(deploy_defs.profile_user, "alice");
+    assert_eq!(deploy_defs.profile_path, ".local/state/nix/profiles/home");
+    assert_eq!(deploy_defs.sudo, None);
+
+    assert!(matches!(
+        deploy_data_for(data, "host", "other").defs(),
+        Err(DeployDataDefsError::UserProfileUser(_, _, _))
+    ));
+}
+
+#[test]
+fn test_ssh_identities() {
+    let data = r#"{
+        "sshIdentities": ["~/.ssh/id_fleet"],
+        "nodes": {
+            "host": {
+                "hostname": "host.example.com",
+                "sshOpts": ["-p", "2222"],
+                "sshIdentities": ["~/.ssh/id_host"],
+                "profiles": {
+                    "system": { "path": "/nix/store/system" }
+                }
+            }
+        }
+    }"#;
+
+    assert_eq!(
+        deploy_data_for(data, "host", "system")
+            .merged_settings
+            .ssh_opts,
+        vec!["-p", "2222", "-i~/.ssh/id_host", "-i~/.ssh/id_fleet"]
+    );
+}
+
+#[test]
+fn test_pick_srv_record() {
+    let record =
+        |priority, weight, port, target: &str| (priority, weight, port, target.to_string());
+
+    assert_eq!(
+        pick_srv_record(
+            vec![
+                record(20, 0, 2222, "backup.example.com."),
+                record(10, 5, 22, "a.example.com."),
+                record(10, 50, 2200, "b.example.com."),
+            ]
+            .into_iter()
+        ),
+        Some(("b.example.com".to_string(), 2200))
+    );
+    assert_eq!(
+        pick_srv_record(vec![record(0, 0, 0, ".")].into_iter()),
+        None
+    );
+    assert_eq!(pick_srv_record(Vec::new().into_iter()), None);
+}
+
+#[test]
+fn test_private_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = PrivateDir::new("deploy-rs-test").unwrap();
+    let other = PrivateDir::new("deploy-rs-test").unwrap();
+    assert_ne!(dir.path(), other.path());
+
+    let mode = std::fs::metadata(dir.path()).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+
+    let path = dir.path().to_path_buf();
+    drop(dir);
+    assert!(!path.exists());
+}
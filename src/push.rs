@@ -2,13 +2,17 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use log::{debug, info};
+use log::{debug, info, warn};
 use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::process::Stdio;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::process::Command;
 
+use crate::{find_in_path, LogCommand, PrivateDir};
+
 #[derive(Error, Debug)]
 pub enum PushProfileError {
     #[error("Failed to run Nix show-derivation command: {0}")]
@@ -26,10 +30,10 @@ pub enum PushProfileError {
     #[error("Nix build command resulted in a bad exit code: {0:?}")]
     BuildExit(Option<i32>),
     #[error(
-        "Activation script deploy-rs-activate does not exist in profile.\n\
+        "Activation script {0} does not exist in profile.\n\
              Did you forget to use deploy-rs#lib.<...>.activate.<...> on your profile path?"
     )]
-    DeployRsActivateDoesntExist,
+    DeployRsActivateDoesntExist(String),
     #[error("Activation script activate-rs does not exist in profile.\n\
              Is there a mismatch in deploy-rs used in the flake you're deploying and deploy-rs command you're running?")]
     ActivateRsDoesntExist,
@@ -41,33 +45,297 @@ pub enum PushProfileError {
     Copy(std::io::Error),
     #[error("Nix copy command resulted in a bad exit code: {0:?}")]
     CopyExit(Option<i32>),
+    #[error("Failed to run Nix path-info command: {0}")]
+    PathInfo(std::io::Error),
+    #[error("Nix path-info command resulted in a bad exit code: {0:?}")]
+    PathInfoExit(Option<i32>),
+    #[error("Failed to parse the output of nix path-info: {0}")]
+    PathInfoParse(serde_json::Error),
+    #[error("Failed to run Nix command verifying the copy: {0}")]
+    VerifyCopy(std::io::Error),
+    #[error("The copied profile is missing or not trusted on the node, verifying it resulted in a bad exit code: {0:?}")]
+    VerifyCopyExit(Option<i32>),
+    #[error("Failed to run Nix command checking that the profiles are on the node: {0}")]
+    CheckPresent(std::io::Error),
+    #[error("The profiles are not on node `{0}` (or it could not be reached), so pushing them can not be skipped: exit code {1:?}")]
+    NotPresent(String, Option<i32>),
+    #[error("`{0}` is needed to limit the bandwidth of copying, but was not found")]
+    BwlimitMissing(&'static str),
+    #[error("Failed to write the SSH wrapper limiting the bandwidth of copying: {0}")]
+    BwlimitWrapper(std::io::Error),
+    #[error("Failed to run the command substituting the profiles from the binary cache on the node: {0}")]
+    RealiseFromCache(std::io::Error),
+    #[error("Substituting the profiles from the binary cache on the node resulted in a bad exit code: {0:?}")]
+    RealiseFromCacheExit(Option<i32>),
+    #[error("{0} timed out after {1} seconds (--build-timeout)")]
+    Timeout(&'static str, u64),
+    #[error("Building on the target node requires a Nix version with flakes support")]
+    RemoteBuildNoFlakes,
 }
 
 pub struct PushProfileData<'a> {
     pub supports_flakes: bool,
     pub check_sigs: bool,
+    pub allow_unsigned_local: bool,
     pub repo: &'a str,
     pub deploy_data: &'a super::DeployData<'a>,
     pub deploy_defs: &'a super::DeployDefs,
     pub keep_result: bool,
     pub result_path: Option<&'a str>,
     pub extra_build_args: &'a [String],
+    pub copy_retries: u32,
+    pub build_timeout: Option<u64>,
+    pub show_closure_size: bool,
+}
+
+/// Options for Nix to make the node substitute from the configured binary caches in addition to its own.
+/// They are sent over `ssh-ng://` to the Nix daemon of the node, which only accepts them from trusted users.
+fn substituter_args(data: &PushProfileData<'_>) -> Vec<String> {
+    let settings = &data.deploy_data.merged_settings;
+    let mut args = Vec::new();
+
+    if !settings.substituters.is_empty() {
+        args.push("--option".to_string());
+        args.push("extra-substituters".to_string());
+        args.push(settings.substituters.join(" "));
+    }
+
+    if !settings.trusted_public_keys.is_empty() {
+        args.push("--option".to_string());
+        args.push("extra-trusted-public-keys".to_string());
+        args.push(settings.trusted_public_keys.join(" "));
+    }
+
+    args
+}
+
+/// Copies the derivation of the profile to the node and builds it there, instead of building locally and copying the result
+async fn build_profile_remotely(
+    data: &PushProfileData<'_>,
+    derivation_name: &str,
+) -> Result<(), PushProfileError> {
+    info!(
+        "Building profile `{}` for node `{}` on the node itself",
+        data.deploy_data.profile_name, data.deploy_data.node_name
+    );
+
+    if !data.supports_flakes {
+        return Err(PushProfileError::RemoteBuildNoFlakes);
+    }
+
+    let hostname = &data.deploy_defs.hostname;
+
+    let store_address = format!("ssh-ng://{}@{}", data.deploy_defs.ssh_user, hostname);
+
+    let ssh_opts_str = data.deploy_data.merged_settings.ssh_opts.join(" ");
+
+    // Only the derivations are copied, their inputs are substituted by the node unless the
+    // connection is fast, and their signatures are checked the same way as with local builds
+    let mut copy_command = std::process::Command::new(crate::nix_tool("nix"));
+    copy_command.arg("copy").args(nix_verbosity(data));
+
+    if data.deploy_data.merged_settings.fast_connection != Some(true) {
+        copy_command
+            .arg("--substitute-on-destination")
+            .args(substituter_args(data));
+    }
+
+    if !data.check_sigs {
+        copy_command.arg("--no-check-sigs");
+    }
+
+    copy_command
+        .arg("--derivation")
+        .arg("--to")
+        .arg(&store_address)
+        .arg(derivation_name)
+        .env("NIX_SSHOPTS", &ssh_opts_str)
+        .envs(crate::nix_ssh_path().map(|path| ("PATH", path)));
+
+    let mut build_command = std::process::Command::new(crate::nix_tool("nix"));
+    build_command
+        .arg("build")
+        .args(nix_verbosity(data))
+        .arg(format!("{}^out", derivation_name))
+        .arg("--eval-store")
+        .arg("auto")
+        .arg("--store")
+        .arg(&store_address)
+        .arg("--no-link")
+        .args(substituter_args(data))
+        .env("NIX_SSHOPTS", &ssh_opts_str)
+        .envs(crate::nix_ssh_path().map(|path| ("PATH", path)));
+
+    // The profile's own arguments come first, so that those given on the command line take precedence
+    for extra_arg in data
+        .deploy_data
+        .merged_settings
+        .extra_build_args
+        .iter()
+        .chain(data.extra_build_args)
+    {
+        build_command.arg(extra_arg);
+    }
+
+    if data.deploy_data.cmd_overrides.dry_run {
+        info!(
+            "Dry run, would copy the derivation with: {:?}",
+            copy_command
+        );
+        info!("Dry run, would build with: {:?}", build_command);
+
+        return Ok(());
+    }
+
+    let started = Instant::now();
+
+    let copy_exit_status = build_timeout(
+        data,
+        "Copying the derivation to the node",
+        Command::from(copy_command)
+            .kill_on_drop(true)
+            .logged()
+            .status(),
+    )
+    .await?
+    .map_err(PushProfileError::Copy)?;
+
+    match copy_exit_status.code() {
+        Some(0) => (),
+        a => return Err(PushProfileError::CopyExit(a)),
+    };
+
+    let build_exit_status = build_timeout(
+        data,
+        "Building the profile on the node",
+        Command::from(build_command)
+            // Logging should be in stderr, this just stops the store path from printing for no reason
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .logged()
+            .status(),
+    )
+    .await?
+    .map_err(PushProfileError::Build)?;
+
+    match build_exit_status.code() {
+        Some(0) => (),
+        a => return Err(PushProfileError::BuildExit(a)),
+    };
+
+    info!(
+        "Built profile `{}` for node `{}` on the node in {:.1}s",
+        data.deploy_data.profile_name,
+        data.deploy_data.node_name,
+        started.elapsed().as_secs_f64()
+    );
+
+    Ok(())
 }
 
+/// Waits for `f`, a Nix command building or copying the profile, for at most the `--build-timeout`.
+/// The commands are killed on drop, so giving up on them kills them as well.
+async fn build_timeout<T>(
+    data: &PushProfileData<'_>,
+    what: &'static str,
+    f: impl std::future::Future<Output = T>,
+) -> Result<T, PushProfileError> {
+    match data.build_timeout {
+        None => Ok(f.await),
+        Some(timeout) => tokio::time::timeout(Duration::from_secs(timeout), f)
+            .await
+            .map_err(|_| PushProfileError::Timeout(what, timeout)),
+    }
+}
+
+/// Logs the size of the closure of the profile, only warning if it can not be determined
+async fn log_closure_size(data: &PushProfileData<'_>) {
+    let path_info_output = Command::new(crate::nix_tool("nix"))
+        .arg("path-info")
+        .arg("--closure-size")
+        .arg(data.deploy_data.store_path())
+        .logged()
+        .output()
+        .await;
+
+    let path_info_output = match path_info_output {
+        Ok(x) if x.status.success() => x,
+        Ok(x) => {
+            warn!(
+                "Could not determine the closure size of profile `{}`, nix path-info resulted in a bad exit code: {:?}",
+                data.deploy_data.profile_name,
+                x.status.code()
+            );
+            return;
+        }
+        Err(e) => {
+            warn!(
+                "Could not determine the closure size of profile `{}`, failed to run nix path-info: {}",
+                data.deploy_data.profile_name, e
+            );
+            return;
+        }
+    };
+
+    // The output is the store path, followed by the size of its closure in bytes
+    let closure_size = String::from_utf8_lossy(&path_info_output.stdout)
+        .split_whitespace()
+        .last()
+        .and_then(|x| x.parse::<u64>().ok());
+
+    match closure_size {
+        Some(closure_size) => info!(
+            "Closure of profile `{}` for node `{}` is {:.1} MB",
+            data.deploy_data.profile_name,
+            data.deploy_data.node_name,
+            closure_size as f64 / 1_000_000.0
+        ),
+        None => warn!(
+            "Could not determine the closure size of profile `{}`, nix path-info output could not be parsed",
+            data.deploy_data.profile_name
+        ),
+    }
+}
+
+/// Builds the profile and copies it to the node.
+/// To copy several profiles at once, use `build_profile` and `copy_profiles` instead.
 pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileError> {
+    build_profile(&data).await?;
+
+    if !builds_remotely(&data) {
+        copy_profiles(&[&data]).await?;
+    }
+
+    Ok(())
+}
+
+/// Returns if the profile is built on the node itself, in which case `build_profile` leaves nothing to copy
+pub fn builds_remotely(data: &PushProfileData<'_>) -> bool {
+    // Building on the local machine is the same as "remotely" building on it
+    !data.deploy_data.is_local()
+        && data
+            .deploy_data
+            .merged_settings
+            .remote_build
+            .unwrap_or(false)
+}
+
+/// Builds (and signs, if a key is given) the profile, or builds it on the node if configured to
+pub async fn build_profile(data: &PushProfileData<'_>) -> Result<(), PushProfileError> {
     debug!(
         "Finding the deriver of store path for {}",
-        &data.deploy_data.profile.profile_settings.path
+        data.deploy_data.store_path()
     );
 
     // `nix-store --query --deriver` doesn't work on invalid paths, so we parse output of show-derivation :(
-    let mut show_derivation_command = Command::new("nix");
+    let mut show_derivation_command = Command::new(crate::nix_tool("nix"));
 
     show_derivation_command
         .arg("show-derivation")
-        .arg(&data.deploy_data.profile.profile_settings.path);
+        .arg(data.deploy_data.store_path());
 
     let show_derivation_output = show_derivation_command
+        .logged()
         .output()
         .await
         .map_err(PushProfileError::ShowDerivation)?;
@@ -88,15 +356,19 @@ pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileEr
         .next()
         .ok_or(PushProfileError::ShowDerivationEmpty)?;
 
+    if builds_remotely(data) {
+        return build_profile_remotely(data, derivation_name).await;
+    }
+
     info!(
         "Building profile `{}` for node `{}`",
         data.deploy_data.profile_name, data.deploy_data.node_name
     );
 
     let mut build_command = if data.supports_flakes {
-        Command::new("nix")
+        std::process::Command::new(crate::nix_tool("nix"))
     } else {
-        Command::new("nix-build")
+        std::process::Command::new(crate::nix_tool("nix-build"))
     };
 
     if data.supports_flakes {
@@ -118,42 +390,65 @@ pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileEr
         (false, true) => build_command.arg("--no-link"),
     };
 
-    for extra_arg in data.extra_build_args {
+    // The profile's own arguments come first, so that those given on the command line take precedence
+    for extra_arg in data
+        .deploy_data
+        .merged_settings
+        .extra_build_args
+        .iter()
+        .chain(data.extra_build_args)
+    {
         build_command.arg(extra_arg);
     }
 
-    let build_exit_status = build_command
-        // Logging should be in stderr, this just stops the store path from printing for no reason
-        .stdout(Stdio::null())
-        .status()
-        .await
+    if data.deploy_data.cmd_overrides.dry_run {
+        info!("Dry run, would build with: {:?}", build_command);
+    } else {
+        let started = Instant::now();
+
+        let build_exit_status = build_timeout(
+            data,
+            "Building the profile",
+            Command::from(build_command)
+                // Logging should be in stderr, this just stops the store path from printing for no reason
+                .stdout(Stdio::null())
+                .kill_on_drop(true)
+                .logged()
+                .status(),
+        )
+        .await?
         .map_err(PushProfileError::Build)?;
 
-    match build_exit_status.code() {
-        Some(0) => (),
-        a => return Err(PushProfileError::BuildExit(a)),
-    };
+        match build_exit_status.code() {
+            Some(0) => (),
+            a => return Err(PushProfileError::BuildExit(a)),
+        };
 
-    if !Path::new(
-        format!(
-            "{}/deploy-rs-activate",
-            data.deploy_data.profile.profile_settings.path
-        )
-        .as_str(),
-    )
-    .exists()
+        info!(
+            "Built profile `{}` for node `{}` in {:.1}s",
+            data.deploy_data.profile_name,
+            data.deploy_data.node_name,
+            started.elapsed().as_secs_f64()
+        );
+    }
+
+    // Nothing was built in a dry run, so there is nothing to check
+    let activate_script = data
+        .deploy_data
+        .activate_script()
+        .unwrap_or("deploy-rs-activate");
+
+    if !data.deploy_data.cmd_overrides.dry_run
+        && !Path::new(format!("{}/{}", data.deploy_data.store_path(), activate_script).as_str())
+            .exists()
     {
-        return Err(PushProfileError::DeployRsActivateDoesntExist);
+        return Err(PushProfileError::DeployRsActivateDoesntExist(
+            activate_script.to_string(),
+        ));
     }
 
-    if !Path::new(
-        format!(
-            "{}/activate-rs",
-            data.deploy_data.profile.profile_settings.path
-        )
-        .as_str(),
-    )
-    .exists()
+    if !data.deploy_data.cmd_overrides.dry_run
+        && !Path::new(format!("{}/activate-rs", data.deploy_data.store_path()).as_str()).exists()
     {
         return Err(PushProfileError::ActivateRsDoesntExist);
     }
@@ -164,39 +459,590 @@ pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileEr
             data.deploy_data.profile_name, data.deploy_data.node_name
         );
 
-        let sign_exit_status = Command::new("nix")
+        let mut sign_command = std::process::Command::new(crate::nix_tool("nix"));
+        sign_command
             .arg("sign-paths")
             .arg("-r")
             .arg("-k")
             .arg(local_key)
-            .arg(&data.deploy_data.profile.profile_settings.path)
+            .arg(data.deploy_data.store_path());
+
+        if data.deploy_data.cmd_overrides.dry_run {
+            info!("Dry run, would sign with: {:?}", sign_command);
+        } else {
+            let sign_exit_status = Command::from(sign_command)
+                .logged()
+                .status()
+                .await
+                .map_err(PushProfileError::Sign)?;
+
+            match sign_exit_status.code() {
+                Some(0) => (),
+                a => return Err(PushProfileError::SignExit(a)),
+            };
+        }
+    }
+
+    if data.show_closure_size && !data.deploy_data.cmd_overrides.dry_run {
+        log_closure_size(data).await;
+    }
+
+    Ok(())
+}
+
+/// Checks that the copied store paths are valid on the node, and when checking signatures,
+/// that they are signed by a trusted key, so that a failed copy is noticed before activating.
+/// The signatures of `unsigned_paths`, which were copied without checking them, are not verified
+async fn verify_copy(
+    profiles: &[&PushProfileData<'_>],
+    unsigned_paths: &[String],
+) -> Result<(), PushProfileError> {
+    let data = match profiles.first() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    let store = format!(
+        "ssh://{}@{}",
+        data.deploy_defs.ssh_user, data.deploy_defs.hostname
+    );
+    let ssh_opts_str = data.deploy_data.merged_settings.ssh_opts.join(" ");
+
+    let profile_paths: Vec<&str> = profiles
+        .iter()
+        .map(|x| x.deploy_data.store_path())
+        .collect();
+
+    let mut verify_commands = Vec::new();
+
+    let mut path_info_command = std::process::Command::new(crate::nix_tool("nix"));
+    path_info_command
+        .arg("path-info")
+        .args(nix_verbosity(data))
+        .arg("--store")
+        .arg(&store)
+        .args(&profile_paths)
+        .env("NIX_SSHOPTS", &ssh_opts_str)
+        .envs(crate::nix_ssh_path().map(|path| ("PATH", path)));
+    verify_commands.push(path_info_command);
+
+    let signed_paths: Vec<String> = profile_paths
+        .into_iter()
+        .filter(|x| !unsigned_paths.iter().any(|y| y == x))
+        .map(crate::deploy::shell_quote)
+        .collect();
+
+    // Against a store opened over SSH, signatures are checked with the keys trusted here,
+    // so they are checked on the node itself, where its own trusted keys apply
+    if data.check_sigs && !signed_paths.is_empty() {
+        let verify = match data.supports_flakes {
+            true => "nix --extra-experimental-features nix-command store verify",
+            false => "nix verify",
+        };
+
+        let mut sigs_command = crate::deploy::node_command(
+            data.deploy_data,
+            &format!(
+                "{}@{}",
+                data.deploy_defs.ssh_user, data.deploy_defs.hostname
+            ),
+        );
+        // The contents were just copied, only the signatures are left to check
+        sigs_command.arg(format!(
+            "{} --no-contents {}",
+            verify,
+            signed_paths.join(" ")
+        ));
+        verify_commands.push(sigs_command);
+    }
+
+    for verify_command in verify_commands {
+        if data.deploy_data.cmd_overrides.dry_run {
+            info!("Dry run, would verify the copy with: {:?}", verify_command);
+
+            continue;
+        }
+
+        let mut verify_command = Command::from(verify_command);
+        verify_command.kill_on_drop(true).stdout(Stdio::null());
+
+        let verify_exit_status = verify_command
+            .logged()
             .status()
             .await
-            .map_err(PushProfileError::Sign)?;
+            .map_err(PushProfileError::VerifyCopy)?;
 
-        match sign_exit_status.code() {
+        match verify_exit_status.code() {
             Some(0) => (),
-            a => return Err(PushProfileError::SignExit(a)),
+            a => return Err(PushProfileError::VerifyCopyExit(a)),
         };
     }
 
+    if !data.deploy_data.cmd_overrides.dry_run {
+        info!("Verified the copy to node `{}`", data.deploy_data.node_name);
+    }
+
+    Ok(())
+}
+
+/// Verbosity flag for the Nix commands connecting to the node with `--verbose-ssh`
+fn nix_verbosity(data: &PushProfileData) -> Option<String> {
+    crate::verbosity_flag(data.deploy_data.cmd_overrides.verbose_ssh)
+}
+
+/// Makes a directory with an `ssh` wrapper that limits what is sent through it to `limit` KiB/s with `pv`,
+/// to be put first into the `PATH` of `nix copy`, as neither Nix nor SSH can limit the bandwidth themselves
+fn make_bwlimit_ssh(limit: u32) -> Result<PrivateDir, PushProfileError> {
+    let ssh = match crate::ssh_bin() {
+        ssh if ssh.is_absolute() => ssh.to_path_buf(),
+        _ => find_in_path("ssh").ok_or(PushProfileError::BwlimitMissing("ssh"))?,
+    };
+    find_in_path("pv").ok_or(PushProfileError::BwlimitMissing("pv"))?;
+
+    // Every copy gets a directory of its own, which nobody else can put another `ssh` into
+    let dir = PrivateDir::new("deploy-rs-bwlimit").map_err(PushProfileError::BwlimitWrapper)?;
+
+    let script = format!(
+        "#!/bin/sh\npv -q -L {}k | '{}' \"$@\"\n",
+        limit,
+        ssh.display()
+    );
+
+    let path = dir.path().join("ssh");
+
+    std::fs::write(&path, script)
+        .and_then(|_| std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700)))
+        .map_err(PushProfileError::BwlimitWrapper)?;
+
+    Ok(dir)
+}
+
+/// Checks that the profiles are already in the store of the node, for activating them without pushing
+pub async fn check_present(profiles: &[&PushProfileData<'_>]) -> Result<(), PushProfileError> {
+    let data = match profiles.first() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    let mut path_info_command = std::process::Command::new(crate::nix_tool("nix"));
+    path_info_command.arg("path-info").args(nix_verbosity(data));
+
+    if !data.deploy_data.is_local() {
+        path_info_command.arg("--store").arg(format!(
+            "ssh://{}@{}",
+            data.deploy_defs.ssh_user, data.deploy_defs.hostname
+        ));
+    }
+
+    path_info_command
+        .args(profiles.iter().map(|x| x.deploy_data.store_path()))
+        .env(
+            "NIX_SSHOPTS",
+            data.deploy_data.merged_settings.ssh_opts.join(" "),
+        )
+        .envs(crate::nix_ssh_path().map(|path| ("PATH", path)));
+
+    if data.deploy_data.cmd_overrides.dry_run {
+        info!(
+            "Dry run, would check that the profiles are on the node with: {:?}",
+            path_info_command
+        );
+
+        return Ok(());
+    }
+
+    let mut path_info_command = Command::from(path_info_command);
+    path_info_command.kill_on_drop(true).stdout(Stdio::null());
+
+    let path_info_exit_status = path_info_command
+        .logged()
+        .status()
+        .await
+        .map_err(PushProfileError::CheckPresent)?;
+
+    match path_info_exit_status.code() {
+        Some(0) => {
+            info!(
+                "Skipping the push, the profiles are already on node `{}`",
+                data.deploy_data.node_name
+            );
+
+            Ok(())
+        }
+        a => Err(PushProfileError::NotPresent(
+            data.deploy_data.node_name.to_string(),
+            a,
+        )),
+    }
+}
+
+/// Returns if the profile is already in the store of the node, for showing what a deployment would do
+pub async fn is_present(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<bool, PushProfileError> {
+    let mut path_info_command = Command::new(crate::nix_tool("nix"));
+    path_info_command
+        .kill_on_drop(true)
+        .arg("path-info")
+        .args(crate::verbosity_flag(deploy_data.cmd_overrides.verbose_ssh));
+
+    if !deploy_data.is_local() {
+        path_info_command.arg("--store").arg(format!(
+            "ssh://{}@{}",
+            deploy_defs.ssh_user, deploy_defs.hostname
+        ));
+    }
+
+    let path_info_exit_status = path_info_command
+        .arg(deploy_data.store_path())
+        .env(
+            "NIX_SSHOPTS",
+            deploy_data.merged_settings.ssh_opts.join(" "),
+        )
+        .envs(crate::nix_ssh_path().map(|path| ("PATH", path)))
+        .stdout(Stdio::null())
+        // Missing paths are reported on stderr, which is expected here
+        .stderr(Stdio::null())
+        .logged()
+        .status()
+        .await
+        .map_err(PushProfileError::CheckPresent)?;
+
+    Ok(path_info_exit_status.success())
+}
+
+/// Copies the built profiles to their node with a single `nix copy`, so that paths shared between them are
+/// only copied once. The connection settings of the first profile are used for all of them.
+pub async fn copy_profiles(profiles: &[&PushProfileData<'_>]) -> Result<(), PushProfileError> {
+    let data = match profiles.first() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    if data.deploy_data.is_local() {
+        info!(
+            "Node `{}` is the local machine and shares its store, nothing to copy",
+            data.deploy_data.node_name
+        );
+
+        return Ok(());
+    }
+
     info!(
-        "Copying profile `{}` to node `{}`",
-        data.deploy_data.profile_name, data.deploy_data.node_name
+        "Copying {} to node `{}`",
+        profiles
+            .iter()
+            .map(|x| format!("profile `{}`", x.deploy_data.profile_name))
+            .collect::<Vec<String>>()
+            .join(", "),
+        data.deploy_data.node_name
+    );
+
+    let profile_paths: Vec<String> = profiles
+        .iter()
+        .map(|x| x.deploy_data.store_path().to_string())
+        .collect();
+
+    if let Some(ref cache) = data.deploy_data.cmd_overrides.to_cache {
+        return copy_via_cache(profiles, &profile_paths, cache).await;
+    }
+
+    let unsigned_paths = match data.check_sigs && data.allow_unsigned_local {
+        true => unsigned_local_paths(data, &profile_paths).await?,
+        false => Vec::new(),
+    };
+
+    // With unsigned paths that were built locally, everything else in the closures is copied first with
+    // the signatures checked, so that the copy of the profiles only has the unsigned paths left to copy
+    let stages = match unsigned_paths.is_empty() {
+        true => vec![(profile_paths, data.check_sigs)],
+        false => {
+            warn!(
+                "Copying {} unsigned paths built locally to node `{}` without checking their signatures",
+                unsigned_paths.len(),
+                data.deploy_data.node_name
+            );
+            for path in &unsigned_paths {
+                debug!("Unsigned path built locally: {}", path);
+            }
+
+            let signed_paths = closure_paths(data, &profile_paths)
+                .await?
+                .into_iter()
+                .filter(|x| !unsigned_paths.contains(x))
+                .collect::<Vec<String>>();
+
+            vec![(signed_paths, true), (profile_paths, false)]
+        }
+    };
+
+    let copy_commands: Vec<std::process::Command> = stages
+        .iter()
+        .filter(|(paths, _)| !paths.is_empty())
+        .map(|(paths, check_sigs)| make_copy_command(data, paths, *check_sigs))
+        .collect();
+
+    let copy_bwlimit = data.deploy_data.cmd_overrides.copy_bwlimit;
+
+    if data.deploy_data.cmd_overrides.dry_run {
+        for copy_command in &copy_commands {
+            info!("Dry run, would copy with: {:?}", copy_command);
+        }
+
+        if let Some(limit) = copy_bwlimit {
+            info!(
+                "Dry run, would limit sending to the node to {} KiB/s with `pv`",
+                limit
+            );
+        }
+
+        if data.deploy_data.cmd_overrides.verify_copy {
+            verify_copy(profiles, &unsigned_paths).await?;
+        }
+
+        return Ok(());
+    }
+
+    // The wrapper is only deleted once the copies are done
+    let wrapper_dir = match copy_bwlimit {
+        Some(limit) => Some(make_bwlimit_ssh(limit)?),
+        None => None,
+    };
+
+    let bwlimit_path = match wrapper_dir {
+        Some(ref wrapper_dir) => {
+            let mut path = std::ffi::OsString::from(wrapper_dir.path());
+            if let Some(x) = crate::nix_ssh_path().or_else(|| std::env::var_os("PATH")) {
+                path.push(":");
+                path.push(x);
+            }
+
+            Some(path)
+        }
+        None => None,
+    };
+
+    let started = Instant::now();
+
+    for mut copy_command in copy_commands {
+        if let Some(ref path) = bwlimit_path {
+            copy_command.env("PATH", path);
+        }
+
+        let mut copy_command = Command::from(copy_command);
+        copy_command.kill_on_drop(true);
+
+        run_copy(data, &mut copy_command).await?;
+    }
+
+    info!(
+        "Copied to node `{}` in {:.1}s",
+        data.deploy_data.node_name,
+        started.elapsed().as_secs_f64()
+    );
+
+    if data.deploy_data.cmd_overrides.verify_copy {
+        verify_copy(profiles, &unsigned_paths).await?;
+    }
+
+    Ok(())
+}
+
+/// Adds the compression settings to the URI of the binary cache the profiles are uploaded to
+pub fn cache_upload_uri(
+    cache: &str,
+    compression: Option<crate::CopyCompression>,
+    level: Option<u32>,
+) -> String {
+    let mut params = Vec::new();
+    // SSH compression has nothing to do with binary caches
+    if let Some(compression) = compression.filter(|x| *x != crate::CopyCompression::Ssh) {
+        params.push(format!("compression={}", compression.as_str()));
+    }
+    if let Some(level) = level {
+        params.push(format!("compression-level={}", level));
+    }
+
+    match params.is_empty() {
+        true => cache.to_string(),
+        false => format!(
+            "{}{}{}",
+            cache,
+            match cache.contains('?') {
+                true => "&",
+                false => "?",
+            },
+            params.join("&")
+        ),
+    }
+}
+
+#[test]
+fn test_cache_upload_uri() {
+    assert_eq!(cache_upload_uri("s3://cache", None, None), "s3://cache");
+    assert_eq!(
+        cache_upload_uri("s3://cache", Some(crate::CopyCompression::Zstd), Some(3)),
+        "s3://cache?compression=zstd&compression-level=3"
+    );
+    assert_eq!(
+        cache_upload_uri(
+            "s3://cache?region=eu-west-1",
+            Some(crate::CopyCompression::Xz),
+            None
+        ),
+        "s3://cache?region=eu-west-1&compression=xz"
+    );
+}
+
+/// Copies the profiles to the binary cache `cache`, then has the node substitute them from it over SSH,
+/// so that the node downloads them from the cache instead of from this machine
+async fn copy_via_cache(
+    profiles: &[&PushProfileData<'_>],
+    profile_paths: &[String],
+    cache: &str,
+) -> Result<(), PushProfileError> {
+    let data = profiles[0];
+
+    let mut copy_command = std::process::Command::new(crate::nix_tool("nix"));
+    copy_command
+        .arg("copy")
+        .args(nix_verbosity(data))
+        .arg("--to")
+        .arg(cache_upload_uri(
+            cache,
+            data.deploy_data.cmd_overrides.copy_compression,
+            data.deploy_data.cmd_overrides.copy_compression_level,
+        ))
+        .args(profile_paths);
+
+    // Substituting checks the signatures of the paths like any other substitution, so the node has to trust
+    // the key they are signed with. Like with `substituters`, the cache is only used if the node allows it.
+    let mut substituters = vec![cache.to_string()];
+    substituters.extend(
+        data.deploy_data
+            .merged_settings
+            .substituters
+            .iter()
+            .cloned(),
+    );
+
+    let mut realise_command = format!(
+        "nix-store --realise {} --option extra-substituters {}",
+        profile_paths
+            .iter()
+            .map(|x| crate::deploy::shell_quote(x))
+            .collect::<Vec<String>>()
+            .join(" "),
+        crate::deploy::shell_quote(&substituters.join(" "))
+    );
+
+    let trusted_public_keys = &data.deploy_data.merged_settings.trusted_public_keys;
+    if !trusted_public_keys.is_empty() {
+        realise_command.push_str(&format!(
+            " --option extra-trusted-public-keys {}",
+            crate::deploy::shell_quote(&trusted_public_keys.join(" "))
+        ));
+    }
+
+    let ssh_addr = format!(
+        "{}@{}",
+        data.deploy_defs.ssh_user, data.deploy_defs.hostname
+    );
+
+    let mut ssh_realise_command = crate::deploy::node_command(data.deploy_data, &ssh_addr);
+    ssh_realise_command
+        .arg(realise_command)
+        // The realised paths are printed on stdout
+        .stdout(Stdio::null());
+
+    if data.deploy_data.cmd_overrides.dry_run {
+        info!(
+            "Dry run, would copy to the binary cache with: {:?}",
+            copy_command
+        );
+        info!(
+            "Dry run, would substitute from the binary cache on the node with: {:?}",
+            ssh_realise_command
+        );
+
+        if data.deploy_data.cmd_overrides.verify_copy {
+            verify_copy(profiles, &[]).await?;
+        }
+
+        return Ok(());
+    }
+
+    let started = Instant::now();
+
+    let mut copy_command = Command::from(copy_command);
+    copy_command.kill_on_drop(true);
+
+    run_copy(data, &mut copy_command).await?;
+
+    info!(
+        "Copied to binary cache {} in {:.1}s",
+        cache,
+        started.elapsed().as_secs_f64()
     );
 
-    let mut copy_command = Command::new("nix");
-    copy_command.arg("copy");
+    let started = Instant::now();
+
+    let mut ssh_realise_command = Command::from(ssh_realise_command);
+    ssh_realise_command.kill_on_drop(true);
+
+    let realise_exit_status = build_timeout(
+        data,
+        "Substituting from the binary cache on the node",
+        ssh_realise_command.logged().status(),
+    )
+    .await?
+    .map_err(PushProfileError::RealiseFromCache)?;
+
+    match realise_exit_status.code() {
+        Some(0) => (),
+        a => return Err(PushProfileError::RealiseFromCacheExit(a)),
+    };
+
+    info!(
+        "Node `{}` substituted the profiles from the binary cache in {:.1}s",
+        data.deploy_data.node_name,
+        started.elapsed().as_secs_f64()
+    );
+
+    if data.deploy_data.cmd_overrides.verify_copy {
+        verify_copy(profiles, &[]).await?;
+    }
+
+    Ok(())
+}
+
+/// Makes the `nix copy` command copying `paths` to the node of `data`, checking their signatures if `check_sigs`
+fn make_copy_command(
+    data: &PushProfileData<'_>,
+    paths: &[String],
+    check_sigs: bool,
+) -> std::process::Command {
+    let mut copy_command = std::process::Command::new(crate::nix_tool("nix"));
+    copy_command.arg("copy").args(nix_verbosity(data));
+
+    // The binary caches can only be passed on to the daemon of the node, which `ssh://` does not talk to
+    let substituter_args = match data.deploy_data.merged_settings.fast_connection {
+        Some(true) => Vec::new(),
+        _ => substituter_args(data),
+    };
 
     if data.deploy_data.merged_settings.fast_connection != Some(true) {
-        copy_command.arg("--substitute-on-destination");
+        copy_command
+            .arg("--substitute-on-destination")
+            .args(&substituter_args);
     }
 
-    if !data.check_sigs {
+    if !check_sigs {
         copy_command.arg("--no-check-sigs");
     }
 
-    let ssh_opts_str = data
+    let mut ssh_opts_str = data
         .deploy_data
         .merged_settings
         .ssh_opts
@@ -206,24 +1052,149 @@ pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileEr
         // .collect::<Vec<String>>()
         .join(" ");
 
-    let hostname = match data.deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &data.deploy_data.node.node_settings.hostname,
+    let hostname = &data.deploy_defs.hostname;
+
+    // Nix cannot compress what it sends over SSH by itself, only ask SSH to (with `ssh -C`)
+    let compress = match data.deploy_data.cmd_overrides.copy_compression {
+        Some(crate::CopyCompression::None) => {
+            ssh_opts_str.push_str(" -oCompression=no");
+            ""
+        }
+        Some(crate::CopyCompression::Ssh) => "?compress=true",
+        _ => "",
     };
 
-    let copy_exit_status = copy_command
+    copy_command
         .arg("--to")
-        .arg(format!("ssh://{}@{}", data.deploy_defs.ssh_user, hostname))
-        .arg(&data.deploy_data.profile.profile_settings.path)
+        .arg(format!(
+            "{}://{}@{}{}",
+            match substituter_args.is_empty() {
+                true => "ssh",
+                false => "ssh-ng",
+            },
+            data.deploy_defs.ssh_user,
+            hostname,
+            compress
+        ))
+        .args(paths)
         .env("NIX_SSHOPTS", ssh_opts_str)
-        .status()
+        .envs(crate::nix_ssh_path().map(|path| ("PATH", path)));
+
+    copy_command
+}
+
+/// Runs a `nix copy`, which is safe to repeat, so transient failures are retried with exponential backoff
+async fn run_copy(
+    data: &PushProfileData<'_>,
+    copy_command: &mut Command,
+) -> Result<(), PushProfileError> {
+    let mut attempt: u32 = 0;
+
+    loop {
+        // A copy that timed out is retried like any other failed one
+        let result = match build_timeout(
+            data,
+            "Copying to the node",
+            copy_command.logged().status(),
+        )
         .await
-        .map_err(PushProfileError::Copy)?;
+        {
+            Err(e) => Err(e),
+            Ok(Err(e)) => Err(PushProfileError::Copy(e)),
+            Ok(Ok(copy_exit_status)) => match copy_exit_status.code() {
+                Some(0) => Ok(()),
+                a => Err(PushProfileError::CopyExit(a)),
+            },
+        };
 
-    match copy_exit_status.code() {
-        Some(0) => (),
-        a => return Err(PushProfileError::CopyExit(a)),
-    };
+        match result {
+            Err(e) if attempt < data.copy_retries => {
+                attempt += 1;
 
-    Ok(())
+                let delay = Duration::from_secs(1 << (attempt - 1).min(6));
+
+                warn!(
+                    "Copying to node `{}` failed ({}), retrying in {}s (attempt {} of {})",
+                    data.deploy_data.node_name,
+                    e,
+                    delay.as_secs(),
+                    attempt,
+                    data.copy_retries
+                );
+
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Queries `nix path-info --json` about the closures of `paths` in the local store
+async fn local_path_info(
+    data: &PushProfileData<'_>,
+    paths: &[String],
+) -> Result<Vec<(String, serde_json::Value)>, PushProfileError> {
+    let path_info_output = Command::new(crate::nix_tool("nix"))
+        .arg("path-info")
+        .arg("--json")
+        .arg("--recursive")
+        .args(nix_verbosity(data))
+        .args(paths)
+        .logged()
+        .output()
+        .await
+        .map_err(PushProfileError::PathInfo)?;
+
+    if !path_info_output.status.success() {
+        return Err(PushProfileError::PathInfoExit(
+            path_info_output.status.code(),
+        ));
+    }
+
+    let path_info: serde_json::Value = serde_json::from_slice(&path_info_output.stdout)
+        .map_err(PushProfileError::PathInfoParse)?;
+
+    // Older versions of Nix output a list of objects with a `path`, newer ones an object keyed by the paths
+    Ok(match path_info {
+        serde_json::Value::Array(infos) => infos
+            .into_iter()
+            .filter_map(|info| Some((info.get("path")?.as_str()?.to_string(), info)))
+            .collect(),
+        serde_json::Value::Object(infos) => infos.into_iter().collect(),
+        _ => Vec::new(),
+    })
+}
+
+/// All store paths in the closures of `paths`
+async fn closure_paths(
+    data: &PushProfileData<'_>,
+    paths: &[String],
+) -> Result<Vec<String>, PushProfileError> {
+    Ok(local_path_info(data, paths)
+        .await?
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect())
+}
+
+/// The store paths in the closures of `paths` that were built locally and have no signatures,
+/// which the node would refuse when checking signatures
+async fn unsigned_local_paths(
+    data: &PushProfileData<'_>,
+    paths: &[String],
+) -> Result<Vec<String>, PushProfileError> {
+    Ok(local_path_info(data, paths)
+        .await?
+        .into_iter()
+        .filter(|(_, info)| {
+            let ultimate = info.get("ultimate").and_then(|x| x.as_bool()) == Some(true);
+            let signed = info
+                .get("signatures")
+                .and_then(|x| x.as_array())
+                .is_some_and(|x| !x.is_empty());
+
+            ultimate && !signed
+        })
+        .map(|(path, _)| path)
+        .collect())
 }
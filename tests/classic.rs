@@ -0,0 +1,45 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::process::Command;
+
+/// Evaluates the classic repository in `tests/classic`, which needs Nix and is skipped without it
+#[test]
+fn test_classic_repo_deploy_data() {
+    if Command::new("nix-instantiate")
+        .arg("--version")
+        .output()
+        .is_err()
+    {
+        eprintln!("nix-instantiate is not available, skipping");
+        return;
+    }
+
+    let output = Command::new(env!("CARGO_BIN_EXE_deploy"))
+        .arg("--print-deploy-data")
+        .arg("--no-eval-cache")
+        .arg(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/classic#example"
+        ))
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let resolved: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+
+    assert_eq!(resolved[0]["node"], "example");
+    assert_eq!(resolved[0]["profile"], "hello");
+    assert_eq!(resolved[0]["hostname"], "localhost");
+    assert_eq!(resolved[0]["profile_user"], "root");
+    assert!(resolved[0]["path"]
+        .as_str()
+        .unwrap()
+        .starts_with("/nix/store/"));
+}
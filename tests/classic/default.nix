@@ -0,0 +1,20 @@
+# SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+#
+# SPDX-License-Identifier: MPL-2.0
+
+# A classic (non-flake) repository with a deployment, so that evaluating one is tested
+{
+  deploy.nodes.example = {
+    hostname = "localhost";
+    profiles.hello = {
+      user = "root";
+      # Evaluating the deployment only needs the store path, nothing is built from this
+      path = builtins.derivation {
+        name = "hello";
+        system = builtins.currentSystem;
+        builder = "/bin/sh";
+        args = [ "-c" "echo hello > $out" ];
+      };
+    };
+  };
+}